@@ -1,29 +1,115 @@
-use crate::{fingerprint::FeatureExtractorConfiguration, search::DatabaseConfiguration};
+use crate::{fingerprint::{FeatureExtractorConfiguration, ChromaTransform, EdgeMode, EnergyMeasure, MagnitudeScale, StereoMode, WindowFunction}, search::{DatabaseConfiguration, PenaltyMode, RecombKey, ScoreRepr}};
 use serde::Deserialize;
 
+/// A configuration value that would make extraction or search misbehave.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigError {
+    /// `window_size` or `window_stride` is zero, or the stride exceeds the window
+    Window { size: usize, stride: usize },
+    /// the quantizer bin widths need more bits than a feature holds
+    BitBudget { bits: usize },
+    /// `quantizer_bits_per_bin_vec` doesn't have one width per chroma bin
+    BinWidthCount { expected: usize, got: usize },
+    /// `quantizer_topk` is zero or exceeds the number of chroma bins
+    Topk { topk: usize, bins: usize },
+    /// `chroma_smoothing` is outside `[0, 1)`
+    Smoothing(f32),
+    /// `search_beam_count` is zero
+    BeamCount,
+    /// `search_decay` is outside `(0, 1]`
+    Decay(f32),
+    /// `search_nonmax_overlap` is outside `[0, 1]`
+    NonmaxOverlap(f32),
+    /// `window_size` has a prime factor too large for a fast FFT; `suggested` is
+    /// the nearest fast size above it
+    SlowFftSize { size: usize, suggested: usize },
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Window { size, stride } => write!(f, "window stride {stride} must be nonzero and at most the window size {size}"),
+            Self::BitBudget { bits } => write!(f, "quantizer needs {bits} bits but a feature holds 64"),
+            Self::BinWidthCount { expected, got } => write!(f, "expected {expected} quantizer bin widths, got {got}"),
+            Self::Topk { topk, bins } => write!(f, "quantizer topk {topk} must be between 1 and the {bins} chroma bins"),
+            Self::Smoothing(s) => write!(f, "chroma smoothing {s} must be in [0, 1)"),
+            Self::BeamCount => write!(f, "search beam count must be nonzero"),
+            Self::Decay(d) => write!(f, "search decay {d} must be in (0, 1]"),
+            Self::NonmaxOverlap(o) => write!(f, "search nonmax overlap {o} must be in [0, 1]"),
+            Self::SlowFftSize { size, suggested } => write!(f, "window size {size} has a large prime factor; try {suggested}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Options missing when deserializing keep their `Default` values, so JS callers
+/// need only pass the ones they change.
 #[derive(Deserialize)]
-#[serde(rename_all = "camelCase")]
+#[serde(rename_all = "camelCase", default)]
 pub struct SessionConfiguration {
     // feature extractor
     pub sample_rate: usize,
     pub window_size: usize,
     pub window_stride: usize,
+    pub window_function: WindowFunction,
+    pub magnitude_scale: MagnitudeScale,
+    pub stereo_mode: StereoMode,
+    pub edge_mode: EdgeMode,
 
     pub chroma_n_octaves: usize, 
     pub chroma_bins_per_octave: usize,
     pub chroma_f_ref: f32,
     pub chroma_q_factor: f32,
+    pub chroma_transform: ChromaTransform,
+
+    pub pad_final_window: bool,
+    pub pad_short_audio: bool,
+    pub chroma_smoothing: f32,
+    pub onset_gated: bool,
+    pub spectral_whitening: bool,
+    pub energy_measure: EnergyMeasure,
 
     pub quantizer_min_energy: f32,
     pub quantizer_bits_per_bin: usize,
+    pub quantizer_bits_per_bin_vec: Option<Vec<usize>>,
     pub quantizer_topk: usize,
+    pub quantizer_soft_assign: bool,
 
     // search
     pub search_beam_count: usize,
+    pub search_adaptive_beams: bool,
     pub search_window_size: usize,
+    pub search_window_back: usize,
+    pub search_jump_penalty: u32,
+    pub search_branch: bool,
     pub search_nonmax_overlap: f32,
     pub search_length_penalty: u32,
     pub search_score_penalty: u32,
+    pub search_penalty_mode: PenaltyMode,
+    pub search_score_repr: ScoreRepr,
+    pub search_recomb_by: RecombKey,
+    pub search_recomb_bucket: usize,
+    pub search_score_breakdown: bool,
+    pub search_decay: f32,
+    pub search_score_threshold: f32,
+    pub search_max_query_frames: Option<usize>,
+    pub search_context_frames: usize,
+    pub search_max_key_scan: Option<usize>,
+    pub search_max_results_per_song: Option<usize>,
+    pub search_max_mean_distance: Option<f32>,
+    pub search_query_hop: usize,
+    pub search_prior_weight: u32,
+    pub search_energy_weight: bool,
+    pub search_contrast_seeding: bool,
+    pub search_idf_seeding: bool,
+    pub search_prefilter: Option<usize>,
+    pub search_coarse_factor: Option<usize>,
+    pub search_coarse_shortlist: usize,
+    pub dedup_query_window: f32,
+
+    // registration
+    pub trim_silence_threshold: Option<f32>,
 }
 
 impl SessionConfiguration {
@@ -31,6 +117,13 @@ impl SessionConfiguration {
         (self.into(), self.into())
     }
 
+    /// Whether features extracted under `self` are stale under `other`: whether their
+    /// `config_hash`es differ. Search settings and `energy_measure` apply to stored
+    /// features as-is.
+    pub fn requires_reextraction(&self, other: &SessionConfiguration) -> bool {
+        FeatureExtractorConfiguration::from(self).config_hash() != FeatureExtractorConfiguration::from(other).config_hash()
+    }
+
     pub fn stride_dt(&self) -> f32 { self.window_stride as f32 / self.sample_rate as f32  }
 }
 
@@ -40,21 +133,82 @@ impl Default for SessionConfiguration {
             sample_rate: 11_500,
             window_size: 4096,
             window_stride: 2048,
+            window_function: WindowFunction::Hann,
+            magnitude_scale: MagnitudeScale::SqrtN,
+            stereo_mode: StereoMode::Downmix,
+            edge_mode: EdgeMode::Truncate,
 
             chroma_n_octaves: 8,
             chroma_bins_per_octave: 12,
             chroma_f_ref: 27.5,
             chroma_q_factor: 20.0,
+            chroma_transform: ChromaTransform::FftProjection,
+
+            pad_final_window: false,
+            pad_short_audio: false,
+            chroma_smoothing: 0.0,
+            onset_gated: false,
+            spectral_whitening: false,
+            energy_measure: EnergyMeasure::Chroma,
             
             quantizer_min_energy: 0.05,
             quantizer_bits_per_bin: 5,
+            quantizer_bits_per_bin_vec: None,
             quantizer_topk: 8,
+            quantizer_soft_assign: false,
 
             search_beam_count: 100,
+            search_adaptive_beams: false,
             search_window_size: 3,
+            search_window_back: 0,
+            search_jump_penalty: 0,
+            search_branch: false,
             search_nonmax_overlap: 1.0,
             search_length_penalty: 3,
             search_score_penalty: 100,
+            search_penalty_mode: PenaltyMode::Fixed,
+            search_score_repr: ScoreRepr::Rational,
+            search_recomb_by: RecombKey::KeyEnd,
+            search_recomb_bucket: 8,
+            search_score_breakdown: false,
+            search_decay: 1.0,
+            search_score_threshold: 10.0,
+            search_max_query_frames: None,
+            search_context_frames: 0,
+            search_max_key_scan: None,
+            search_max_results_per_song: None,
+            search_max_mean_distance: None,
+            search_query_hop: 1,
+            search_prior_weight: 10,
+            search_energy_weight: false,
+            search_contrast_seeding: false,
+            search_idf_seeding: false,
+            search_prefilter: None,
+            search_coarse_factor: None,
+            search_coarse_shortlist: 10,
+            dedup_query_window: 0.0,
+
+            trim_silence_threshold: None,
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::de::{value::{Error, MapDeserializer}, IntoDeserializer};
+
+    #[test]
+    fn test_partial_configuration() {
+        let fields = [("sampleRate", 8000usize), ("searchBeamCount", 64)];
+        let cfg = SessionConfiguration::deserialize(MapDeserializer::<_, Error>::new(fields.into_iter())).unwrap();
+
+        let defaults = SessionConfiguration::default();
+        assert_eq!((cfg.sample_rate, cfg.search_beam_count), (8000, 64));
+        assert_eq!(cfg.window_size, defaults.window_size);
+        assert_eq!(cfg.search_score_threshold, defaults.search_score_threshold);
+
+        // a mistyped option is an error rather than a silent fallback
+        let mistyped = MapDeserializer::<_, Error>::new([("sampleRate", "fast".into_deserializer())].into_iter());
+        assert!(SessionConfiguration::deserialize(mistyped).is_err());
+    }
+}
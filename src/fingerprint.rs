@@ -1,27 +1,139 @@
 extern crate nalgebra as na;
-use std::sync::Arc;
+use std::{borrow::Cow, collections::HashSet, ops::Range, sync::Arc};
 
-use realfft::{num_complex::ComplexFloat, RealFftPlanner, RealToComplex};
+use realfft::{num_complex::{Complex, ComplexFloat}, RealFftPlanner, RealToComplex};
 
 use na::{DMatrix};
 
-use crate::config::SessionConfiguration;
+use crate::config::{ConfigError, SessionConfiguration};
+use serde::Deserialize;
 
+/// Taper applied to each analysis window before the FFT.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum WindowFunction {
+    Hann,
+    Hamming,
+    Blackman,
+}
+
+/// How FFT magnitudes are normalized before the chroma projection.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum MagnitudeScale {
+    /// divide by `sqrt(window_size)`
+    SqrtN,
+    /// divide by half the window's coherent gain (the sum of its coefficients), so a
+    /// sinusoid's peak bin reads as its amplitude whatever the window
+    CoherentGain,
+}
+
+/// How two-channel audio is reduced to one feature per frame.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum StereoMode {
+    /// average the channels, which cancels out-of-phase content
+    Downmix,
+    /// fingerprint each channel and OR the features' bits together
+    Fuse,
+}
+
+/// How each window's spectrum is reduced to chroma bins.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum ChromaTransform {
+    /// one FFT per window, projected onto gaussians around each pitch
+    FftProjection,
+    /// a constant-Q transform: each pitch gets its own kernel, long enough to hold
+    /// its frequency apart from its neighbours' (up to `window_size`), so low notes
+    /// keep their resolution. `spectral_whitening` doesn't apply.
+    ConstantQ,
+}
+
+/// What a frame's energy, as reported alongside its feature, measures.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum EnergyMeasure {
+    /// the sum of the chroma vector, which carries the filterbank's A-weighting, so
+    /// bass-heavy frames read quiet
+    Chroma,
+    /// the sum of the frame's raw FFT magnitudes, unweighted, so any audible content
+    /// reads as loud as it is
+    Spectrum,
+}
+
+/// How the ends of a clip are handled before it's cut into windows.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum EdgeMode {
+    /// window the audio as is, so frame `i` starts at sample `i * window_stride`
+    Truncate,
+    /// pad both ends with half a window of zeros, so frame `i` is centered on
+    /// sample `i * window_stride`
+    ZeroPad,
+    /// like `ZeroPad`, padding with the audio mirrored about its first and last samples
+    Reflect,
+}
+
+#[derive(PartialEq)]
 pub struct FeatureExtractorConfiguration {
     sample_rate: usize,
     window_size: usize,
     window_stride: usize,
+    window_function: WindowFunction,
+    magnitude_scale: MagnitudeScale,
+    stereo_mode: StereoMode,
+    /// applies to batch extraction; streaming always truncates
+    edge_mode: EdgeMode,
 
     chroma_n_octaves: usize, 
     chroma_bins_per_octave: usize,
     chroma_f_ref: f32,
     chroma_q_factor: f32,
+    chroma_transform: ChromaTransform,
+
+    pad_final_window: bool,
+
+    /// zero-pad audio shorter than one window into a single feature; when off,
+    /// such audio yields no features at all
+    pad_short_audio: bool,
+
+    /// one-pole smoothing of each chroma bin over time, in `[0, 1)`. 0 disables it;
+    /// larger values track sustained notes better but smear onsets, lagging by
+    /// roughly `s / (1 - s)` frames.
+    chroma_smoothing: f32,
+
+    /// keep only the frames at note attacks, found as peaks of the spectral flux.
+    /// Applies to `features`, `features_indexed` and `features_transposed`;
+    /// streaming extraction stays dense.
+    onset_gated: bool,
+
+    /// scale each octave of every spectrum to unit mean magnitude before the chroma
+    /// projection, so EQ differences between recordings don't tilt the chroma
+    spectral_whitening: bool,
+
+    /// the energy reported with each frame, which `Session::explain` tells silence by
+    /// and `search_energy_weight` weights frames by; features are unaffected
+    energy_measure: EnergyMeasure,
 
     quantizer_min_energy: f32,
     quantizer_bits_per_bin: usize,
+
+    /// per-bin widths overriding `quantizer_bits_per_bin`, one per chroma bin,
+    /// summing to at most 64
+    quantizer_bits_per_bin_vec: Option<Vec<usize>>,
+
     quantizer_topk: usize,
+
+    /// rank bins by their magnitude blended with their neighbours', so a peak
+    /// between two pitch classes ranks consistently
+    quantizer_soft_assign: bool,
 }
 
+/// A query frame as the search consumes it: its index, feature, energy (the sum of
+/// its chroma vector) and spectral contrast (see `spectral_contrast`).
+pub(crate) type Frame = (usize, Feature, f32, f32);
+
 #[derive(Clone, Copy)]
 pub struct Feature(u64);
 
@@ -29,6 +141,35 @@ impl Feature {
     pub fn distance(&self, other: &Self) -> u32 {
         (self.0 ^ other.0).count_ones()
     }
+
+    /// `distance` to each of `keys`, four at a time with AVX2 on x86-64 CPUs that
+    /// have it. Other targets, wasm included, take the scalar loop.
+    pub fn distance_to_slice(&self, keys: &[Feature]) -> Vec<u32> {
+        #[cfg(target_arch = "x86_64")]
+        if std::arch::is_x86_feature_detected!("avx2") {
+            // SAFETY: the CPU was just checked for AVX2
+            return unsafe { distance_to_slice_avx2(self.0, keys) }
+        }
+
+        keys.iter().map(|key| self.distance(key)).collect()
+    }
+
+    /// Circularly rotates the `n_bins` packed `bits_per_bin`-wide fields by `bins`
+    /// positions, moving bin `i` to bin `i + bins` with its thermometer code intact.
+    /// Bits above the packed fields are left alone. Panics if the fields don't fit
+    /// in 64 bits.
+    pub fn transpose(&self, bins: isize, bits_per_bin: usize, n_bins: usize) -> Feature {
+        let width = bits_per_bin * n_bins;
+        assert!(width <= 64, "{n_bins} bins of {bits_per_bin} bits don't fit in a feature");
+        if width == 0 { return *self }
+
+        let mask = u64::MAX.checked_shr(64 - width as u32).unwrap_or(0);
+        let shift = bins.rem_euclid(n_bins as isize) as usize * bits_per_bin;
+        let fields = self.0 & mask;
+        let rotated = (fields << shift | fields.checked_shr((width - shift) as u32).unwrap_or(0)) & mask;
+
+        Feature(self.0 & !mask | rotated)
+    }
 }
 impl AsMut<u64> for Feature {
     fn as_mut(&mut self) -> &mut u64 { &mut self.0 } }
@@ -37,21 +178,208 @@ impl AsRef<u64> for Feature {
 impl From<u64> for Feature {
     fn from(value: u64) -> Self { Self(value) } }
 
+/// How many of the thermometer levels a `(bits_per_bin, topk)` pair actually reaches.
+/// The quantizer maps the `i`th of the top-k bins to level `i * (bits_per_bin + 1) / topk`,
+/// so a topk smaller than `bits_per_bin + 1` skips levels and wastes bits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QuantizerLevels {
+    pub used: usize,
+    pub available: usize,
+}
+
+impl QuantizerLevels {
+    pub fn new(bits_per_bin: usize, topk: usize) -> Self {
+        let levels: HashSet<usize> = (0..topk)
+            .map(|new_index| new_index * (bits_per_bin + 1) / topk)
+            .collect();
+
+        Self { used: levels.len(), available: bits_per_bin + 1 }
+    }
+
+    pub fn is_full(&self) -> bool { self.used == self.available }
+
+    /// The smallest topk (at most `n_bins`) that reaches every level exactly once.
+    pub fn suggest_topk(bits_per_bin: usize, n_bins: usize) -> usize {
+        (bits_per_bin + 1).min(n_bins)
+    }
+}
+
+/// How finely the FFT resolves the chroma bins; see
+/// `FeatureExtractor::frequency_resolution_report`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResolutionReport {
+    /// spacing of the FFT bins
+    pub hz_per_bin: f32,
+    /// the lowest frequency whose neighbouring chroma bin is more than one FFT bin away
+    pub lowest_resolvable_hz: f32,
+    /// every octave of the filterbank below nyquist, lowest first
+    pub octaves: Vec<OctaveResolution>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OctaveResolution {
+    /// center of the octave's first chroma bin
+    pub low_hz: f32,
+    /// gap between the octave's two lowest, and so closest, chroma bin centers
+    pub spacing_hz: f32,
+    /// standard deviation of the first bin's gaussian filter, `low_hz / chroma_q_factor`
+    pub filter_width_hz: f32,
+    /// whether `spacing_hz` exceeds one FFT bin, so neighbouring pitch classes land
+    /// on different bins
+    pub resolved: bool,
+}
+
+/// Features stored as LEB128 varints of the XOR against the previous feature.
+/// Sustained audio repeats or barely changes its features, so most deltas
+/// shrink to a byte or two. Decoding is sequential, matching how search scans keys.
+#[derive(Clone)]
+pub struct CompressedSong {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+pub struct CompressedIter<'a> {
+    bytes: &'a [u8],
+    prev: u64,
+}
+
+impl CompressedSong {
+    pub fn len(&self) -> usize { self.len }
+
+    pub fn is_empty(&self) -> bool { self.len == 0 }
+
+    /// bytes held by the encoded stream
+    pub fn byte_len(&self) -> usize { self.bytes.len() }
+
+    pub fn iter(&self) -> CompressedIter<'_> {
+        CompressedIter { bytes: &self.bytes, prev: 0 }
+    }
+}
+
+/// Leading byte of `CompressedSong::to_base64` strings, bumped whenever the layout changes.
+const BASE64_VERSION: u8 = 1;
+const BASE64_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+impl CompressedSong {
+    /// Encodes the song as a version byte followed by its delta stream, in unpadded
+    /// URL-safe base64, for sharing a fingerprint in a link or QR code.
+    pub fn to_base64(&self) -> String {
+        let bytes: Vec<u8> = std::iter::once(BASE64_VERSION).chain(self.bytes.iter().copied()).collect();
+
+        bytes
+            .chunks(3)
+            .flat_map(|chunk| {
+                let group = chunk.iter().enumerate().fold(0u32, |acc, (i, &b)| acc | (b as u32) << (16 - 8 * i));
+                (0..=chunk.len()).map(move |i| BASE64_ALPHABET[(group >> (18 - 6 * i)) as usize & 0x3f] as char)
+            })
+            .collect()
+    }
+
+    /// Parses a string from `to_base64`, or `None` if it is malformed or from an
+    /// unknown version.
+    pub fn from_base64(s: &str) -> Option<Self> {
+        let sextets: Vec<u32> = s.bytes()
+            .map(|c| BASE64_ALPHABET.iter().position(|&a| a == c).map(|v| v as u32))
+            .collect::<Option<_>>()?;
+
+        if sextets.len() % 4 == 1 { return None }
+
+        let bytes: Vec<u8> = sextets
+            .chunks(4)
+            .flat_map(|chunk| {
+                let group = chunk.iter().enumerate().fold(0u32, |acc, (i, &v)| acc | v << (18 - 6 * i));
+                (0..chunk.len() - 1).map(move |i| (group >> (16 - 8 * i)) as u8)
+            })
+            .collect();
+
+        let (&BASE64_VERSION, bytes) = bytes.split_first()? else { return None };
+
+        // every varint must be terminated and fit in 64 bits
+        let mut len = 0;
+        let mut run = 0;
+        for &byte in bytes {
+            run += 1;
+            if run > 10 { return None }
+            if byte & 0x80 == 0 { len += 1; run = 0; }
+        }
+        if run != 0 { return None }
+
+        Some(Self { bytes: bytes.to_vec(), len })
+    }
+}
+
+impl From<&[Feature]> for CompressedSong {
+    fn from(features: &[Feature]) -> Self {
+        let mut bytes = Vec::with_capacity(features.len() * 2);
+        let mut prev = 0u64;
+
+        for feature in features {
+            let mut delta = feature.0 ^ prev;
+            prev = feature.0;
+
+            while delta >= 0x80 {
+                bytes.push((delta as u8) | 0x80);
+                delta >>= 7;
+            }
+            bytes.push(delta as u8);
+        }
+
+        bytes.shrink_to_fit();
+        Self { bytes, len: features.len() }
+    }
+}
+
+impl Iterator for CompressedIter<'_> {
+    type Item = Feature;
+
+    fn next(&mut self) -> Option<Feature> {
+        let mut delta = 0u64;
+        let mut shift = 0;
+
+        loop {
+            let (&byte, rest) = self.bytes.split_first()?;
+            self.bytes = rest;
+
+            delta |= ((byte & 0x7f) as u64) << shift;
+            shift += 7;
+
+            if byte & 0x80 == 0 { break }
+        }
+
+        self.prev ^= delta;
+        Some(Feature(self.prev))
+    }
+}
+
 impl From<&SessionConfiguration> for FeatureExtractorConfiguration {
     fn from(value: &SessionConfiguration) -> Self {
         Self {
             sample_rate: value.sample_rate,
             window_size: value.window_size,
             window_stride: value.window_stride,
+            window_function: value.window_function,
+            magnitude_scale: value.magnitude_scale,
+            stereo_mode: value.stereo_mode,
+            edge_mode: value.edge_mode,
 
             chroma_n_octaves: value.chroma_n_octaves,
             chroma_bins_per_octave: value.chroma_bins_per_octave,
             chroma_f_ref: value.chroma_f_ref,
             chroma_q_factor: value.chroma_q_factor,
+            chroma_transform: value.chroma_transform,
+
+            pad_final_window: value.pad_final_window,
+            pad_short_audio: value.pad_short_audio,
+            chroma_smoothing: value.chroma_smoothing,
+            onset_gated: value.onset_gated,
+            spectral_whitening: value.spectral_whitening,
+            energy_measure: value.energy_measure,
 
             quantizer_min_energy: value.quantizer_min_energy,
             quantizer_bits_per_bin: value.quantizer_bits_per_bin,
+            quantizer_bits_per_bin_vec: value.quantizer_bits_per_bin_vec.clone(),
             quantizer_topk: value.quantizer_topk,
+            quantizer_soft_assign: value.quantizer_soft_assign,
         }
     }
 }
@@ -59,48 +387,474 @@ impl From<&SessionConfiguration> for FeatureExtractorConfiguration {
 
 pub struct FeatureExtractor {
     cfg: FeatureExtractorConfiguration,
+    bin_layout: Vec<(usize, usize)>,
     chroma: DMatrix<f32>,
+    /// FFT bins of each octave above `chroma_f_ref`, for `spectral_whitening`
+    octave_bands: Vec<Range<usize>>,
     fft: Arc<dyn RealToComplex<f32>>,
     window: Vec<f32>,
+    /// what `magnitude_scale` multiplies each FFT magnitude by
+    magnitude_factor: f32,
+    /// empty unless `chroma_transform` is `ConstantQ`
+    cq_kernels: Vec<CqKernel>,
+}
+
+/// One pitch's constant-Q kernel: a tapered complex exponential, centred in the
+/// analysis window and pre-scaled by `magnitude_scale` and the pitch's A-weighting.
+struct CqKernel {
+    /// chroma bin the pitch belongs to
+    bin: usize,
+    /// first sample of the window the kernel covers
+    offset: usize,
+    coeffs: Vec<Complex<f32>>,
+}
+
+impl FeatureExtractorConfiguration {
+    fn bin_widths(&self) -> Vec<usize> {
+        self.quantizer_bits_per_bin_vec
+            .clone()
+            .unwrap_or_else(|| vec![self.quantizer_bits_per_bin; self.chroma_bins_per_octave])
+    }
+
+    /// `(bit offset, width)` of each chroma bin within a feature.
+    pub(crate) fn bin_layout(&self) -> Vec<(usize, usize)> {
+        self.bin_widths()
+            .into_iter()
+            .scan(0, |offset, width| {
+                let bin = (*offset, width);
+                *offset += width;
+                Some(bin)
+            })
+            .collect()
+    }
+
+    /// The sample of the original audio frame 0 is centered on: a half window in
+    /// under `Truncate`, the first sample once `edge_mode` pads the start.
+    pub(crate) fn frame_offset(&self) -> usize {
+        match self.edge_mode {
+            EdgeMode::Truncate => self.window_size / 2,
+            EdgeMode::ZeroPad | EdgeMode::Reflect => 0,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let (size, stride) = (self.window_size, self.window_stride);
+        if size == 0 || stride == 0 || stride > size { return Err(ConfigError::Window { size, stride }) }
+
+        let widths = self.bin_widths();
+        if widths.len() != self.chroma_bins_per_octave {
+            return Err(ConfigError::BinWidthCount { expected: self.chroma_bins_per_octave, got: widths.len() })
+        }
+
+        let bits = widths.iter().sum();
+        if bits > 64 { return Err(ConfigError::BitBudget { bits }) }
+
+        let (topk, bins) = (self.quantizer_topk, self.chroma_bins_per_octave);
+        if topk == 0 || topk > bins { return Err(ConfigError::Topk { topk, bins }) }
+
+        if !(0.0..1.0).contains(&self.chroma_smoothing) { return Err(ConfigError::Smoothing(self.chroma_smoothing)) }
+
+        Ok(())
+    }
+
+    /// Levels of the widest bin in `bin_layout`, the one that runs out of ranks first:
+    /// when it is full, every narrower bin is too.
+    pub fn quantizer_levels(&self) -> QuantizerLevels {
+        let widest = self.bin_layout().into_iter().map(|(_, width)| width).max().unwrap_or(self.quantizer_bits_per_bin);
+        QuantizerLevels::new(widest, self.quantizer_topk)
+    }
+
+    /// FNV-1a hash of every setting that shapes the extracted features, stable across
+    /// builds and platforms, so stored features can be tied to the config that made them.
+    pub fn config_hash(&self) -> u64 {
+        let widths = self.quantizer_bits_per_bin_vec.as_deref().unwrap_or(&[]);
+        let words = [
+            self.sample_rate as u64,
+            self.window_size as u64,
+            self.window_stride as u64,
+            self.window_function as u64,
+            self.magnitude_scale as u64,
+            self.chroma_n_octaves as u64,
+            self.chroma_bins_per_octave as u64,
+            self.chroma_f_ref.to_bits() as u64,
+            self.chroma_q_factor.to_bits() as u64,
+            self.pad_final_window as u64,
+            self.pad_short_audio as u64,
+            self.chroma_smoothing.to_bits() as u64,
+            self.onset_gated as u64,
+            self.quantizer_min_energy.to_bits() as u64,
+            self.quantizer_bits_per_bin as u64,
+            self.quantizer_bits_per_bin_vec.is_some() as u64,
+            widths.len() as u64,
+        ];
+
+        fnv1a(words.into_iter()
+            .chain(widths.iter().map(|&w| w as u64))
+            .chain([self.quantizer_topk as u64, self.quantizer_soft_assign as u64, self.spectral_whitening as u64, self.stereo_mode as u64, self.edge_mode as u64, self.chroma_transform as u64]))
+    }
 }
 
 impl From<FeatureExtractorConfiguration> for FeatureExtractor {
+    /// Panics on an invalid configuration; see `try_new`. Unlike `try_new`, a slow
+    /// `window_size` is accepted.
     fn from(cfg: FeatureExtractorConfiguration) -> Self {
+        if let Err(err) = cfg.validate() { panic!("invalid feature extractor configuration: {err}") }
+
+        Self::build(cfg)
+    }
+}
+
+impl FeatureExtractor {
+    /// Like `From`, but returns configuration errors instead of panicking, and also
+    /// rejects a `window_size` with a prime factor too large for a fast FFT.
+    pub fn try_new(cfg: FeatureExtractorConfiguration) -> Result<Self, ConfigError> {
+        cfg.validate()?;
+
+        let size = cfg.window_size;
+        if largest_prime_factor(size) > MAX_FFT_PRIME_FACTOR {
+            return Err(ConfigError::SlowFftSize { size, suggested: nearest_fast_fft_size(size) })
+        }
+
+        Ok(Self::build(cfg))
+    }
+
+    /// Builds an extractor from an already validated configuration.
+    fn build(cfg: FeatureExtractorConfiguration) -> Self {
+
         let chroma = Self::chroma_matrix(&cfg);
+        let octave_bands = Self::octave_bands(&cfg);
+        let bin_layout = cfg.bin_layout();
 
         let mut fft_planner = RealFftPlanner::new();
         let fft = fft_planner.plan_fft_forward(cfg.window_size);
 
-        let omega = std::f32::consts::TAU / (cfg.window_size - 1) as f32;
-        let window: Vec<f32> = (0..cfg.window_size)
-            .map(|i| 0.5 - 0.5 * (i as f32 * omega).cos())
+        let window = taper(cfg.window_function, cfg.window_size);
+        let magnitude_factor = match cfg.magnitude_scale {
+            MagnitudeScale::SqrtN => (cfg.window_size as f32).sqrt().recip(),
+            MagnitudeScale::CoherentGain => 2.0 / window.iter().sum::<f32>(),
+        };
+        let cq_kernels = match cfg.chroma_transform {
+            ChromaTransform::FftProjection => Vec::new(),
+            ChromaTransform::ConstantQ => Self::cq_kernels(&cfg),
+        };
+
+        Self { cfg, bin_layout, chroma, octave_bands, fft, window, magnitude_factor, cq_kernels }
+    }
+}
+
+/// The FFT's input, output and scratch space, kept between spectrograms to spare
+/// reallocating them.
+struct FftBuffers {
+    input: Vec<f32>,
+    output: Vec<Complex<f32>>,
+    scratch: Vec<Complex<f32>>,
+}
+
+impl FftBuffers {
+    fn new(extractor: &FeatureExtractor) -> Self {
+        Self {
+            input: extractor.fft.make_input_vec(),
+            output: extractor.fft.make_output_vec(),
+            scratch: extractor.fft.make_scratch_vec(),
+        }
+    }
+}
+
+/// Overlap-and-save feature extraction over audio arriving in chunks.
+///
+/// Between pushes it keeps the samples from the start of the next window onwards:
+/// the `window_size - window_stride` overlap, plus any part of a stride not yet
+/// covered by a whole window. Windows therefore land exactly where a batch call
+/// would put them, and smoothing carries over from the last emitted frame.
+/// Under `ZeroPad` and `Reflect` the head padding goes in once the first half
+/// window has arrived, and the tail padding at `finish`, as `pad_edges` does.
+pub struct StreamingExtractor<'a> {
+    extractor: &'a FeatureExtractor,
+    buffer: Vec<f32>,
+    /// windows emitted so far
+    emitted: usize,
+    /// whether the head padding is still to be added
+    head_pending: bool,
+    /// the last `window_size / 2 + 1` samples pushed, to reflect the tail from
+    recent: Vec<f32>,
+    /// last smoothed chroma vector, for `chroma_smoothing`
+    prev: Option<Vec<f32>>,
+}
+
+impl StreamingExtractor<'_> {
+    /// Appends `audio`, returning the features of every window it completes.
+    pub fn push(&mut self, audio: &[f32]) -> Vec<Feature> {
+        let audio = sanitize(audio);
+        self.buffer.extend_from_slice(&audio);
+
+        if self.extractor.cfg.edge_mode != EdgeMode::Truncate {
+            let keep = self.extractor.cfg.window_size / 2 + 1;
+            self.recent.extend_from_slice(&audio[audio.len().saturating_sub(keep)..]);
+            self.recent.drain(..self.recent.len().saturating_sub(keep));
+
+            // reflecting the head needs the first `keep` samples
+            if self.head_pending {
+                if self.buffer.len() < keep { return Vec::new() }
+                self.pad_head();
+            }
+        }
+
+        self.drain_windows()
+    }
+
+    /// Emits every whole window in the buffer and drops the samples no later
+    /// window needs.
+    fn drain_windows(&mut self) -> Vec<Feature> {
+        let cfg = &self.extractor.cfg;
+
+        let windows: Vec<&[f32]> = self.buffer
+            .windows(cfg.window_size)
+            .step_by(cfg.window_stride)
             .collect();
+        let count = windows.len();
+
+        let features = Self::emit(self.extractor, &mut self.prev, &windows);
+
+        self.buffer.drain(..count * cfg.window_stride);
+        self.emitted += count;
+
+        features
+    }
+
+    /// Ends the stream, emitting the padded windows `pad_short_audio` and
+    /// `pad_final_window` would add at the end of a batch call.
+    pub fn finish(mut self) -> Vec<Feature> {
+        let mut features = Vec::new();
+
+        if !self.recent.is_empty() {
+            if self.head_pending { self.pad_head() }
+
+            let (pad, len) = (self.extractor.cfg.window_size / 2, self.recent.len());
+            let reflect = self.extractor.cfg.edge_mode == EdgeMode::Reflect;
+            let tail = (1..=pad).map(|d| if reflect && d < len { self.recent[len - 1 - d] } else { 0.0 });
+            self.buffer.extend(tail);
+
+            features = self.drain_windows();
+        }
+
+        let cfg = &self.extractor.cfg;
+        let buffer = std::mem::take(&mut self.buffer);
+
+        let mut windows: Vec<&[f32]> = Vec::new();
+
+        if self.emitted == 0 && cfg.pad_short_audio && !buffer.is_empty() {
+            windows.push(&buffer);
+        }
+
+        if cfg.pad_final_window {
+            let tail_start = windows.len() * cfg.window_stride;
+            if buffer.len().saturating_sub(tail_start) > cfg.window_size / 2 {
+                windows.push(&buffer[tail_start..]);
+            }
+        }
+
+        features.extend(Self::emit(self.extractor, &mut self.prev, &windows));
+        features
+    }
+
+    /// Prepends the `ZeroPad` or `Reflect` head; the buffer still holds the
+    /// stream from its first sample.
+    fn pad_head(&mut self) {
+        let (pad, len) = (self.extractor.cfg.window_size / 2, self.buffer.len());
+        let reflect = self.extractor.cfg.edge_mode == EdgeMode::Reflect;
+
+        let head: Vec<f32> = (1..=pad).rev().map(|d| if reflect && d < len { self.buffer[d] } else { 0.0 }).collect();
+        self.buffer.splice(0..0, head);
+        self.head_pending = false;
+    }
+
+    fn emit(extractor: &FeatureExtractor, prev: &mut Option<Vec<f32>>, windows: &[&[f32]]) -> Vec<Feature> {
+        if windows.is_empty() { return Vec::new() }
+
+        let mut chroma_vectors = extractor.project(windows);
+        extractor.smooth(&mut chroma_vectors, prev.as_deref());
+
+        let last = chroma_vectors.nrows() - 1;
+        *prev = Some(chroma_vectors.row(last).iter().copied().collect());
 
-        Self { cfg, chroma, fft, window }
+        extractor.quantize(&chroma_vectors)
     }
 }
 
-impl FeatureExtractor {
+/// `Feature::distance_to_slice` with AVX2: popcounts each byte of the XOR through a
+/// nibble lookup table, then sums a feature's eight bytes with `vpsadbw`.
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+unsafe fn distance_to_slice_avx2(query: u64, keys: &[Feature]) -> Vec<u32> {
+    use std::arch::x86_64::*;
 
-    fn a_curve(f: f32) -> f32 {
-        const C0_SQ: f32 = 20.6 * 20.6;
-        const C1_SQ: f32 = 107.7 * 107.7;
-        const C2_SQ: f32 = 737.9 * 737.9;
-        const C3: f32 = 12194.0;
-        const C3_SQ: f32 = C3 * C3;
+    let lookup = _mm256_setr_epi8(
+        0, 1, 1, 2, 1, 2, 2, 3, 1, 2, 2, 3, 2, 3, 3, 4,
+        0, 1, 1, 2, 1, 2, 2, 3, 1, 2, 2, 3, 2, 3, 3, 4,
+    );
+    let low_nibble = _mm256_set1_epi8(0x0f);
+    let broadcast = _mm256_set1_epi64x(query as i64);
 
-        if f <= 0.0 { return 0.0 }
+    let mut distances = Vec::with_capacity(keys.len());
+    let chunks = keys.chunks_exact(4);
+    let remainder = chunks.remainder();
 
-        let f_sq = f.powi(2);
+    for chunk in chunks {
+        let x = _mm256_xor_si256(broadcast, _mm256_setr_epi64x(chunk[0].0 as i64, chunk[1].0 as i64, chunk[2].0 as i64, chunk[3].0 as i64));
+        let lo = _mm256_and_si256(x, low_nibble);
+        let hi = _mm256_and_si256(_mm256_srli_epi16::<4>(x), low_nibble);
+        let bytes = _mm256_add_epi8(_mm256_shuffle_epi8(lookup, lo), _mm256_shuffle_epi8(lookup, hi));
+        let sums = _mm256_sad_epu8(bytes, _mm256_setzero_si256());
 
-        let num_log = 2.0 * C3.ln() + 4.0 * f.ln();
-        let denom_log = (f_sq + C0_SQ).ln()
-            + 0.5 * (f_sq + C1_SQ).ln()
-            + 0.5 * (f_sq + C2_SQ).ln()
-            + (f_sq + C3_SQ).ln();
-        
-        (num_log - denom_log).exp()
+        distances.extend([
+            _mm256_extract_epi64::<0>(sums) as u32,
+            _mm256_extract_epi64::<1>(sums) as u32,
+            _mm256_extract_epi64::<2>(sums) as u32,
+            _mm256_extract_epi64::<3>(sums) as u32,
+        ]);
+    }
+
+    distances.extend(remainder.iter().map(|key| (query ^ key.0).count_ones()));
+    distances
+}
+
+/// `len` coefficients of `window_function`.
+fn taper(window_function: WindowFunction, len: usize) -> Vec<f32> {
+    let omega = std::f32::consts::TAU / len.saturating_sub(1).max(1) as f32;
+
+    (0..len)
+        .map(|i| {
+            let x = i as f32 * omega;
+            match window_function {
+                WindowFunction::Hann => 0.5 - 0.5 * x.cos(),
+                WindowFunction::Hamming => 0.54 - 0.46 * x.cos(),
+                WindowFunction::Blackman => 0.42 - 0.5 * x.cos() + 0.08 * (2.0 * x).cos(),
+            }
+        })
+        .collect()
+}
+
+/// `audio` with any `NaN` or infinite samples replaced by silence, borrowed if
+/// there were none.
+fn sanitize(audio: &[f32]) -> Cow<'_, [f32]> {
+    if audio.iter().all(|sample| sample.is_finite()) { return Cow::Borrowed(audio) }
+
+    Cow::Owned(audio.iter().map(|&sample| if sample.is_finite() { sample } else { 0.0 }).collect())
+}
+
+/// Scales 16-bit PCM by 2^-15 into `[-1, 1)`.
+#[cfg(feature = "wasm")]
+pub(crate) fn pcm_i16_to_f32(audio: &[i16]) -> Vec<f32> {
+    audio.iter().map(|&s| s as f32 / 32768.0).collect()
+}
+
+/// Marks the frames where a note starts: local peaks of the spectral flux (the summed
+/// rise in magnitude since the previous frame) that stand above its mean.
+fn onset_frames(spectrogram: &DMatrix<f32>) -> Vec<bool> {
+    let flux: Vec<f32> = (0..spectrogram.nrows())
+        .map(|t| match t {
+            0 => spectrogram.row(0).sum(),
+            _ => spectrogram.row(t)
+                .iter()
+                .zip(spectrogram.row(t - 1).iter())
+                .map(|(now, before)| (now - before).max(0.0))
+                .sum(),
+        })
+        .collect();
+
+    let mean = flux.iter().sum::<f32>() / flux.len().max(1) as f32;
+
+    (0..flux.len())
+        .map(|t| {
+            let rising = t == 0 || flux[t] > flux[t - 1];
+            let peak = flux.get(t + 1).is_none_or(|&next| flux[t] >= next);
+            rising && peak && flux[t] > mean
+        })
+        .collect()
+}
+
+/// How peaky a chroma vector is, in `[0, 1]`: `1 - mean / max`. Near 1 for a lone
+/// pitch class, 0 for a flat vector or silence.
+pub fn spectral_contrast(chroma_vector: impl Iterator<Item = f32>) -> f32 {
+    let (count, sum, max) = chroma_vector.fold((0, 0.0, 0.0f32), |(count, sum, max), v| (count + 1, sum + v, max.max(v)));
+    if max <= 0.0 { return 0.0 }
+
+    1.0 - sum / count as f32 / max
+}
+
+/// The thermometer code for the `rank`th of the top `topk` bins in a `width`-bit
+/// field: `rank * (width + 1) / topk` low bits set. `rank < topk` keeps that level at
+/// most `width`, so the code never spills into the next bin's field.
+fn thermometer_code(rank: usize, width: usize, topk: usize) -> u64 {
+    let level = rank * (width + 1) / topk;
+    u64::MAX.checked_shr(64 - level as u32).unwrap_or(0)
+}
+
+/// 64-bit FNV-1a over the little-endian bytes of `words`.
+pub(crate) fn fnv1a(words: impl Iterator<Item = u64>) -> u64 {
+    words
+        .flat_map(u64::to_le_bytes)
+        .fold(0xcbf2_9ce4_8422_2325, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+/// Largest prime factor a `window_size` may have. The FFT has hardcoded butterflies
+/// up to here; larger primes fall back to algorithms many times slower.
+const MAX_FFT_PRIME_FACTOR: usize = 31;
+
+fn largest_prime_factor(mut n: usize) -> usize {
+    let mut largest = 1;
+    let mut p = 2;
+    while p * p <= n {
+        while n.is_multiple_of(p) { n /= p; largest = p; }
+        p += 1;
     }
+    largest.max(n)
+}
+
+/// The smallest size at least `n` whose only prime factors are 2, 3 and 5, which
+/// the FFT handles fastest.
+pub fn nearest_fast_fft_size(n: usize) -> usize {
+    (n.max(1)..)
+        .find(|&m| {
+            let mut m = m;
+            for p in [2, 3, 5] { while m.is_multiple_of(p) { m /= p; } }
+            m == 1
+        })
+        .unwrap()
+}
+
+/// Linear IEC 61672 A-weighting gain at `freq_hz`, normalized to 1.0 at 1 kHz.
+/// The chroma filterbank weights its bins by the same curve without the
+/// normalization, about 2 dB lower, which the energy thresholds are tuned to.
+pub fn a_weight(freq_hz: f32) -> f32 {
+    // the curve's +2.0 dB offset, as a log amplitude
+    const A1000: f32 = 0.1 * std::f32::consts::LN_10;
+
+    a_curve(freq_hz) * A1000.exp()
+}
+
+/// The A-weighting curve as the filterbank applies it, `a_weight` less its +2 dB.
+fn a_curve(freq_hz: f32) -> f32 {
+    const C0_SQ: f32 = 20.6 * 20.6;
+    const C1_SQ: f32 = 107.7 * 107.7;
+    const C2_SQ: f32 = 737.9 * 737.9;
+    const C3: f32 = 12194.0;
+    const C3_SQ: f32 = C3 * C3;
+
+    if freq_hz <= 0.0 { return 0.0 }
+
+    let f_sq = freq_hz.powi(2);
+
+    let num_log = 2.0 * C3.ln() + 4.0 * freq_hz.ln();
+    let denom_log = (f_sq + C0_SQ).ln()
+        + 0.5 * (f_sq + C1_SQ).ln()
+        + 0.5 * (f_sq + C2_SQ).ln()
+        + (f_sq + C3_SQ).ln();
+    
+    (num_log - denom_log).exp()
+}
+
+impl FeatureExtractor {
 
     fn chroma_matrix(cfg: &FeatureExtractorConfiguration) -> DMatrix<f32> {
         /*
@@ -110,6 +864,7 @@ impl FeatureExtractor {
         let nrows = cfg.window_size / 2 + 1;
         let ncols = cfg.chroma_bins_per_octave;
         let bin_step = (cfg.chroma_bins_per_octave as f32).recip();
+        let nyquist = cfg.sample_rate as f32 / 2.0;
 
         DMatrix::from_fn(nrows, ncols, |fft_index, bin_index| {
             // row is the sample index within FFT
@@ -117,10 +872,11 @@ impl FeatureExtractor {
             let row_freq = (cfg.sample_rate as f32) * (fft_index as f32) / (cfg.window_size as f32);
 
             let bin_factor: f32 = (0..cfg.chroma_n_octaves)
-                .map(| octave | {
-                    let octave_frac = octave as f32 + bin_index as f32 * bin_step;
-                    let tone_freq = octave_frac.exp2() * cfg.chroma_f_ref;
-                    
+                .map(| octave | (octave as f32 + bin_index as f32 * bin_step).exp2() * cfg.chroma_f_ref)
+                // tones past nyquist can't be represented; their tails would only
+                // leak phantom energy into the top rows
+                .filter(|&tone_freq| tone_freq <= nyquist)
+                .map(| tone_freq | {
                     // sigma = target tone center / q
                     // z = (tone - fft tone) / sigma
                     //   = (tone - fft tone) * q / target tone
@@ -130,75 +886,541 @@ impl FeatureExtractor {
                 })
                 .sum();
             
-            bin_factor * Self::a_curve(row_freq)
+            bin_factor * a_curve(row_freq)
         })
     }
 
-    pub fn features(&self, audio: &[f32]) -> Vec<Feature> {
-        let cfg = &self.cfg;
-
-        // build spectogram of audio
+    /// One kernel per pitch below nyquist. A kernel spans `q / f` seconds, where
+    /// `q = 1 / (2^(1/bins) - 1)` puts neighbouring pitches one DFT bin apart, capped
+    /// at `window_size`.
+    fn cq_kernels(cfg: &FeatureExtractorConfiguration) -> Vec<CqKernel> {
+        let bins = cfg.chroma_bins_per_octave;
+        let nyquist = cfg.sample_rate as f32 / 2.0;
+        let q = ((bins as f32).recip().exp2() - 1.0).recip();
 
-        let mut input = self.fft.make_input_vec();
-        let mut output = self.fft.make_output_vec();
-        let mut scratch = self.fft.make_scratch_vec();
+        (0..cfg.chroma_n_octaves)
+            .flat_map(|octave| (0..bins).map(move |bin| (bin, (octave as f32 + bin as f32 / bins as f32).exp2() * cfg.chroma_f_ref)))
+            .filter(|&(_, freq)| freq <= nyquist)
+            .map(|(bin, freq)| {
+                let len = ((q * cfg.sample_rate as f32 / freq).round() as usize).clamp(2, cfg.window_size);
+                let taper = taper(cfg.window_function, len);
+                let scale = a_curve(freq) * match cfg.magnitude_scale {
+                    MagnitudeScale::SqrtN => (len as f32).sqrt().recip(),
+                    MagnitudeScale::CoherentGain => 2.0 / taper.iter().sum::<f32>(),
+                };
+                let omega = -std::f32::consts::TAU * freq / cfg.sample_rate as f32;
 
-        let windows = audio
-            .windows(cfg.window_size)
-            .step_by(cfg.window_stride);
+                let coeffs = taper.iter()
+                    .enumerate()
+                    .map(|(n, w)| Complex::from_polar(w * scale, omega * n as f32))
+                    .collect();
 
-        let mut spectrogram: DMatrix<f32> = DMatrix::zeros(windows.len(), output.len());
+                CqKernel { bin, offset: (cfg.window_size - len) / 2, coeffs }
+            })
+            .collect()
+    }
 
-        for (chunk_index, chunk) in windows.enumerate() {
-            input[..chunk.len()]
-                .iter_mut()
-                .enumerate()
-                .for_each(|(i, s)| *s = chunk[i] * self.window[i]);
-            input[chunk.len()..].fill(0.0);
+    /// Splits the FFT bins into octaves starting at `chroma_f_ref`; the first band
+    /// also takes the bins below it and the last those above the top octave.
+    fn octave_bands(cfg: &FeatureExtractorConfiguration) -> Vec<Range<usize>> {
+        let nrows = cfg.window_size / 2 + 1;
+        let hz_per_bin = cfg.sample_rate as f32 / cfg.window_size as f32;
 
-            let _ = self.fft.process_with_scratch(&mut input, &mut output, &mut scratch);
-            let normalizing_factor = (cfg.window_size as f32).sqrt().recip();
+        let mut edges: Vec<usize> = (1..cfg.chroma_n_octaves)
+            .map(|octave| ((octave as f32).exp2() * cfg.chroma_f_ref / hz_per_bin).ceil() as usize)
+            .map(|edge| edge.min(nrows))
+            .collect();
+        edges.insert(0, 0);
+        edges.push(nrows);
+        edges.dedup();
 
-            for i in 0..output.len() {
-                spectrogram[(chunk_index, i)] = output[i].abs() * normalizing_factor;
-            }
-        }
+        edges.windows(2).map(|edge| edge[0]..edge[1]).collect()
+    }
 
-        // downproject to chroma vectors
-        let chroma_vectors = spectrogram * &self.chroma;
-        
-        // quantize chroma vectors
-        
-        let mut features = Vec::with_capacity(chroma_vectors.shape().0);
-        
-        let mut sorted_chroma = Vec::with_capacity(chroma_vectors.shape().1);
-        for chroma_vector in chroma_vectors.row_iter() {
-            // need to implement median filtering?
+    /// Reports whether `window_size` and `sample_rate` leave neighbouring chroma bins
+    /// resolvable in each octave. Raising `window_size` (or lowering `sample_rate`)
+    /// narrows the FFT bins and pushes the lowest resolvable note down.
+    pub fn frequency_resolution_report(&self) -> ResolutionReport {
+        let cfg = &self.cfg;
+        let hz_per_bin = cfg.sample_rate as f32 / cfg.window_size as f32;
+        let nyquist = cfg.sample_rate as f32 / 2.0;
+        // ratio of neighbouring bin centers, less one
+        let step = (cfg.chroma_bins_per_octave as f32).recip().exp2() - 1.0;
 
-            sorted_chroma.extend(chroma_vector.iter().enumerate().map(|(i, &v)| (v, i)));
+        let octaves = (0..cfg.chroma_n_octaves)
+            .map(|octave| (octave as f32).exp2() * cfg.chroma_f_ref)
+            .take_while(|&low_hz| low_hz <= nyquist)
+            .map(|low_hz| OctaveResolution {
+                low_hz,
+                spacing_hz: low_hz * step,
+                filter_width_hz: low_hz / cfg.chroma_q_factor,
+                resolved: low_hz * step > hz_per_bin,
+            })
+            .collect();
 
-            sorted_chroma.sort_unstable_by(|a, b| a.0.total_cmp(&b.0));
-            // sorted chroma is now in ascended order. the percentile of the element
-            // at index i is its position in this new array
-            let feature = sorted_chroma.drain(sorted_chroma.len()-cfg.quantizer_topk..)
-                .enumerate()
-                .map(|(new_index, (_, old_index))| {
-                    let bin = new_index * (cfg.quantizer_bits_per_bin + 1) / cfg.quantizer_topk;
-                    let tempcode = (1u64 << bin) - 1;
-                    tempcode << (old_index * cfg.quantizer_bits_per_bin)
-                })
-                .reduce(|a, b| a | b)
-                .unwrap_or(0)
-                .into();
+        ResolutionReport { hz_per_bin, lowest_resolvable_hz: hz_per_bin / step, octaves }
+    }
 
-            features.push(feature);
+    pub fn sample_rate(&self) -> usize { self.cfg.sample_rate }
 
-            sorted_chroma.clear();
-        }
+    /// Seconds between consecutive frames.
+    pub fn stride_dt(&self) -> f32 { self.cfg.window_stride as f32 / self.cfg.sample_rate as f32 }
 
-        features
+    /// Runs one silent window through the whole pipeline so FFT twiddles, scratch
+    /// allocation and the chroma matrix are touched before the first real extraction.
+    pub fn warmup(&self) {
+        let silence = vec![0.0; self.cfg.window_size];
+        let _ = self.quantize(&self.chroma_vectors(&silence));
     }
-}
+
+    /// The span of `audio` from the first to the last stride-long block whose RMS
+    /// exceeds `threshold`, extended so every window starting in it is whole. The
+    /// start falls on a stride, so frame `i` of the trimmed audio is frame
+    /// `i + start / window_stride` of the original. Empty if nothing is that loud.
+    pub fn silence_bounds(&self, audio: &[f32], threshold: f32) -> Range<usize> {
+        let stride = self.cfg.window_stride;
+        let loud = |block: &[f32]| (block.iter().map(|s| s * s).sum::<f32>() / block.len() as f32).sqrt() > threshold;
+
+        let Some(first) = audio.chunks(stride).position(loud) else { return 0..0 };
+        let last = audio.chunks(stride).rposition(loud).unwrap_or(first);
+
+        first * stride..(last * stride + self.cfg.window_size).min(audio.len())
+    }
+
+    /// `audio` without its leading and trailing silence; see `silence_bounds`.
+    pub fn trim_silence<'a>(&self, audio: &'a [f32], threshold: f32) -> &'a [f32] {
+        &audio[self.silence_bounds(audio, threshold)]
+    }
+
+    /// Like `features_indexed` over `trim_silence(audio, threshold)`, with frames
+    /// still numbered from the start of `audio`.
+    pub fn features_indexed_trimmed(&self, audio: &[f32], threshold: f32) -> Vec<(usize, Feature)> {
+        let bounds = self.silence_bounds(audio, threshold);
+        let skipped = bounds.start / self.cfg.window_stride;
+
+        self.features_indexed(&audio[bounds])
+            .into_iter()
+            .map(|(frame, feature)| (frame + skipped, feature))
+            .collect()
+    }
+
+    /// Number of features `features` emits for `len` samples of audio.
+    pub fn feature_count_for(&self, len: usize) -> usize {
+        let cfg = &self.cfg;
+        let len = if len > 0 && cfg.edge_mode != EdgeMode::Truncate { len + 2 * (cfg.window_size / 2) } else { len };
+
+        let windows = if len >= cfg.window_size {
+            (len - cfg.window_size) / cfg.window_stride + 1
+        } else {
+            (cfg.pad_short_audio && len > 0) as usize
+        };
+        let padded = cfg.pad_final_window && len.saturating_sub(windows * cfg.window_stride) > cfg.window_size / 2;
+
+        windows + padded as usize
+    }
+
+    pub fn features(&self, audio: &[f32]) -> Vec<Feature> {
+        if !self.cfg.onset_gated { return self.quantize(&self.chroma_vectors(audio)) }
+
+        self.features_indexed(audio).into_iter().map(|(_, feature)| feature).collect()
+    }
+
+    /// Features paired with the frame each was taken from. Frames are consecutive
+    /// unless `onset_gated` drops those between note attacks.
+    pub fn features_indexed(&self, audio: &[f32]) -> Vec<(usize, Feature)> {
+        self.frames(audio)
+            .into_iter()
+            .map(|(frame, feature, _, _)| (frame, feature))
+            .collect()
+    }
+
+    /// Features paired with their energy, by default the sum of the chroma vector
+    /// each was quantized from; see `energy_measure`. Silence reports near zero.
+    pub fn features_with_energy(&self, audio: &[f32]) -> Vec<(Feature, f32)> {
+        self.frames(audio)
+            .into_iter()
+            .map(|(_, feature, energy, _)| (feature, energy))
+            .collect()
+    }
+
+    /// Extracts each clip's `features` in turn, reusing one set of FFT buffers
+    /// across them all; the native entry point for indexing many files.
+    pub fn features_batch<'a>(&'a self, clips: impl Iterator<Item = &'a [f32]> + 'a) -> impl Iterator<Item = Vec<Feature>> + 'a {
+        let mut buffers = FftBuffers::new(self);
+
+        clips.map(move |clip| {
+            self.frames_with(clip, &mut buffers)
+                .into_iter()
+                .map(|(_, feature, _, _)| feature)
+                .collect()
+        })
+    }
+
+    /// Each feature with its frame index, energy and spectral contrast.
+    pub(crate) fn frames(&self, audio: &[f32]) -> Vec<Frame> {
+        self.frames_with(audio, &mut FftBuffers::new(self))
+    }
+
+    fn frames_with(&self, audio: &[f32], buffers: &mut FftBuffers) -> Vec<Frame> {
+        let (chroma_vectors, onsets, spectrum_energy) = self.analyze_with(audio, buffers);
+
+        self.quantize(&chroma_vectors)
+            .into_iter()
+            .zip(chroma_vectors.row_iter())
+            .enumerate()
+            .filter(|(frame, _)| onsets.as_ref().is_none_or(|onsets| onsets[*frame]))
+            .map(|(frame, (feature, chroma_vector))| {
+                let contrast = spectral_contrast(chroma_vector.iter().copied());
+                let energy = spectrum_energy.as_ref().map_or_else(|| chroma_vector.sum(), |energies| energies[frame]);
+                (frame, feature, energy, contrast)
+            })
+            .collect()
+    }
+
+    /// Smoothed chroma vectors, the onset frames under `onset_gated` and the spectrum
+    /// energies under `EnergyMeasure::Spectrum`, all from one pass over the spectrogram.
+    fn analyze_with(&self, audio: &[f32], buffers: &mut FftBuffers) -> (DMatrix<f32>, Option<Vec<bool>>, Option<Vec<f32>>) {
+        let audio = self.pad_edges(audio);
+        let windows = self.windows(&audio);
+
+        let spectrum = |spectrogram: &DMatrix<f32>| -> Option<Vec<f32>> {
+            (self.cfg.energy_measure == EnergyMeasure::Spectrum).then(|| spectrogram.row_iter().map(|row| row.sum()).collect())
+        };
+
+        let (mut chroma_vectors, onsets, spectrum_energy) = match self.cfg.chroma_transform {
+            ChromaTransform::FftProjection => {
+                let mut spectrogram = self.spectrogram_with(&windows, buffers);
+                let onsets = self.cfg.onset_gated.then(|| onset_frames(&spectrogram));
+                let spectrum_energy = spectrum(&spectrogram);
+                self.whiten(&mut spectrogram);
+                (spectrogram * &self.chroma, onsets, spectrum_energy)
+            }
+            // onsets and spectrum energy still come from the FFT
+            ChromaTransform::ConstantQ => {
+                let spectrogram = (self.cfg.onset_gated || self.cfg.energy_measure == EnergyMeasure::Spectrum)
+                    .then(|| self.spectrogram_with(&windows, buffers));
+                let onsets = spectrogram.as_ref().filter(|_| self.cfg.onset_gated).map(onset_frames);
+                let spectrum_energy = spectrogram.as_ref().and_then(spectrum);
+                (self.constant_q(&windows), onsets, spectrum_energy)
+            }
+        };
+        self.smooth(&mut chroma_vectors, None);
+
+        (chroma_vectors, onsets, spectrum_energy)
+    }
+
+    /// Features of two-channel audio, combined by `stereo_mode`. The longer channel
+    /// is cut to the shorter's length.
+    pub fn features_stereo(&self, left: &[f32], right: &[f32]) -> Vec<Feature> {
+        self.frames_stereo(left, right).into_iter().map(|(_, feature, _, _)| feature).collect()
+    }
+
+    /// Like `frames`, for two channels. Fused frames keep every frame either channel
+    /// yields, ORing the features, summing the energies and keeping the higher
+    /// contrast where both do.
+    pub(crate) fn frames_stereo(&self, left: &[f32], right: &[f32]) -> Vec<Frame> {
+        let len = left.len().min(right.len());
+        let (left, right) = (&left[..len], &right[..len]);
+
+        match self.cfg.stereo_mode {
+            StereoMode::Downmix => {
+                let mix: Vec<f32> = left.iter().zip(right).map(|(l, r)| 0.5 * (l + r)).collect();
+                self.frames(&mix)
+            }
+            StereoMode::Fuse => {
+                let mut fused = self.frames(left);
+                fused.extend(self.frames(right));
+                fused.sort_by_key(|&(frame, _, _, _)| frame);
+                fused.dedup_by(|(frame, feature, energy, contrast), (kept_frame, kept_feature, kept_energy, kept_contrast)| {
+                    if frame != kept_frame { return false }
+                    *kept_feature = Feature(kept_feature.0 | feature.0);
+                    *kept_energy += *energy;
+                    *kept_contrast = kept_contrast.max(*contrast);
+                    true
+                });
+                fused
+            }
+        }
+    }
+
+    /// Starts a chunked extraction whose output matches `features` over the
+    /// concatenation of everything pushed, however the audio is split.
+    pub fn streaming(&self) -> StreamingExtractor<'_> {
+        StreamingExtractor {
+            extractor: self,
+            buffer: Vec::new(),
+            emitted: 0,
+            head_pending: self.cfg.edge_mode != EdgeMode::Truncate,
+            recent: Vec::new(),
+            prev: None,
+        }
+    }
+
+    /// Resamples `audio` from `input_rate` to the configured sample rate before
+    /// extracting, so callers can pass audio at whatever rate it was decoded at.
+    pub fn features_at_rate(&self, audio: &[f32], input_rate: u32) -> Vec<Feature> {
+        if input_rate as usize == self.cfg.sample_rate { return self.features(audio) }
+
+        self.features(&crate::resample_antialiased(audio, input_rate, self.cfg.sample_rate as u32))
+    }
+
+    /// Recommends a `quantizer_topk` for representative `audio` by finding the knee of
+    /// each frame's sorted chroma magnitudes and averaging the number of bins above it.
+    pub fn suggest_topk(&self, audio: &[f32]) -> usize {
+        let chroma_vectors = self.chroma_vectors(audio);
+        let n = chroma_vectors.ncols();
+
+        let mut sorted = Vec::with_capacity(n);
+        let mut knees = Vec::with_capacity(chroma_vectors.nrows());
+
+        for chroma_vector in chroma_vectors.row_iter() {
+            sorted.extend(chroma_vector.iter().copied());
+            sorted.sort_unstable_by(|a, b| b.total_cmp(a));
+
+            let (max, min) = (sorted[0], sorted[n - 1]);
+            if max - min > f32::EPSILON {
+                // the knee is the point furthest below the line joining the curve's endpoints
+                let knee = (0..n)
+                    .map(|i| {
+                        let line = 1.0 - i as f32 / (n - 1) as f32;
+                        let curve = (sorted[i] - min) / (max - min);
+                        (i, line - curve)
+                    })
+                    .max_by(|a, b| a.1.total_cmp(&b.1))
+                    .map_or(n, |(i, _)| i);
+                knees.push(knee);
+            }
+
+            sorted.clear();
+        }
+
+        if knees.is_empty() { return self.cfg.quantizer_topk }
+
+        let mean = knees.iter().sum::<usize>() as f32 / knees.len() as f32;
+        (mean.round() as usize).clamp(1, n)
+    }
+
+    /// Extracts features with the chroma bins circularly rotated by each of `shifts`
+    /// before quantization, so a transposed recording can be matched against its key.
+    /// A shift of `s` moves the energy of bin `i` into bin `i + s`. Features are
+    /// paired with their frame, as in `features_indexed`.
+    pub fn features_transposed(&self, audio: &[f32], shifts: impl IntoIterator<Item = isize>) -> Vec<(isize, Vec<(usize, Feature)>)> {
+        let (chroma_vectors, onsets, _) = self.analyze_with(audio, &mut FftBuffers::new(self));
+        let (nrows, ncols) = chroma_vectors.shape();
+
+        let gate = |features: Vec<Feature>| -> Vec<(usize, Feature)> {
+            features.into_iter()
+                .enumerate()
+                .filter(|(frame, _)| onsets.as_ref().is_none_or(|onsets| onsets[*frame]))
+                .collect()
+        };
+
+        // the quantizer ranks bins without regard to their position, so with every bin
+        // the same width, rotating the packed features matches rotating the chroma
+        if self.cfg.quantizer_bits_per_bin_vec.is_none() {
+            let features = self.quantize(&chroma_vectors);
+            let bits = self.cfg.quantizer_bits_per_bin;
+
+            return shifts.into_iter()
+                .map(|shift| (shift, gate(features.iter().map(|feature| feature.transpose(shift, bits, ncols)).collect())))
+                .collect()
+        }
+
+        shifts.into_iter()
+            .map(|shift| {
+                let rotated = DMatrix::from_fn(nrows, ncols, |row, col| {
+                    chroma_vectors[(row, (col as isize - shift).rem_euclid(ncols as isize) as usize)]
+                });
+                (shift, gate(self.quantize(&rotated)))
+            })
+            .collect()
+    }
+
+    fn chroma_vectors(&self, audio: &[f32]) -> DMatrix<f32> {
+        // build spectogram of audio
+        let audio = self.pad_edges(audio);
+        let mut chroma_vectors = self.project(&self.windows(&audio));
+        self.smooth(&mut chroma_vectors, None);
+
+        chroma_vectors
+    }
+
+    /// The sample of the original audio frame `frame` is centered on, accounting for
+    /// the half window `edge_mode` pads the start with.
+    pub fn frame_center(&self, frame: usize) -> usize {
+        frame * self.cfg.window_stride + self.cfg.frame_offset()
+    }
+
+    /// Pads `audio` by half a window at each end as `edge_mode` asks. Reflection
+    /// past the far end of a clip shorter than that is zero. Non-finite samples are
+    /// zeroed first so they can't poison the FFT of every window they touch.
+    fn pad_edges<'a>(&self, audio: &'a [f32]) -> Cow<'a, [f32]> {
+        let audio = sanitize(audio);
+        if self.cfg.edge_mode == EdgeMode::Truncate || audio.is_empty() { return audio }
+
+        let (pad, len) = (self.cfg.window_size / 2, audio.len());
+        let reflect = self.cfg.edge_mode == EdgeMode::Reflect;
+
+        let head = (1..=pad).rev().map(|d| if reflect && d < len { audio[d] } else { 0.0 });
+        let tail = (1..=pad).map(|d| if reflect && d < len { audio[len - 1 - d] } else { 0.0 });
+
+        Cow::Owned(head.chain(audio.iter().copied()).chain(tail).collect())
+    }
+
+    /// Slices `audio` into analysis windows, adding the padded ones the config asks for.
+    fn windows<'a>(&self, audio: &'a [f32]) -> Vec<&'a [f32]> {
+        let cfg = &self.cfg;
+
+        let mut windows: Vec<&[f32]> = audio
+            .windows(cfg.window_size)
+            .step_by(cfg.window_stride)
+            .collect();
+
+        if windows.is_empty() && cfg.pad_short_audio && !audio.is_empty() {
+            windows.push(audio);
+        }
+
+        // emit one zero-padded window over the leftover tail if it's at least half full
+        if cfg.pad_final_window {
+            let tail_start = windows.len() * cfg.window_stride;
+            if audio.len().saturating_sub(tail_start) > cfg.window_size / 2 {
+                windows.push(&audio[tail_start..]);
+            }
+        }
+
+        windows
+    }
+
+    /// Builds the spectrogram of `windows`, zero-padding short ones, and projects it
+    /// onto the chroma bins.
+    fn project(&self, windows: &[&[f32]]) -> DMatrix<f32> {
+        if self.cfg.chroma_transform == ChromaTransform::ConstantQ { return self.constant_q(windows) }
+
+        // downproject to chroma vectors. this stays a dense multiply: about half the
+        // filterbank clears even a 1e-2 threshold, so a banded product saves nothing;
+        // see `bench_banded_projection`.
+        let mut spectrogram = self.spectrogram(windows);
+        self.whiten(&mut spectrogram);
+
+        spectrogram * &self.chroma
+    }
+
+    /// Chroma vectors of `windows` by `ChromaTransform::ConstantQ`, summing each
+    /// pitch class's kernel magnitudes across octaves. Short windows are zero-padded.
+    fn constant_q(&self, windows: &[&[f32]]) -> DMatrix<f32> {
+        let mut chroma_vectors = DMatrix::zeros(windows.len(), self.cfg.chroma_bins_per_octave);
+
+        for (row, window) in windows.iter().enumerate() {
+            for kernel in &self.cq_kernels {
+                let samples = window.get(kernel.offset..).unwrap_or(&[]);
+                let sum: Complex<f32> = samples.iter().zip(&kernel.coeffs).map(|(&x, c)| c * x).sum();
+                chroma_vectors[(row, kernel.bin)] += sum.abs();
+            }
+        }
+
+        chroma_vectors
+    }
+
+    /// Applies `spectral_whitening`, dividing each octave of each frame by its
+    /// mean magnitude. Silent octaves stay silent.
+    fn whiten(&self, spectrogram: &mut DMatrix<f32>) {
+        if !self.cfg.spectral_whitening { return }
+
+        for mut row in spectrogram.row_iter_mut() {
+            for band in &self.octave_bands {
+                let mut octave = row.columns_range_mut(band.clone());
+                let mean = octave.mean();
+                if mean > 0.0 { octave /= mean; }
+            }
+        }
+    }
+
+    fn spectrogram(&self, windows: &[&[f32]]) -> DMatrix<f32> {
+        self.spectrogram_with(windows, &mut FftBuffers::new(self))
+    }
+
+    fn spectrogram_with(&self, windows: &[&[f32]], buffers: &mut FftBuffers) -> DMatrix<f32> {
+        let FftBuffers { input, output, scratch } = buffers;
+
+        let mut spectrogram: DMatrix<f32> = DMatrix::zeros(windows.len(), output.len());
+
+        for (chunk_index, chunk) in windows.iter().enumerate() {
+            input[..chunk.len()]
+                .iter_mut()
+                .enumerate()
+                .for_each(|(i, s)| *s = chunk[i] * self.window[i]);
+            input[chunk.len()..].fill(0.0);
+
+            let _ = self.fft.process_with_scratch(input, output, scratch);
+
+            for i in 0..output.len() {
+                spectrogram[(chunk_index, i)] = output[i].abs() * self.magnitude_factor;
+            }
+        }
+
+        spectrogram
+    }
+
+    /// Applies `chroma_smoothing` down each bin, continuing from the smoothed row
+    /// `prev` when the frames follow on from an earlier block.
+    fn smooth(&self, chroma_vectors: &mut DMatrix<f32>, prev: Option<&[f32]>) {
+        if self.cfg.chroma_smoothing <= 0.0 { return }
+
+        // y[n] = a*x[n] + (1-a)*y[n-1] down each bin
+        let a = 1.0 - self.cfg.chroma_smoothing;
+        for (j, mut bin) in chroma_vectors.column_iter_mut().enumerate() {
+            if let (Some(prev), Some(first)) = (prev, bin.get_mut(0)) {
+                *first = a * *first + (1.0 - a) * prev[j];
+            }
+            for i in 1..bin.len() {
+                bin[i] = a * bin[i] + (1.0 - a) * bin[i - 1];
+            }
+        }
+    }
+
+    fn quantize(&self, chroma_vectors: &DMatrix<f32>) -> Vec<Feature> {
+        let cfg = &self.cfg;
+
+        // quantize chroma vectors
+        
+        let mut features = Vec::with_capacity(chroma_vectors.shape().0);
+        
+        let mut sorted_chroma = Vec::with_capacity(chroma_vectors.shape().1);
+        for chroma_vector in chroma_vectors.row_iter() {
+            // need to implement median filtering?
+
+            if cfg.quantizer_soft_assign {
+                // circular [1/4, 1/2, 1/4] kernel over pitch classes
+                let n = chroma_vector.len();
+                sorted_chroma.extend((0..n).map(|i| {
+                    let v = 0.25 * chroma_vector[(i + n - 1) % n] + 0.5 * chroma_vector[i] + 0.25 * chroma_vector[(i + 1) % n];
+                    (v, i)
+                }));
+            } else {
+                sorted_chroma.extend(chroma_vector.iter().enumerate().map(|(i, &v)| (v, i)));
+            }
+
+            sorted_chroma.sort_unstable_by(|a, b| a.0.total_cmp(&b.0));
+            // sorted chroma is now in ascended order. the percentile of the element
+            // at index i is its position in this new array
+            let feature = sorted_chroma.drain(sorted_chroma.len()-cfg.quantizer_topk..)
+                .enumerate()
+                .map(|(new_index, (_, old_index))| {
+                    let (offset, width) = self.bin_layout[old_index];
+                    thermometer_code(new_index, width, cfg.quantizer_topk).checked_shl(offset as u32).unwrap_or(0)
+                })
+                .reduce(|a, b| a | b)
+                .unwrap_or(0)
+                .into();
+
+            features.push(feature);
+
+            sorted_chroma.clear();
+        }
+
+        features
+    }
+}
 
 #[cfg(test)]
 mod tests {
@@ -245,4 +1467,852 @@ mod tests {
         // Verify we got some features
         assert!(!features.is_empty(), "Should extract at least one feature");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_features_at_rate() {
+        let config = SessionConfiguration::default();
+        let (extractor_cfg, _) = config.into_child_configs();
+        let extractor: FeatureExtractor = extractor_cfg.into();
+
+        let audio: Vec<f32> = (0..44_100 * 2)
+            .map(|i| (std::f32::consts::TAU * 440.0 * i as f32 / 44_100.0).sin()
+                + 0.3 * (std::f32::consts::TAU * 9_000.0 * i as f32 / 44_100.0).sin())
+            .collect();
+
+        let resampled = crate::resample_antialiased(&audio, 44_100, config.sample_rate as u32);
+        assert_eq!(resampled.len(), audio.len() * config.sample_rate / 44_100);
+
+        let expected: Vec<u64> = extractor.features(&resampled).iter().map(|f| *f.as_ref()).collect();
+        let actual: Vec<u64> = extractor.features_at_rate(&audio, 44_100).iter().map(|f| *f.as_ref()).collect();
+        assert!(!actual.is_empty());
+        assert_eq!(actual, expected);
+
+        // audio already at the configured rate passes straight through
+        let native: Vec<u64> = extractor.features_at_rate(&resampled, config.sample_rate as u32).iter().map(|f| *f.as_ref()).collect();
+        assert_eq!(native, expected);
+    }
+
+    #[test]
+    fn test_chroma_smoothing() {
+        let config = SessionConfiguration::default();
+
+        // a held A4 buried in white noise
+        let audio: Vec<f32> = crate::tests::noise(config.sample_rate * 6, 0x2545_f491)
+            .into_iter()
+            .enumerate()
+            .map(|(i, noise)| 0.3 * (std::f32::consts::TAU * 440.0 * i as f32 / config.sample_rate as f32).sin() + 0.6 * noise)
+            .collect();
+
+        let jitter = |smoothing: f32| {
+            let (cfg, _) = SessionConfiguration { chroma_smoothing: smoothing, ..Default::default() }.into_child_configs();
+            let features = FeatureExtractor::from(cfg).features(&audio);
+            features.windows(2).map(|w| w[0].distance(&w[1])).sum::<u32>() as f32 / (features.len() - 1) as f32
+        };
+
+        assert!(jitter(0.8) < jitter(0.0));
+    }
+
+    #[test]
+    fn test_suggest_topk() {
+        let config = SessionConfiguration::default();
+        let (extractor_cfg, _) = config.into_child_configs();
+        let extractor: FeatureExtractor = extractor_cfg.into();
+
+        let chord = |freqs: &[f32]| -> Vec<f32> {
+            (0..config.sample_rate * 2)
+                .map(|i| freqs.iter()
+                    .map(|f| (std::f32::consts::TAU * f * i as f32 / config.sample_rate as f32).sin())
+                    .sum::<f32>() / freqs.len() as f32)
+                .collect()
+        };
+
+        let triad = extractor.suggest_topk(&chord(&[261.6, 329.6, 392.0]));
+        let cluster = extractor.suggest_topk(&chord(&[261.6, 277.2, 293.7, 311.1, 329.6, 349.2, 370.0, 392.0, 415.3]));
+
+        assert!(triad <= 4, "triad suggested {triad}");
+        assert!(cluster > triad, "cluster suggested {cluster}, triad {triad}");
+    }
+
+    #[test]
+    fn test_feature_count_for() {
+        for (pad_final_window, pad_short_audio) in [(false, false), (true, false), (false, true), (true, true)] {
+            let (cfg, _) = SessionConfiguration { pad_final_window, pad_short_audio, ..Default::default() }.into_child_configs();
+            let extractor = FeatureExtractor::from(cfg);
+
+            for len in [0, 100, 2048, 4095, 4096, 4097, 6144, 7000, 20_000] {
+                let audio = vec![0.1; len];
+                assert_eq!(extractor.feature_count_for(len), extractor.features(&audio).len(), "len {len}, pad {pad_final_window}/{pad_short_audio}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_non_finite_samples() {
+        let (cfg, _) = SessionConfiguration::default().into_child_configs();
+        let extractor = FeatureExtractor::from(cfg);
+
+        let clean = crate::tests::synthesize_tone(440.0, 2.0, 11_500);
+        let mut zeroed = clean.clone();
+        let mut poisoned = clean.clone();
+        for (i, bad) in [(1000, f32::NAN), (5000, f32::INFINITY), (9000, f32::NEG_INFINITY)] {
+            zeroed[i] = 0.0;
+            poisoned[i] = bad;
+        }
+
+        let bits = |features: Vec<Feature>| -> Vec<u64> { features.into_iter().map(|f| f.0).collect() };
+        let features = bits(extractor.features(&poisoned));
+        assert_eq!(features, bits(extractor.features(&zeroed)));
+        assert!(features.iter().all(|&f| f != 0 && f != u64::MAX));
+
+        let mut streaming = extractor.streaming();
+        let mut streamed = streaming.push(&poisoned);
+        streamed.extend(streaming.finish());
+        assert_eq!(bits(streamed), features);
+
+        for (feature, energy) in extractor.features_with_energy(&poisoned) {
+            assert!(energy.is_finite());
+            assert!(feature.0.count_ones() > 0);
+        }
+    }
+
+    /// Dense against banded chroma projection, and the FFT for scale. Run with
+    /// `cargo test --release bench_banded_projection -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_banded_projection() {
+        let (cfg, _) = SessionConfiguration::default().into_child_configs();
+        let extractor = FeatureExtractor::from(cfg);
+        let chroma = &extractor.chroma;
+
+        let audio = crate::tests::noise(11_500 * 10, 3);
+        let windows = extractor.windows(&audio);
+        let spectrogram = extractor.spectrogram(&windows);
+
+        for threshold in [1e-3, 1e-2] {
+            // runs of rows above the threshold in each filterbank column
+            let bands: Vec<Vec<Range<usize>>> = chroma.column_iter()
+                .map(|column| {
+                    let floor = column.max() * threshold;
+                    let mut bands: Vec<Range<usize>> = Vec::new();
+                    for row in (0..column.len()).filter(|&row| column[row] > floor) {
+                        match bands.last_mut() {
+                            Some(band) if band.end == row => band.end += 1,
+                            _ => bands.push(row..row + 1),
+                        }
+                    }
+                    bands
+                })
+                .collect();
+            let banded = || {
+                let mut out = DMatrix::zeros(spectrogram.nrows(), chroma.ncols());
+                for (col, bands) in bands.iter().enumerate() {
+                    let mut out_column = out.column_mut(col);
+                    for row in bands.iter().flat_map(|band| band.clone()) {
+                        out_column.axpy(chroma[(row, col)], &spectrogram.column(row), 1.0);
+                    }
+                }
+                out
+            };
+
+            let dense = &spectrogram * chroma;
+            let tolerance = 1e-2 * dense.max();
+            assert!(dense.iter().zip(banded().iter()).all(|(d, b)| (d - b).abs() <= tolerance));
+
+            let time = |f: &dyn Fn() -> DMatrix<f32>| {
+                let start = std::time::Instant::now();
+                for _ in 0..100 { std::hint::black_box(f()); }
+                start.elapsed() / 100
+            };
+            let kept: usize = bands.iter().flatten().map(|band| band.len()).sum();
+            println!(
+                "threshold {threshold}: kept {kept} of {} weights, dense {:?}, banded {:?}, fft {:?}",
+                chroma.len(), time(&|| &spectrogram * chroma), time(&banded), time(&|| extractor.spectrogram(&windows)),
+            );
+        }
+    }
+
+    #[test]
+    fn test_edge_mode() {
+        let extractor = |edge_mode| {
+            let (cfg, _) = SessionConfiguration { edge_mode, ..Default::default() }.into_child_configs();
+            FeatureExtractor::from(cfg)
+        };
+        let (truncate, zero_pad, reflect) = (extractor(EdgeMode::Truncate), extractor(EdgeMode::ZeroPad), extractor(EdgeMode::Reflect));
+
+        for len in [0, 100, 2048, 4096, 7000, 20_000] {
+            let audio = vec![0.1; len];
+            assert_eq!(reflect.feature_count_for(len), reflect.features(&audio).len(), "len {len}");
+        }
+
+        // padding by half a window each end adds window_size / window_stride frames,
+        // the first centered on the first sample
+        assert_eq!(reflect.feature_count_for(20_000), truncate.feature_count_for(20_000) + 2);
+        assert_eq!((truncate.frame_center(0), reflect.frame_center(0)), (2048, 0));
+        assert_eq!(reflect.frame_center(3), truncate.frame_center(2));
+
+        // a note sounding from the first sample is heard in the first padded frame
+        let tone = crate::tests::synthesize_tone(440.0, 2.0, 11_500);
+        for extractor in [&zero_pad, &reflect] {
+            let frames = extractor.features_with_energy(&tone);
+            assert!(frames[0].1 > 0.5 * frames[3].1);
+            assert!(frames[0].0.distance(&frames[3].0) <= 4);
+        }
+        let bits = |features: Vec<Feature>| -> Vec<u64> { features.into_iter().map(|f| f.0).collect() };
+        let truncated = bits(truncate.features(&tone));
+        assert_eq!(bits(reflect.features(&tone))[1..=truncated.len()], truncated);
+    }
+
+    #[test]
+    fn test_frequency_resolution_report() {
+        let (cfg, _) = SessionConfiguration::default().into_child_configs();
+        let report = FeatureExtractor::from(cfg).frequency_resolution_report();
+
+        // 11.5 kHz over 4096 samples is ~2.8 Hz a bin, wider than the ~1.6 Hz between
+        // the semitones above A0
+        assert!((report.hz_per_bin - 11_500.0 / 4096.0).abs() < 1e-4);
+        assert!(!report.octaves[0].resolved);
+        assert!(report.octaves[1..5].iter().all(|octave| octave.resolved));
+        assert!(report.lowest_resolvable_hz > report.octaves[0].low_hz && report.lowest_resolvable_hz < report.octaves[1].low_hz);
+
+        // octaves past nyquist aren't in the filterbank
+        assert_eq!(report.octaves.len(), 8);
+        let (cfg, _) = SessionConfiguration { sample_rate: 4000, ..Default::default() }.into_child_configs();
+        assert_eq!(FeatureExtractor::from(cfg).frequency_resolution_report().octaves.len(), 7);
+    }
+
+    #[test]
+    fn test_distance_to_slice() {
+        let mut random = crate::tests::random_u64s(0x2545_f491_4f6c_dd1d);
+        let mut next = || random.next().unwrap();
+
+        // every remainder after the groups of four, and the all-ones/all-zeros extremes
+        for len in (0..10).chain([1000]) {
+            let keys: Vec<Feature> = (0..len).map(|_| Feature(next())).collect();
+            for query in [Feature(next()), Feature(0), Feature(u64::MAX)] {
+                let scalar: Vec<u32> = keys.iter().map(|key| query.distance(key)).collect();
+                assert_eq!(query.distance_to_slice(&keys), scalar);
+
+                #[cfg(target_arch = "x86_64")]
+                if std::arch::is_x86_feature_detected!("avx2") {
+                    assert_eq!(unsafe { distance_to_slice_avx2(query.0, &keys) }, scalar);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_constant_q() {
+        let extractor = |chroma_transform| -> FeatureExtractor {
+            SessionConfiguration { chroma_transform, ..Default::default() }.into_child_configs().0.into()
+        };
+        let (projection, constant_q) = (extractor(ChromaTransform::FftProjection), extractor(ChromaTransform::ConstantQ));
+
+        // A1 and B1 a whole tone apart, about two FFT bins, with A#1 between them
+        let tone = |freq: f32| crate::tests::synthesize_tone(freq, 2.0, 11_500);
+        let dyad: Vec<f32> = tone(55.0).iter().zip(tone(61.74)).map(|(a, b)| a + b).collect();
+        let (a, a_sharp, b) = (0, 1, 2);
+
+        // the projection's gaussians smear both notes over A#, where CQ shows a dip
+        let blurred = projection.chroma_vectors(&dyad);
+        let resolved = constant_q.chroma_vectors(&dyad);
+        let row = 3;
+        assert!(blurred[(row, a_sharp)] > blurred[(row, a)].min(blurred[(row, b)]));
+        assert!(resolved[(row, a_sharp)] < resolved[(row, a)].min(resolved[(row, b)]));
+
+        // either way the pipeline yields a feature per frame
+        assert_eq!(constant_q.features(&dyad).len(), projection.features(&dyad).len());
+        let streamed = {
+            let mut stream = constant_q.streaming();
+            let mut features = stream.push(&dyad);
+            features.extend(stream.finish());
+            features
+        };
+        let bits = |features: Vec<Feature>| -> Vec<u64> { features.into_iter().map(|f| f.0).collect() };
+        assert_eq!(bits(streamed), bits(constant_q.features(&dyad)));
+    }
+
+    #[test]
+    fn test_energy_measure() {
+        let energy = |energy_measure, freq| -> f32 {
+            let extractor: FeatureExtractor = SessionConfiguration { energy_measure, ..Default::default() }.into_child_configs().0.into();
+            extractor.features_with_energy(&crate::tests::synthesize_tone(freq, 2.0, 11_500))[3].1
+        };
+
+        // a strong 35 Hz tone is all but silent once A-weighted, unlike at 1 kHz
+        let (bass, mid) = (energy(EnergyMeasure::Chroma, 35.0), energy(EnergyMeasure::Chroma, 1000.0));
+        assert!(bass < 0.05 * mid, "{bass} vs {mid}");
+
+        // unweighted, the two read alike
+        let (bass, mid) = (energy(EnergyMeasure::Spectrum, 35.0), energy(EnergyMeasure::Spectrum, 1000.0));
+        assert!(bass > 0.5 * mid && mid > 0.5 * bass, "{bass} vs {mid}");
+    }
+
+    #[test]
+    fn test_warmup() {
+        let config = SessionConfiguration::default();
+        let audio: Vec<f32> = (0..config.sample_rate * 2).map(|i| (i as f32 * 0.13).sin()).collect();
+
+        let (cfg, _) = config.into_child_configs();
+        let cold = FeatureExtractor::from(cfg);
+        let cold_features: Vec<u64> = cold.features(&audio).iter().map(|f| *f.as_ref()).collect();
+
+        let (cfg, _) = config.into_child_configs();
+        let warm = FeatureExtractor::from(cfg);
+        warm.warmup();
+
+        let start = std::time::Instant::now();
+        let warm_features: Vec<u64> = warm.features(&audio).iter().map(|f| *f.as_ref()).collect();
+        let warm_elapsed = start.elapsed();
+
+        let start = std::time::Instant::now();
+        let _ = warm.features(&audio);
+        let steady_elapsed = start.elapsed();
+
+        assert_eq!(warm_features, cold_features);
+        // generous bound: the warmed first call should be in line with later calls
+        assert!(warm_elapsed < steady_elapsed * 4 + std::time::Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_compressed_song_round_trip() {
+        let features: Vec<Feature> = [0u64, 0, 0b1011, 0b1011, u64::MAX, 1 << 63, 0x0f0f_0f0f_0f0f_0f0f, 0]
+            .into_iter()
+            .map(Feature::from)
+            .collect();
+
+        let compressed = CompressedSong::from(features.as_slice());
+        assert_eq!(compressed.len(), features.len());
+
+        let decoded: Vec<u64> = compressed.iter().map(|f| f.0).collect();
+        let original: Vec<u64> = features.iter().map(|f| f.0).collect();
+        assert_eq!(decoded, original);
+    }
+
+    #[test]
+    fn test_quantizer_levels() {
+        for (bits_per_bin, topk, used) in [(5, 8, 6), (5, 6, 6), (5, 3, 3), (5, 1, 1), (1, 8, 2), (3, 12, 4), (4, 4, 4)] {
+            let levels = QuantizerLevels::new(bits_per_bin, topk);
+            assert_eq!(levels.used, used, "({bits_per_bin}, {topk})");
+            assert_eq!(levels.available, bits_per_bin + 1);
+            assert_eq!(levels.is_full(), used == bits_per_bin + 1);
+        }
+
+        assert_eq!(QuantizerLevels::suggest_topk(5, 12), 6);
+        assert_eq!(QuantizerLevels::suggest_topk(15, 12), 12);
+        assert!(QuantizerLevels::new(5, QuantizerLevels::suggest_topk(5, 12)).is_full());
+
+        let (cfg, _) = SessionConfiguration::default().into_child_configs();
+        assert!(cfg.quantizer_levels().is_full());
+
+        // a per-bin layout is judged by its widest bin, whatever `quantizer_bits_per_bin` says
+        let mut widths = vec![2; 12];
+        widths[3] = 9;
+        let (cfg, _) = SessionConfiguration { quantizer_bits_per_bin_vec: Some(widths), ..Default::default() }.into_child_configs();
+        assert_eq!(cfg.quantizer_levels(), QuantizerLevels::new(9, cfg.quantizer_topk));
+        assert!(!cfg.quantizer_levels().is_full());
+    }
+
+    #[test]
+    fn test_short_audio() {
+        let config = SessionConfiguration::default();
+        let note: Vec<f32> = (0..config.window_size / 3)
+            .map(|i| (std::f32::consts::TAU * 440.0 * i as f32 / config.sample_rate as f32).sin())
+            .collect();
+
+        let (cfg, _) = config.into_child_configs();
+        assert!(FeatureExtractor::from(cfg).features(&note).is_empty());
+
+        let (cfg, _) = SessionConfiguration { pad_short_audio: true, ..Default::default() }.into_child_configs();
+        let extractor = FeatureExtractor::from(cfg);
+        let features = extractor.features(&note);
+        assert_eq!(features.len(), 1);
+        assert_ne!(*features[0].as_ref(), 0);
+
+        assert!(extractor.features(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_pad_final_window() {
+        let config = SessionConfiguration::default();
+        let audio: Vec<f32> = (0..config.window_size + 3 * config.window_stride + 100)
+            .map(|i| (i as f32 * 0.1).sin())
+            .collect();
+
+        let (unpadded_cfg, _) = config.into_child_configs();
+        let (padded_cfg, _) = SessionConfiguration { pad_final_window: true, ..Default::default() }.into_child_configs();
+
+        let unpadded = FeatureExtractor::from(unpadded_cfg).features(&audio);
+        let padded = FeatureExtractor::from(padded_cfg).features(&audio);
+
+        assert_eq!(unpadded.len(), 4);
+        assert_eq!(padded.len(), unpadded.len() + 1);
+
+        // an exact fit leaves only half a window behind, so nothing extra is emitted
+        let exact = &audio[..config.window_size + 3 * config.window_stride];
+        let (padded_cfg, _) = SessionConfiguration { pad_final_window: true, ..Default::default() }.into_child_configs();
+        assert_eq!(FeatureExtractor::from(padded_cfg).features(exact).len(), 4);
+    }
+
+    #[test]
+    fn test_a_weight_reference_values() {
+        // IEC 61672-1 table values, in dB
+        let db = |f: f32| 20.0 * a_weight(f).log10();
+
+        assert!(db(1000.0).abs() < 0.01, "1 kHz: {}", db(1000.0));
+        assert!((db(100.0) - -19.1).abs() < 0.1, "100 Hz: {}", db(100.0));
+        assert!((db(10_000.0) - -2.5).abs() < 0.1, "10 kHz: {}", db(10_000.0));
+        assert_eq!(a_weight(0.0), 0.0);
+
+        // the filterbank keeps the unnormalized curve, so feature energies don't shift
+        assert!((20.0 * a_curve(1000.0).log10() - -2.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_streaming_matches_batch() {
+        let sample_rate = SessionConfiguration::default().sample_rate;
+        let freqs = [261.6, 329.6, 392.0, 523.3, 440.0];
+        let note_len = sample_rate / 2;
+        let audio: Vec<f32> = (0..note_len * freqs.len())
+            .map(|i| (std::f32::consts::TAU * freqs[i / note_len] * i as f32 / sample_rate as f32).sin() * 0.5)
+            .collect();
+
+        let mut random = crate::tests::random_u64s(0x2545_f491);
+        let mut next = |bound: usize| (random.next().unwrap() % bound as u64) as usize;
+
+        for config in [
+            SessionConfiguration::default(),
+            SessionConfiguration { chroma_smoothing: 0.6, pad_final_window: true, ..Default::default() },
+            SessionConfiguration { edge_mode: EdgeMode::ZeroPad, pad_final_window: true, ..Default::default() },
+            SessionConfiguration { edge_mode: EdgeMode::Reflect, chroma_smoothing: 0.6, ..Default::default() },
+        ] {
+            let extractor: FeatureExtractor = config.into_child_configs().0.into();
+            let batch: Vec<u64> = extractor.features(&audio).iter().map(|f| f.0).collect();
+
+            for trial in 0..20 {
+                // mixes tiny, stride-sized and multi-window chunks
+                let max_chunk = [7, config.window_stride, config.window_size * 3][trial % 3];
+                let mut stream = extractor.streaming();
+                let mut streamed = Vec::new();
+
+                let mut rest = &audio[..];
+                while !rest.is_empty() {
+                    let (chunk, tail) = rest.split_at((next(max_chunk) + 1).min(rest.len()));
+                    streamed.extend(stream.push(chunk));
+                    rest = tail;
+                }
+                streamed.extend(stream.finish());
+
+                assert_eq!(streamed.iter().map(|f| f.0).collect::<Vec<_>>(), batch, "trial {trial}");
+            }
+        }
+
+        // a short clip pads into a single feature, as in a batch call
+        let extractor: FeatureExtractor = SessionConfiguration { pad_short_audio: true, ..Default::default() }.into_child_configs().0.into();
+        let mut stream = extractor.streaming();
+        assert!(stream.push(&audio[..1000]).is_empty());
+        assert_eq!(stream.finish().iter().map(|f| f.0).collect::<Vec<_>>(), extractor.features(&audio[..1000]).iter().map(|f| f.0).collect::<Vec<_>>());
+
+        // under Reflect a clip shorter than the half window reflects only what it has
+        let extractor: FeatureExtractor = SessionConfiguration { edge_mode: EdgeMode::Reflect, pad_short_audio: true, ..Default::default() }.into_child_configs().0.into();
+        let mut stream = extractor.streaming();
+        assert!(stream.push(&audio[..1000]).is_empty());
+        assert_eq!(stream.finish().iter().map(|f| f.0).collect::<Vec<_>>(), extractor.features(&audio[..1000]).iter().map(|f| f.0).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_bits_per_bin_vec() {
+        // wide mid bins, narrow extremes: 1+2+3+6+8+8+8+8+6+3+2+1 = 56 bits
+        let widths = vec![1, 2, 3, 6, 8, 8, 8, 8, 6, 3, 2, 1];
+        let config = SessionConfiguration { quantizer_bits_per_bin_vec: Some(widths.clone()), ..Default::default() };
+        let extractor: FeatureExtractor = config.into_child_configs().0.into();
+
+        assert_eq!(extractor.bin_layout.last(), Some(&(55, 1)));
+
+        let sample_rate = config.sample_rate;
+        let audio: Vec<f32> = (0..sample_rate * 2)
+            .map(|i| {
+                let t = i as f32 / sample_rate as f32;
+                (std::f32::consts::TAU * 261.6 * t).sin() + 0.6 * (std::f32::consts::TAU * 392.0 * t).sin()
+            })
+            .collect();
+        let features = extractor.features(&audio);
+        assert!(!features.is_empty());
+
+        for feature in features {
+            assert!(feature.0 < 1 << 56, "bits past the last bin are untouched");
+
+            // every bin decodes to a thermometer code within its own field
+            let mut set_bins = 0;
+            for (&(offset, width), expected_width) in extractor.bin_layout.iter().zip(&widths) {
+                assert_eq!(width, *expected_width);
+                let code = (feature.0 >> offset) & ((1u64 << width) - 1);
+                assert_eq!(code & (code + 1), 0, "{code:b} is not a thermometer code");
+                set_bins += (code != 0) as usize;
+            }
+            assert!(set_bins <= config.quantizer_topk);
+        }
+    }
+
+    #[test]
+    fn test_coherent_gain_normalization() {
+        let config = SessionConfiguration::default();
+        // centered on an FFT bin, so the peak isn't split by scalloping
+        let bin = 100;
+        let freq = bin as f32 * config.sample_rate as f32 / config.window_size as f32;
+        let sine: Vec<f32> = (0..config.window_size)
+            .map(|i| (std::f32::consts::TAU * freq * i as f32 / config.sample_rate as f32).sin())
+            .collect();
+
+        let peak = |window_function, magnitude_scale| {
+            let config = SessionConfiguration { window_function, magnitude_scale, ..Default::default() };
+            let extractor: FeatureExtractor = config.into_child_configs().0.into();
+            extractor.spectrogram(&[&sine])[(0, bin)]
+        };
+
+        let windows = [WindowFunction::Hann, WindowFunction::Hamming, WindowFunction::Blackman];
+
+        for window in windows {
+            let magnitude = peak(window, MagnitudeScale::CoherentGain);
+            assert!((magnitude - 1.0).abs() < 0.01, "{window:?}: {magnitude}");
+        }
+
+        // the sqrt(N) scale depends on the window
+        let (hann, blackman) = (peak(WindowFunction::Hann, MagnitudeScale::SqrtN), peak(WindowFunction::Blackman, MagnitudeScale::SqrtN));
+        assert!((hann / blackman - 0.5 / 0.42).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_base64_round_trip() {
+        let features: Vec<Feature> = [0u64, 0b1011, 0b1011, u64::MAX, 1 << 63, 0x0123_4567_89ab_cdef]
+            .into_iter()
+            .map(Feature)
+            .collect();
+
+        for n in 0..=features.len() {
+            let encoded = CompressedSong::from(&features[..n]).to_base64();
+            assert!(encoded.bytes().all(|c| c.is_ascii_alphanumeric() || c == b'-' || c == b'_'));
+
+            let decoded = CompressedSong::from_base64(&encoded).expect("round trip");
+            assert_eq!(decoded.len(), n);
+            assert_eq!(decoded.iter().map(|f| f.0).collect::<Vec<_>>(), features[..n].iter().map(|f| f.0).collect::<Vec<_>>());
+        }
+
+        let encoded = CompressedSong::from(&features[..]).to_base64();
+        assert!(CompressedSong::from_base64("").is_none());
+        assert!(CompressedSong::from_base64(&encoded[..encoded.len() - 1]).is_none(), "truncated varint");
+        assert!(CompressedSong::from_base64(&format!("{encoded}!")).is_none());
+        assert_eq!(CompressedSong::from_base64("AQ").map(|song| song.len()), Some(0), "version 1, no features");
+        assert!(CompressedSong::from_base64("Ag").is_none(), "unknown version 2");
+    }
+
+    #[test]
+    fn test_onset_gated() {
+        let config = SessionConfiguration { onset_gated: true, ..Default::default() };
+        let sample_rate = config.sample_rate;
+        let extractor: FeatureExtractor = config.into_child_configs().0.into();
+
+        // plucked notes: a decaying tone, then a rest
+        let freqs = [261.6, 392.0, 329.6, 523.3, 440.0, 293.7, 349.2, 246.9];
+        let (note_len, rest_len) = (sample_rate * 6 / 10, sample_rate * 3 / 10);
+        let audio: Vec<f32> = freqs.iter()
+            .flat_map(|&f| (0..note_len + rest_len).map(move |i| {
+                if i >= note_len { return 0.0 }
+                let t = i as f32 / sample_rate as f32;
+                (std::f32::consts::TAU * f * t).sin() * (-4.0 * t).exp()
+            }))
+            .collect();
+
+        let indexed = extractor.features_indexed(&audio);
+        let dense = extractor.feature_count_for(audio.len());
+        assert!(indexed.len().abs_diff(freqs.len()) <= 1, "{} onsets for {} notes", indexed.len(), freqs.len());
+        assert!(indexed.len() * 3 < dense);
+
+        // frames keep their place in the dense sequence
+        assert!(indexed.windows(2).all(|w| w[0].0 < w[1].0));
+        let ungated: FeatureExtractor = SessionConfiguration::default().into_child_configs().0.into();
+        let all = ungated.features(&audio);
+        assert!(indexed.iter().all(|&(frame, feature)| all[frame].0 == feature.0));
+        assert_eq!(extractor.features(&audio).len(), indexed.len());
+    }
+
+    #[test]
+    fn test_features_with_energy() {
+        let config = SessionConfiguration::default();
+        let sample_rate = config.sample_rate;
+        let extractor: FeatureExtractor = config.into_child_configs().0.into();
+
+        // two seconds of silence, then two of a loud tone
+        let audio: Vec<f32> = (0..sample_rate * 4)
+            .map(|i| if i < sample_rate * 2 { 0.0 } else { (std::f32::consts::TAU * 440.0 * i as f32 / sample_rate as f32).sin() })
+            .collect();
+
+        let with_energy = extractor.features_with_energy(&audio);
+        let features = extractor.features(&audio);
+        assert_eq!(with_energy.len(), features.len());
+        assert!(with_energy.iter().zip(&features).all(|((a, _), b)| a.0 == b.0));
+
+        let (first, last) = (with_energy[0].1, with_energy[with_energy.len() - 1].1);
+        assert!(first.abs() < 1e-6, "silence energy {first}");
+        assert!(last > 1.0, "tone energy {last}");
+    }
+
+    #[test]
+    fn test_nearest_fast_fft_size() {
+        assert_eq!(nearest_fast_fft_size(4095), 4096);
+        assert_eq!(nearest_fast_fft_size(4096), 4096);
+        assert_eq!(nearest_fast_fft_size(4097), 4320);
+        assert_eq!(nearest_fast_fft_size(0), 1);
+        assert_eq!(largest_prime_factor(4093), 4093);
+
+        let cfg = |window_size| SessionConfiguration { window_size, ..Default::default() }.into_child_configs().0;
+        assert!(FeatureExtractor::try_new(cfg(4095)).is_ok(), "13 is a fast factor");
+        assert_eq!(FeatureExtractor::try_new(cfg(4093)).err(), Some(ConfigError::SlowFftSize { size: 4093, suggested: 4096 }));
+
+        // still usable, just slow, when built without `try_new`
+        let extractor = FeatureExtractor::from(cfg(4093));
+        assert!(!extractor.features(&crate::tests::noise(4093 * 3, 1)).is_empty());
+    }
+
+    #[test]
+    fn test_chroma_matrix_nyquist() {
+        let cfg = |chroma_n_octaves| SessionConfiguration { chroma_n_octaves, ..Default::default() }.into_child_configs().0;
+
+        // at 11.5 kHz the eighth octave from 27.5 Hz already starts past nyquist
+        let in_band = FeatureExtractor::chroma_matrix(&cfg(8));
+        assert_eq!(FeatureExtractor::chroma_matrix(&cfg(12)), in_band);
+        assert_ne!(FeatureExtractor::chroma_matrix(&cfg(7)), in_band);
+    }
+
+    #[test]
+    fn test_soft_assign() {
+        // a tone halfway between A and A#, rendered two cents either side
+        let midpoint = 440.0 * 2f32.powf(1.0 / 24.0);
+        let distance = |soft: bool| {
+            let config = SessionConfiguration { quantizer_soft_assign: soft, ..Default::default() };
+            let sample_rate = config.sample_rate;
+            let extractor: FeatureExtractor = config.into_child_configs().0.into();
+            let render = |f: f32| -> Vec<f32> {
+                (0..sample_rate * 2).map(|i| (std::f32::consts::TAU * f * i as f32 / sample_rate as f32).sin()).collect()
+            };
+
+            let sharp = extractor.features(&render(midpoint * 2f32.powf(2.0 / 1200.0)));
+            let flat = extractor.features(&render(midpoint * 2f32.powf(-2.0 / 1200.0)));
+            sharp.iter().zip(&flat).map(|(a, b)| a.distance(b)).sum::<u32>()
+        };
+
+        assert!(distance(false) > 0, "hard assignment flips between the two bins");
+        assert_eq!(distance(true), 0);
+    }
+
+    #[test]
+    fn test_trim_silence() {
+        let config = SessionConfiguration::default();
+        let (sample_rate, stride) = (config.sample_rate, config.window_stride);
+        let extractor: FeatureExtractor = config.into_child_configs().0.into();
+
+        let tone = (0..sample_rate * 2).map(|i| (std::f32::consts::TAU * 440.0 * i as f32 / sample_rate as f32).sin());
+        let padded: Vec<f32> = std::iter::repeat_n(0.0, sample_rate).chain(tone).chain(std::iter::repeat_n(0.0, sample_rate)).collect();
+
+        let bounds = extractor.silence_bounds(&padded, 0.01);
+        assert_eq!(bounds.start % stride, 0);
+        assert!(bounds.start <= sample_rate && sample_rate - bounds.start < stride);
+        assert!(bounds.end >= sample_rate * 3 && bounds.end < padded.len());
+        assert_eq!(extractor.trim_silence(&padded, 0.01).len(), bounds.len());
+
+        // the trimmed frames are the original's, shifted by the trimmed strides
+        let full = extractor.features_indexed(&padded);
+        let trimmed = extractor.features_indexed_trimmed(&padded, 0.01);
+        assert!(trimmed.len() < full.len());
+        assert_eq!(trimmed[0].0, bounds.start / stride);
+        for (frame, feature) in trimmed {
+            assert_eq!(full[frame].1.0, feature.0);
+        }
+
+        assert!(extractor.silence_bounds(&[0.0; 10000], 0.01).is_empty());
+    }
+
+    #[test]
+    fn test_stereo_fuse() {
+        let extractor = |stereo_mode| -> FeatureExtractor {
+            SessionConfiguration { stereo_mode, ..Default::default() }.into_child_configs().0.into()
+        };
+        let sample_rate = SessionConfiguration::default().sample_rate;
+
+        // a chord whose channels are exact inverses, so a downmix is pure silence
+        let left: Vec<f32> = (0..sample_rate * 2)
+            .map(|i| [261.6f32, 329.6, 392.0].iter().map(|f| (std::f32::consts::TAU * f * i as f32 / sample_rate as f32).sin()).sum::<f32>() * 0.3)
+            .collect();
+        let right: Vec<f32> = left.iter().map(|s| -s).collect();
+
+        let downmix = extractor(StereoMode::Downmix);
+        let silent = downmix.features(&vec![0.0; left.len()]);
+        let mixed = downmix.features_stereo(&left, &right);
+        assert!(mixed.iter().zip(&silent).all(|(a, b)| a.0 == b.0), "the downmix is indistinguishable from silence");
+
+        let fuse = extractor(StereoMode::Fuse);
+        let fused = fuse.features_stereo(&left, &right);
+        let mono = fuse.features(&left);
+        assert_eq!(fused.len(), mono.len());
+        assert!(fused.iter().zip(&mono).all(|(a, b)| a.0 == b.0));
+        assert!(fused.iter().zip(&silent).any(|(a, b)| a.0 != b.0));
+
+        // each fused frame carries both channels' energy
+        let frames = fuse.frames_stereo(&left, &right);
+        assert!((frames[0].2 - 2.0 * fuse.frames(&left)[0].2).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_spectral_contrast() {
+        assert_eq!(spectral_contrast([1.0; 12].into_iter()), 0.0);
+        assert_eq!(spectral_contrast([0.0; 12].into_iter()), 0.0);
+        let peak = (0..12).map(|i| if i == 3 { 1.0 } else { 0.0 });
+        assert!((spectral_contrast(peak) - 11.0 / 12.0).abs() < 1e-6);
+
+        let config = SessionConfiguration::default();
+        let sample_rate = config.sample_rate;
+        let extractor: FeatureExtractor = config.into_child_configs().0.into();
+        let tone: Vec<f32> = (0..sample_rate * 2).map(|i| (std::f32::consts::TAU * 440.0 * i as f32 / sample_rate as f32).sin()).collect();
+        let noise = crate::tests::noise(sample_rate * 2, 11);
+
+        let mean_contrast = |audio: &[f32]| {
+            let frames = extractor.frames(audio);
+            frames.iter().map(|&(_, _, _, contrast)| contrast).sum::<f32>() / frames.len() as f32
+        };
+        assert!(mean_contrast(&tone) > mean_contrast(&noise) + 0.3);
+    }
+
+    #[test]
+    fn test_thermometer_code_fits_field() {
+        for width in 0..=64 {
+            for topk in 1..=12 {
+                let mut previous = 0;
+                for rank in 0..topk {
+                    let code = thermometer_code(rank, width, topk);
+                    assert!(width == 64 || code >> width == 0, "rank {rank} of {topk} overflows {width} bits: {code:#b}");
+                    assert_eq!(code & code.wrapping_add(1), 0, "not a thermometer code: {code:#b}");
+                    assert!(code >= previous);
+                    previous = code;
+                }
+            }
+        }
+
+        // the top rank reaches the full field only when topk leaves no gaps
+        assert_eq!(thermometer_code(5, 5, 6), 0b11111);
+        assert_eq!(thermometer_code(3, 5, 4), 0b1111);
+    }
+
+    #[test]
+    fn test_features_batch() {
+        let config = SessionConfiguration::default();
+        let sample_rate = config.sample_rate;
+        let extractor: FeatureExtractor = config.into_child_configs().0.into();
+
+        let clips: Vec<Vec<f32>> = vec![
+            crate::tests::synth_melody(&[261.6, 329.6, 392.0], 0.5, sample_rate),
+            crate::tests::noise(sample_rate * 2, 5),
+            crate::tests::synthesize_tone(440.0, 1.0, sample_rate),
+        ];
+
+        let batch: Vec<Vec<Feature>> = extractor.features_batch(clips.iter().map(Vec::as_slice)).collect();
+        assert_eq!(batch.len(), clips.len());
+        for (features, clip) in batch.iter().zip(&clips) {
+            let single = extractor.features(clip);
+            assert_eq!(features.len(), single.len());
+            assert!(features.iter().zip(&single).all(|(a, b)| a.0 == b.0));
+        }
+    }
+
+    #[test]
+    fn test_spectral_whitening() {
+        let distance = |whitening: bool| {
+            let config = SessionConfiguration { spectral_whitening: whitening, ..Default::default() };
+            let sample_rate = config.sample_rate;
+            let extractor: FeatureExtractor = config.into_child_configs().0.into();
+
+            // low notes with eight harmonics each, so their energy spans several octaves
+            let audio: Vec<f32> = [110.0, 146.8, 164.8, 130.8].iter()
+                .flat_map(|&f: &f32| (0..sample_rate).map(move |i| {
+                    let t = i as f32 / sample_rate as f32;
+                    (1..=8).map(|h| (std::f32::consts::TAU * f * h as f32 * t).sin() / h as f32).sum::<f32>() * 0.2
+                }))
+                .collect();
+
+            // mix in a one-pole lowpass of itself for a heavy bass shelf
+            let mut low = 0.0;
+            let boosted: Vec<f32> = audio.iter()
+                .map(|&x| { low += 0.02 * (x - low); x + 8.0 * low })
+                .collect();
+
+            let key = extractor.features(&audio);
+            let query = extractor.features(&boosted);
+            key.iter().zip(&query).map(|(a, b)| a.distance(b)).sum::<u32>()
+        };
+
+        let (plain, whitened) = (distance(false), distance(true));
+        assert!(whitened < plain, "whitened {whitened} vs plain {plain}");
+    }
+
+    #[test]
+    fn test_feature_transpose() {
+        let (bits, bins) = (5, 12);
+        let feature = Feature(0x0123_4567_89ab_cdef);
+
+        assert_eq!(feature.transpose(bins as isize, bits, bins).0, feature.0);
+        assert_eq!(feature.transpose(0, bits, bins).0, feature.0);
+        assert_eq!(feature.transpose(3, bits, bins).transpose(-3, bits, bins).0, feature.0);
+        assert_eq!(feature.transpose(-1, bits, bins).0, feature.transpose(11, bits, bins).0);
+
+        // the highest bin wraps around to the lowest, the spare top bits stay put
+        let top = Feature(0b10111 << 55 | 1 << 63);
+        assert_eq!(top.transpose(1, bits, bins).0, 0b10111 | 1 << 63);
+        assert_eq!(Feature(0b00011).transpose(2, bits, bins).0, 0b00011 << 10);
+
+        let full = Feature(u64::MAX);
+        assert_eq!(full.transpose(5, 4, 16).0, full.0);
+        assert_eq!(Feature(0xf << 60).transpose(1, 4, 16).0, 0xf);
+    }
+
+    #[test]
+    #[should_panic(expected = "don't fit in a feature")]
+    fn test_feature_transpose_too_wide() {
+        Feature(1).transpose(1, 5, 13);
+    }
+
+    #[test]
+    fn test_features_transposed() {
+        let config = SessionConfiguration::default();
+        let rotated: FeatureExtractor = config.into_child_configs().0.into();
+        // the same layout spelled per bin, which takes the rotate-then-quantize path
+        let widths = vec![config.quantizer_bits_per_bin; config.chroma_bins_per_octave];
+        let requantized: FeatureExtractor = SessionConfiguration { quantizer_bits_per_bin_vec: Some(widths), ..config }.into_child_configs().0.into();
+
+        let melody = crate::tests::synth_melody(&crate::tests::MELODY[..5], 0.5, config.sample_rate);
+        let bits = |features: &[(isize, Vec<(usize, Feature)>)]| -> Vec<(isize, Vec<(usize, u64)>)> {
+            features.iter().map(|(shift, features)| (*shift, features.iter().map(|(frame, f)| (*frame, f.0)).collect())).collect()
+        };
+        assert_eq!(bits(&rotated.features_transposed(&melody, -3..=3)), bits(&requantized.features_transposed(&melody, -3..=3)));
+    }
+
+    #[test]
+    fn test_try_new() {
+        let cfg = |config: SessionConfiguration| config.into_child_configs().0;
+
+        assert!(FeatureExtractor::try_new(cfg(SessionConfiguration::default())).is_ok());
+
+        let wide = SessionConfiguration { quantizer_bits_per_bin: 6, ..Default::default() };
+        assert_eq!(FeatureExtractor::try_new(cfg(wide)).err(), Some(ConfigError::BitBudget { bits: 72 }));
+
+        let wide = SessionConfiguration { quantizer_bits_per_bin: 6, ..Default::default() };
+        let panicked = std::panic::catch_unwind(|| FeatureExtractor::from(cfg(wide)));
+        assert!(panicked.is_err());
+
+        let widths = SessionConfiguration { quantizer_bits_per_bin_vec: Some(vec![4; 11]), ..Default::default() };
+        assert_eq!(FeatureExtractor::try_new(cfg(widths)).err(), Some(ConfigError::BinWidthCount { expected: 12, got: 11 }));
+
+        let stride = SessionConfiguration { window_stride: 0, ..Default::default() };
+        assert!(matches!(FeatureExtractor::try_new(cfg(stride)), Err(ConfigError::Window { .. })));
+
+        let topk = SessionConfiguration { quantizer_topk: 13, ..Default::default() };
+        assert!(matches!(FeatureExtractor::try_new(cfg(topk)), Err(ConfigError::Topk { .. })));
+    }
+}
@@ -1,126 +1,331 @@
 pub mod fingerprint;
 pub mod search;
 pub mod config;
+#[cfg(feature = "wasm")]
+mod session;
+#[cfg(feature = "metrics")]
+mod metrics;
+#[cfg(all(feature = "metrics", target_arch = "wasm32"))]
+compile_error!("the `metrics` feature times searches with `std::time::Instant`, which panics on wasm32");
+#[cfg(feature = "wasm")]
 use wasm_bindgen::prelude::*;
 
-pub use search::{Database, DatabaseConfiguration};
+pub use search::{Database, DatabaseConfiguration, DatabaseStats, FeatureDatabaseFile, FileError, FingerprintMismatch, QueryResult};
 pub use fingerprint::{FeatureExtractor, FeatureExtractorConfiguration};
-pub use config::SessionConfiguration;
+pub use config::{ConfigError, SessionConfiguration};
+#[cfg(feature = "wasm")]
+pub use session::{ExplainReason, ExplainReport, Session, SessionQueryResult, SessionSearchResults, SessionSongMatches, PlanarError, SessionStats, SongKeyError};
+#[cfg(feature = "metrics")]
+pub use metrics::SessionMetrics;
 
-#[wasm_bindgen]
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
 pub fn resample(audio: &[f32], fs_in: u32, fs_out: u32) -> Vec<f32> {
-    let resampled_len = audio.len() * fs_out as usize / fs_in as usize;
+    resample_linear(audio, fs_in, fs_out)
+}
 
-    let resampled = (0..resampled_len)
-        .map(|i| {
-            let ileft = i * audio.len() / resampled_len;
-            let frac = (i * audio.len() % resampled_len) as f32 / resampled_len as f32;
-            let left = audio[ileft];
-            let right = audio[(ileft+1).min(audio.len()-1)];
+fn resample_linear(audio: &[f32], fs_in: u32, fs_out: u32) -> Vec<f32> {
+    if fs_in == 0 { return Vec::new() }
 
-            left * (1.0 - frac) + right * frac
-        })
-        .collect();
+    resample_to_len(audio, audio.len() * fs_out as usize / fs_in as usize)
+}
 
-    resampled
+/// Linearly interpolates `audio` to exactly `out_len` samples.
+#[cfg_attr(feature = "wasm", wasm_bindgen(js_name = resampleToLen))]
+pub fn resample_to_len(audio: &[f32], out_len: usize) -> Vec<f32> {
+    let mut out = vec![0.0; out_len];
+    interpolate_into(audio, out_len, &mut out);
+    out
 }
 
-#[wasm_bindgen]
-pub struct Session {
-    extractor: FeatureExtractor,
-    db: Database,
-    stride_dt: f32,
+/// Resamples into a caller-owned buffer so streaming callers can reuse one
+/// allocation. Returns the number of samples written, which is clamped to `out.len()`.
+#[cfg_attr(feature = "wasm", wasm_bindgen(js_name = resampleInto))]
+pub fn resample_into(audio: &[f32], out: &mut [f32], fs_in: u32, fs_out: u32) -> usize {
+    if fs_in == 0 { return 0 }
+
+    let resampled_len = audio.len() * fs_out as usize / fs_in as usize;
+    interpolate_into(audio, resampled_len, out);
+
+    resampled_len.min(out.len())
 }
 
-#[wasm_bindgen]
-pub struct SessionQueryResult {
-    uuid: String,
+/// Writes the first `out.len()` samples of `audio` interpolated to `out_len` samples.
+fn interpolate_into(audio: &[f32], out_len: usize, out: &mut [f32]) {
+    if audio.is_empty() { return }
 
-    #[wasm_bindgen(readonly)]
-    pub score: f32,
+    for (i, sample) in out.iter_mut().enumerate().take(out_len) {
+        let ileft = i * audio.len() / out_len;
+        let frac = (i * audio.len() % out_len) as f32 / out_len as f32;
+        let left = audio[ileft];
+        let right = audio[(ileft+1).min(audio.len()-1)];
 
-    #[wasm_bindgen(js_name = keyStart, readonly)]
-    pub key_start: f32,
+        *sample = left * (1.0 - frac) + right * frac;
+    }
+}
 
-    #[wasm_bindgen(js_name = keyEnd, readonly)]
-    pub key_end: f32,
+/// Like `resample`, but low-pass filters below the output Nyquist first when
+/// downsampling so high frequencies don't alias into the chroma range.
+pub(crate) fn resample_antialiased(audio: &[f32], fs_in: u32, fs_out: u32) -> Vec<f32> {
+    Resampler::new(fs_in, fs_out).resample(audio)
+}
 
-    #[wasm_bindgen(js_name = queryStart, readonly)]
-    pub query_start: f32
+/// Anti-aliased resampling between a fixed pair of rates, with the low-pass kernel
+/// computed once up front for callers converting many clips.
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+pub struct Resampler {
+    fs_in: u32,
+    fs_out: u32,
+    /// only needed when downsampling
+    kernel: Option<Vec<f32>>,
 }
 
-#[wasm_bindgen]
-impl SessionQueryResult {
-    #[wasm_bindgen(getter)]
-    pub fn uuid(&self) -> String {
-        self.uuid.clone()
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+impl Resampler {
+    #[cfg_attr(feature = "wasm", wasm_bindgen(constructor))]
+    pub fn new(fs_in: u32, fs_out: u32) -> Self {
+        let kernel = (fs_out < fs_in).then(|| lowpass_kernel(fs_out as f32 / fs_in as f32));
+
+        Self { fs_in, fs_out, kernel }
     }
+
+    pub fn resample(&self, audio: &[f32]) -> Vec<f32> {
+        let Some(kernel) = &self.kernel else { return resample_linear(audio, self.fs_in, self.fs_out) };
+        let half = kernel.len() / 2;
+
+        let filtered: Vec<f32> = (0..audio.len())
+            .map(|i| kernel.iter()
+                .enumerate()
+                .filter_map(|(k, &tap)| (i + k).checked_sub(half)
+                    .and_then(|j| audio.get(j))
+                    .map(|&s| s * tap))
+                .sum())
+            .collect();
+
+        resample_linear(&filtered, self.fs_in, self.fs_out)
+    }
+}
+
+/// Linear resampling of audio arriving in chunks, such as live microphone frames.
+/// Output matches `resample` over the concatenated input whenever the total length
+/// converts to a whole number of output samples.
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+pub struct StreamingResampler {
+    fs_in: u32,
+    fs_out: u32,
+    /// input samples seen so far, including those dropped from `buffer`. Positions
+    /// are `u64` so `next * fs_in` can't overflow a 32-bit `usize` seconds into a stream
+    received: u64,
+    /// index of the next output sample
+    next: u64,
+    /// input from the left neighbour of the next output sample onwards
+    buffer: Vec<f32>,
 }
 
-#[wasm_bindgen]
-impl Session {
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+impl StreamingResampler {
+    #[cfg_attr(feature = "wasm", wasm_bindgen(constructor))]
+    pub fn new(fs_in: u32, fs_out: u32) -> Self {
+        Self { fs_in, fs_out, received: 0, next: 0, buffer: Vec::new() }
+    }
 
-    fn resample(audio: &[f32], fs_in: u32, fs_out: u32) -> Vec<f32> {
-        let resampled_len = audio.len() * fs_out as usize / fs_in as usize;
-        let resampled = (0..resampled_len)
-            .map(|i| {
-                let ileft = i * audio.len() / resampled_len;
-                let frac = (i * audio.len() % resampled_len) as f32 / resampled_len as f32;
-                let left = audio[ileft];
-                let right = audio[(ileft+1).min(audio.len()-1)];
+    /// Appends `chunk` and returns every output sample whose neighbours have arrived.
+    pub fn process(&mut self, chunk: &[f32]) -> Vec<f32> {
+        self.buffer.extend_from_slice(chunk);
+        self.received += chunk.len() as u64;
 
-                left * (1.0 - frac) + right * frac
-            })
-            .collect();
-        resampled
+        self.emit(u64::MAX, false)
     }
 
-    #[wasm_bindgen(constructor)]
-    pub fn new(cfg: JsValue) -> Self {
-        let cfg: SessionConfiguration = serde_wasm_bindgen::from_value(cfg)
-            .unwrap_or_default();
+    /// Ends the stream, returning the samples that interpolate toward the last input.
+    pub fn finish(mut self) -> Vec<f32> {
+        if self.fs_in == 0 { return Vec::new() }
+
+        let out_len = self.received * self.fs_out as u64 / self.fs_in as u64;
+        self.emit(out_len, true)
+    }
+}
+
+impl StreamingResampler {
+    /// Interpolates output samples up to `end`, stopping at the first whose right
+    /// neighbour hasn't arrived unless `flush` lets it clamp to the last input.
+    fn emit(&mut self, end: u64, flush: bool) -> Vec<f32> {
+        let (fs_in, fs_out) = (self.fs_in as u64, self.fs_out as u64);
+        if fs_in == 0 || fs_out == 0 { return Vec::new() }
+
+        // input index of buffer[0]
+        let start = self.received - self.buffer.len() as u64;
+        let mut out = Vec::new();
 
-        let stride_dt = cfg.stride_dt();
+        while self.next < end {
+            let position = self.next * fs_in;
+            let ileft = position / fs_out;
+            if ileft >= self.received || (!flush && ileft + 1 >= self.received) { break }
 
-        let (extractor_cfg, db_cfg) = cfg.into_child_configs();
+            let frac = (position % fs_out) as f32 / fs_out as f32;
+            let left = self.buffer[(ileft - start) as usize];
+            let right = self.buffer[((ileft + 1).min(self.received - 1) - start) as usize];
 
-        Self {
-            extractor: extractor_cfg.into(),
-            db: db_cfg.into(),
-            stride_dt
+            out.push(left * (1.0 - frac) + right * frac);
+            self.next += 1;
         }
+
+        let keep_from = (self.next * fs_in / fs_out).min(self.received);
+        self.buffer.drain(..(keep_from - start) as usize);
+
+        out
     }
+}
 
-    #[wasm_bindgen]
-    pub fn register(&mut self, uuid: String, audio: &[f32]) -> Result<(), JsError> {
-        let uuid = uuid::Uuid::try_parse(&uuid)?;
+/// Hann-windowed sinc with unity DC gain, cutting off at `ratio` of the input Nyquist.
+fn lowpass_kernel(ratio: f32) -> Vec<f32> {
+    let half = (4.0 / ratio).ceil() as usize;
+    let len = 2 * half + 1;
 
-        self.db.insert(uuid, self.extractor.features(audio));
+    let kernel: Vec<f32> = (0..len)
+        .map(|i| {
+            let x = i as f32 - half as f32;
+            let sinc = if x == 0.0 { 1.0 } else { (std::f32::consts::PI * ratio * x).sin() / (std::f32::consts::PI * ratio * x) };
+            let window = 0.5 - 0.5 * (std::f32::consts::TAU * i as f32 / (len - 1) as f32).cos();
+            sinc * window
+        })
+        .collect();
+
+    let gain: f32 = kernel.iter().sum();
+    kernel.into_iter().map(|tap| tap / gain).collect()
+}
 
-        Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resample_to_len() {
+        // a sine of 100 samples per cycle
+        let sine = |period: f32, len: usize| -> Vec<f32> { (0..len).map(|i| (std::f32::consts::TAU * i as f32 / period).sin()).collect() };
+        let audio = sine(100.0, 1000);
+
+        assert_eq!(resample_to_len(&audio, audio.len()), audio);
+
+        // halving the rate keeps every other sample: the same tone, 50 samples per cycle
+        let halved = resample(&audio, 44_100, 22_050);
+        assert_eq!(halved.len(), 500);
+        assert!(halved.iter().zip(sine(50.0, 500)).all(|(a, b)| (a - b).abs() < 1e-5));
+
+        // doubling it keeps the originals and puts midpoints between them
+        let doubled = resample(&audio, 22_050, 44_100);
+        assert_eq!(doubled.len(), 2000);
+        assert!(doubled.iter().step_by(2).zip(&audio).all(|(a, b)| a == b));
+        assert!((doubled[1] - 0.5 * (audio[0] + audio[1])).abs() < 1e-6);
+
+        assert_eq!(resample_to_len(&[], 4), vec![0.0; 4]);
     }
 
-    pub fn search(&mut self, audio: &[f32]) -> Vec<SessionQueryResult> {
-        let features = self.extractor.features(audio);
+    #[test]
+    fn test_resampler_reuse() {
+        let clips: Vec<Vec<f32>> = (0..20).map(|seed| noise(4410, seed)).collect();
+        let resampler = Resampler::new(44_100, 11_500);
+
+        // the kernel is built once, in `new`, and every clip reuses it
+        assert_eq!(resampler.kernel, Some(lowpass_kernel(11_500.0 / 44_100.0)));
 
-        let mut q = self.db.new_query();
+        let cached: Vec<Vec<f32>> = clips.iter().map(|clip| resampler.resample(clip)).collect();
+        let transient: Vec<Vec<f32>> = clips.iter().map(|clip| resample_antialiased(clip, 44_100, 11_500)).collect();
+        assert_eq!(cached, transient);
+
+        // upsampling needs no kernel and stays linear
+        let upsampler = Resampler::new(11_500, 44_100);
+        assert!(upsampler.kernel.is_none());
+        assert_eq!(upsampler.resample(&clips[0]), resample(&clips[0], 11_500, 44_100));
+    }
+
+    #[test]
+    fn test_resample_into() {
+        let audio: Vec<f32> = (0..4410).map(|i| (i as f32 * 0.02).sin()).collect();
+        let expected = resample(&audio, 44_100, 11_500);
+
+        let mut out = vec![0.0; expected.len()];
+        assert_eq!(resample_into(&audio, &mut out, 44_100, 11_500), expected.len());
+        assert_eq!(out, expected);
+
+        // a short buffer is filled as far as it goes
+        let mut short = vec![0.0; 100];
+        assert_eq!(resample_into(&audio, &mut short, 44_100, 11_500), 100);
+        assert_eq!(short, expected[..100]);
+    }
+
+    #[test]
+    fn test_streaming_resampler() {
+        // converts to a whole number of output samples at every rate pair below
+        let audio: Vec<f32> = (0..441 * 115 * 2).map(|i| (i as f32 * 0.03).sin()).collect();
+
+        for (fs_in, fs_out) in [(44_100, 11_500), (11_500, 44_100), (48_000, 48_000), (44_100, 22_050)] {
+            let expected = resample(&audio, fs_in, fs_out);
+
+            for chunk_len in [1, 7, 441, 4410, 10_000] {
+                let mut resampler = StreamingResampler::new(fs_in, fs_out);
+                let mut streamed: Vec<f32> = audio.chunks(chunk_len).flat_map(|chunk| resampler.process(chunk)).collect();
+                streamed.extend(resampler.finish());
+
+                assert_eq!(streamed, expected, "{fs_in} -> {fs_out} in chunks of {chunk_len}");
+            }
+        }
 
-        for feature in features.into_iter() { q.update(feature); }
+        // deep into a stream, past where 32-bit sample positions would overflow, output
+        // continues exactly as from the start of a period of the rate ratio
+        let mut fresh = StreamingResampler::new(44_100, 11_500);
+        let mut late = StreamingResampler { received: 441 << 25, next: 115 << 25, ..StreamingResampler::new(44_100, 11_500) };
+        assert_eq!(late.process(&audio), fresh.process(&audio));
+        assert_eq!(late.finish(), fresh.finish());
 
-        q.finalize().into_iter()
-            .map(|res| SessionQueryResult {
-                uuid: res.uuid.to_string(),
-                score: res.score,
-                key_start: res.key_start as f32 * self.stride_dt,
-                key_end: res.key_end as f32 * self.stride_dt,
-                query_start: res.query_start as f32 * self.stride_dt,
+        assert!(StreamingResampler::new(0, 11_500).process(&audio).is_empty());
+        assert!(StreamingResampler::new(44_100, 11_500).finish().is_empty());
+    }
+
+    /// A sine at half scale, so tests don't need audio fixtures.
+    pub(crate) fn synthesize_tone(freq: f32, duration: f32, sample_rate: usize) -> Vec<f32> {
+        let len = (duration * sample_rate as f32) as usize;
+        (0..len)
+            .map(|i| (std::f32::consts::TAU * freq * i as f32 / sample_rate as f32).sin() * 0.5)
+            .collect()
+    }
+
+    /// `synthesize_tone`s of `note_secs` each, one after another.
+    pub(crate) fn synth_melody(freqs: &[f32], note_secs: f32, sample_rate: usize) -> Vec<f32> {
+        freqs.iter().flat_map(|&f| synthesize_tone(f, note_secs, sample_rate)).collect()
+    }
+
+    /// The tune most tests register; its first six notes make the shorter verse.
+    pub(crate) const MELODY: [f32; 8] = [261.6, 329.6, 392.0, 523.3, 440.0, 349.2, 293.7, 246.9];
+
+    /// `count` eight-note melodies stepping through one scale at different
+    /// intervals, half a second a note: a catalogue of near neighbours.
+    pub(crate) fn synth_catalogue(count: usize, sample_rate: usize) -> Vec<Vec<f32>> {
+        let scale = [261.6, 293.7, 329.6, 349.2, 392.0, 440.0, 493.9, 523.3];
+        (0..count)
+            .map(|i| {
+                let melody: Vec<f32> = (0..8).map(|n| scale[(i * 5 + n * (i % 3 + 2)) % scale.len()]).collect();
+                synth_melody(&melody, 0.5, sample_rate)
             })
             .collect()
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    pub(crate) fn noise(len: usize, mut seed: u32) -> Vec<f32> {
+        (0..len)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                seed as f32 / u32::MAX as f32 - 0.5
+            })
+            .collect()
+    }
 
+    /// An endless xorshift64 stream, for random features and chunk sizes. `seed`
+    /// must be nonzero.
+    pub(crate) fn random_u64s(mut seed: u64) -> impl Iterator<Item = u64> {
+        std::iter::repeat_with(move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed
+        })
+    }
 }
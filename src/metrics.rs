@@ -0,0 +1,82 @@
+//! Running totals of a `Session`'s work, for scraping by a native service.
+
+use std::{fmt::Write, time::Duration};
+
+/// Counts of registrations and searches since the session was created. Timing uses
+/// `std::time::Instant`, so this is for native builds only.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SessionMetrics {
+    pub registrations: u64,
+    pub searches: u64,
+    /// summed wall time of every search
+    pub search_time: Duration,
+    /// summed result count of every search
+    pub search_results: u64,
+    /// songs in the database after the latest registration
+    pub songs: usize,
+}
+
+// only a `Session` records, and there's none without the `wasm` feature
+#[cfg_attr(not(feature = "wasm"), allow(dead_code))]
+impl SessionMetrics {
+    pub(crate) fn record_registration(&mut self, songs: usize) {
+        self.registrations += 1;
+        self.songs = songs;
+    }
+
+    pub(crate) fn record_search(&mut self, elapsed: Duration, results: usize) {
+        self.searches += 1;
+        self.search_time += elapsed;
+        self.search_results += results as u64;
+    }
+
+    /// Mean search latency, zero before the first search.
+    pub fn mean_search_latency(&self) -> Duration {
+        if self.searches == 0 { return Duration::ZERO }
+        self.search_time / self.searches as u32
+    }
+
+    /// Mean results per search, zero before the first search.
+    pub fn mean_result_count(&self) -> f64 {
+        if self.searches == 0 { return 0.0 }
+        self.search_results as f64 / self.searches as f64
+    }
+
+    /// Formats the metrics in the Prometheus text exposition format.
+    pub fn to_prometheus_text(&self) -> String {
+        let metrics: [(&str, &str, &str, String); 5] = [
+            ("session_registrations_total", "counter", "Songs registered.", self.registrations.to_string()),
+            ("session_searches_total", "counter", "Searches run.", self.searches.to_string()),
+            ("session_search_latency_seconds_mean", "gauge", "Mean search latency in seconds.", self.mean_search_latency().as_secs_f64().to_string()),
+            ("session_search_results_mean", "gauge", "Mean results returned per search.", self.mean_result_count().to_string()),
+            ("session_songs", "gauge", "Songs in the database.", self.songs.to_string()),
+        ];
+
+        let mut text = String::new();
+        for (name, kind, help, value) in metrics {
+            // writing to a String can't fail
+            let _ = writeln!(text, "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}");
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prometheus_text() {
+        let mut metrics = SessionMetrics::default();
+        assert!(metrics.to_prometheus_text().contains("\nsession_search_results_mean 0\n"));
+
+        metrics.record_registration(1);
+        metrics.record_search(Duration::from_millis(10), 3);
+        metrics.record_search(Duration::from_millis(30), 0);
+
+        let text = metrics.to_prometheus_text();
+        assert!(text.contains("# TYPE session_searches_total counter\nsession_searches_total 2\n"));
+        assert!(text.contains("\nsession_search_latency_seconds_mean 0.02\n"));
+        assert!(text.contains("\nsession_search_results_mean 1.5\n"));
+    }
+}
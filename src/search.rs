@@ -1,59 +1,504 @@
-use crate::{config::SessionConfiguration, fingerprint::Feature};
+use crate::{config::{ConfigError, SessionConfiguration}, fingerprint::{CompressedIter, CompressedSong, Feature, FeatureExtractor, FeatureExtractorConfiguration}};
 
-use std::{cmp::Ordering, collections::{BinaryHeap, HashMap, hash_map::Entry::{Occupied, Vacant}}};
+use std::{cmp::{Ordering, Reverse}, io::{Read, Write}, ops::Range, sync::atomic::{AtomicU64, Ordering::Relaxed}, collections::{BinaryHeap, HashMap, HashSet, hash_map::Entry::{Occupied, Vacant}}};
 use uuid::Uuid;
 use serde::{Serialize, Deserialize};
 
+/// Key length, in frames, at which `PenaltyMode::LogLength` seeds with exactly
+/// `search_score_penalty` (about three minutes at the default stride).
+const REFERENCE_KEY_FRAMES: f32 = 1024.0;
+
+/// Leading bytes of a `FeatureDatabaseFile`.
+const FILE_MAGIC: [u8; 4] = *b"SRFD";
+
+/// Layout version of `FeatureDatabaseFile`, bumped whenever the layout changes.
+const FILE_VERSION: u32 = 1;
+
+/// Fixed-point scale applied to scores when `search_decay` or `search_energy_weight`
+/// is active, so decayed or weighted sums keep their fractional part in integer `Fraction`s.
+const DECAY_SCALE: u32 = 1 << 10;
+
+/// With `search_adaptive_beams`, songs whose best score is within this factor of
+/// the overall best are contenders; the rest keep only a quarter of their budget.
+const ADAPTIVE_CONTENDER_RATIO: f32 = 1.5;
+
+/// With `search_max_key_scan`, every this many query frames the whole key is
+/// scanned anyway, so new matches can seed anywhere.
+const KEY_SCAN_RESEED_INTERVAL: usize = 8;
+
+/// Fewest beams `Database::suggest_beam_count` recommends.
+const MIN_SUGGESTED_BEAMS: usize = 16;
+
+/// How a beam's running score is held.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum ScoreRepr {
+    /// an exact integer fraction, whose terms grow with the match
+    Rational,
+    /// the quotient itself as an `f32`, updated incrementally, so very long matches
+    /// can't overflow and comparisons skip the cross-multiply
+    RunningMean,
+}
+
+/// What beams must share to recombine, the weaker giving way to the stronger.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum RecombKey {
+    /// the key frame they end on
+    KeyEnd,
+    /// the key frame they end on and their `query_start` bucketed by
+    /// `search_recomb_bucket` frames, so matches of different query regions to the
+    /// same key position are all kept
+    KeyEndQueryBucket,
+}
+
+/// How the seeding penalty of a new beam is chosen per song.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum PenaltyMode {
+    /// every song is seeded with `search_score_penalty`
+    Fixed,
+    /// the penalty scales with `ln(song length)`, so short jingles and long tracks
+    /// pay a penalty proportional to how many seed positions they offer
+    LogLength,
+}
+
+#[derive(Clone)]
 pub struct DatabaseConfiguration {
     sample_rate: usize,
+    window_size: usize,
     window_stride: usize,
+    /// `FeatureExtractorConfiguration::frame_offset`, for the `*_sample` methods
+    frame_offset: usize,
 
     chroma_bins_per_octave: usize,
 
     quantizer_bits_per_bin: usize,
+    /// `(bit offset, width)` of each chroma bin, for reading features coarsely
+    bin_layout: Vec<(usize, usize)>,
+    /// `config_hash` of the extractor settings, which features registered through
+    /// `register_features` and `import_database` must match
+    config_fingerprint: u64,
 
     search_beam_count: usize,
+    search_adaptive_beams: bool,
+    /// how far past the next key frame a beam may step; in source frames for songs
+    /// with a frame map
     search_window_size: usize,
+    search_window_back: usize,
+    /// added per key frame a beam skips when it extends past the next frame
+    search_jump_penalty: u32,
+    /// also extend each beam along its second-best step, approximating a DTW
+    /// lattice for queries whose tempo drifts
+    search_branch: bool,
+    /// most a result may overlap a better one from the same song, as a fraction of
+    /// its span: 0.0 suppresses any overlap, 1.0 suppresses nothing
     search_nonmax_overlap: f32,
     search_length_penalty: u32,
     search_score_penalty: u32,
+    search_penalty_mode: PenaltyMode,
+    search_score_repr: ScoreRepr,
+    search_recomb_by: RecombKey,
+    search_recomb_bucket: usize,
+    search_score_breakdown: bool,
+    search_decay: f32,
+    search_max_query_frames: Option<usize>,
+    search_context_frames: usize,
+    /// once a song has beams, score only windows of this many key frames around
+    /// where each beam goes next, scanning the whole key every `KEY_SCAN_RESEED_INTERVAL` frames
+    search_max_key_scan: Option<usize>,
+    /// keep only each song's best this many beams when finalizing, so one long
+    /// track can't crowd out the rest
+    search_max_results_per_song: Option<usize>,
+    /// drop a beam once its mean feature distance per frame exceeds this
+    search_max_mean_distance: Option<f32>,
+    search_prior_weight: u32,
+    search_energy_weight: bool,
+    /// scale each step's seed penalty by `2 * (1 - contrast)` of its query frame, so
+    /// peaky frames seed strong beams and flat ones weak beams
+    search_contrast_seeding: bool,
+    /// scale each seed's penalty by `1.5 - rarity` of the key feature it starts on,
+    /// where rarity is the feature's inverse document frequency within its song
+    /// normalized to `[0, 1]`, so beams anchored on sustained, repeated tones seed weak
+    search_idf_seeding: bool,
+    /// also keep each song OR-reduced over this many frames, for a coarse first pass
+    search_coarse_factor: Option<usize>,
+}
+
+impl DatabaseConfiguration {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.search_beam_count == 0 { return Err(ConfigError::BeamCount) }
+        if !(self.search_decay > 0.0 && self.search_decay <= 1.0) { return Err(ConfigError::Decay(self.search_decay)) }
+        if !(0.0..=1.0).contains(&self.search_nonmax_overlap) { return Err(ConfigError::NonmaxOverlap(self.search_nonmax_overlap)) }
+
+        Ok(())
+    }
+
+    fn decays(&self) -> bool { self.search_decay < 1.0 }
+
+    fn score_scale(&self) -> u32 { if self.decays() || self.search_energy_weight { DECAY_SCALE } else { 1 } }
+
+    /// The recombination table slot of a beam ending on `key_end` that started at `query_start`.
+    fn recomb_key(&self, key_end: usize, query_start: usize) -> (usize, usize) {
+        match self.search_recomb_by {
+            RecombKey::KeyEnd => (key_end, 0),
+            RecombKey::KeyEndQueryBucket => (key_end, query_start / self.search_recomb_bucket.max(1)),
+        }
+    }
+
+    /// Per-song beam limits for this step, given each song's best candidate score.
+    /// Without `search_adaptive_beams` every song gets `search_beam_count`; with it,
+    /// clearly losing songs give up most of theirs to the contenders, keeping the
+    /// total at `search_beam_count` per song.
+    fn beam_budgets(&self, best_scores: &[Option<f32>]) -> Vec<usize> {
+        let count = self.search_beam_count;
+        let global_best = best_scores.iter().flatten().copied().reduce(f32::min);
+
+        let (true, Some(global_best)) = (self.search_adaptive_beams, global_best) else {
+            return vec![count; best_scores.len()];
+        };
+
+        let is_contender = |best: &Option<f32>| best.is_some_and(|best| best <= global_best * ADAPTIVE_CONTENDER_RATIO);
+        let contenders = best_scores.iter().filter(|best| is_contender(best)).count();
+        let losing_budget = (count / 4).max(1);
+        let contender_budget = (count * best_scores.len() - losing_budget * (best_scores.len() - contenders)) / contenders;
+
+        best_scores
+            .iter()
+            .map(|best| if is_contender(best) { contender_budget } else { losing_budget })
+            .collect()
+    }
+
+    /// Reduces a feature to the set of chroma bins in the top half of their
+    /// thermometer range, which survives small shifts in window alignment.
+    fn coarse_token(&self, feature: Feature) -> u64 {
+        self.bin_layout
+            .iter()
+            .enumerate()
+            .filter(|&(_, &(offset, width))| width > 0 && (*feature.as_ref() >> (offset + width / 2)) & 1 == 1)
+            .fold(0, |token, (bin, _)| token | 1 << bin)
+    }
+
+    /// The best and worst scores a match over `query_frames` frames can get: a
+    /// perfect match pays only the seeding penalty, the worst also differs in every
+    /// feature bit and jumps as far as the window allows at each step. Assumes
+    /// `PenaltyMode::Fixed`, no `search_decay`, and songs registered without a prior.
+    pub fn score_bounds(&self, query_frames: usize) -> (f32, f32) {
+        let frames = query_frames.max(1) as f32;
+        let penalty = self.search_score_penalty as f32;
+        let d = frames + self.search_length_penalty as f32;
+
+        let max_distance: usize = self.bin_layout.iter().map(|&(_, width)| width).sum();
+        let max_jump = self.search_window_size.saturating_sub(1) as f32 * self.search_jump_penalty as f32;
+        let worst = penalty + frames * max_distance as f32 + (frames - 1.0) * max_jump;
+
+        (penalty / d, worst / d)
+    }
+
+    fn seed_penalty(&self, key_len: usize) -> u32 {
+        match self.search_penalty_mode {
+            PenaltyMode::Fixed => self.search_score_penalty,
+            PenaltyMode::LogLength => {
+                let scale = (key_len.max(2) as f32).ln() / REFERENCE_KEY_FRAMES.ln();
+                (self.search_score_penalty as f32 * scale).round() as u32
+            }
+        }
+    }
 }
 
 impl From<&SessionConfiguration> for DatabaseConfiguration {
     fn from(value: &SessionConfiguration) -> Self {
         Self {
             sample_rate: value.sample_rate,
+            window_size: value.window_size,
             window_stride: value.window_stride,
+            frame_offset: FeatureExtractorConfiguration::from(value).frame_offset(),
 
             chroma_bins_per_octave: value.chroma_bins_per_octave,
             quantizer_bits_per_bin: value.quantizer_bits_per_bin,
+            bin_layout: FeatureExtractorConfiguration::from(value).bin_layout(),
+            config_fingerprint: FeatureExtractorConfiguration::from(value).config_hash(),
 
             search_beam_count: value.search_beam_count,
+            search_adaptive_beams: value.search_adaptive_beams,
             search_window_size: value.search_window_size,
+            search_window_back: value.search_window_back,
+            search_jump_penalty: value.search_jump_penalty,
+            search_branch: value.search_branch,
             search_nonmax_overlap: value.search_nonmax_overlap,
             search_length_penalty: value.search_length_penalty,
-            search_score_penalty: value.search_score_penalty
+            search_score_penalty: value.search_score_penalty,
+            search_penalty_mode: value.search_penalty_mode,
+            search_score_repr: value.search_score_repr,
+            search_recomb_by: value.search_recomb_by,
+            search_recomb_bucket: value.search_recomb_bucket,
+            search_score_breakdown: value.search_score_breakdown,
+            search_decay: value.search_decay,
+            search_max_query_frames: value.search_max_query_frames,
+            search_context_frames: value.search_context_frames,
+            search_max_key_scan: value.search_max_key_scan,
+            search_max_results_per_song: value.search_max_results_per_song,
+            search_max_mean_distance: value.search_max_mean_distance,
+            search_prior_weight: value.search_prior_weight,
+            search_energy_weight: value.search_energy_weight,
+            search_contrast_seeding: value.search_contrast_seeding,
+            search_idf_seeding: value.search_idf_seeding,
+            search_coarse_factor: value.search_coarse_factor,
         }
     }
 }
 
+#[derive(Debug, PartialEq)]
 pub struct QueryResult {
     pub uuid: Uuid,
     pub score: f32, 
     pub key_start: usize, 
     pub key_end: usize,
     pub query_start: usize,
+    /// `key_start..=key_end` widened by `search_context_frames` on each side,
+    /// clamped to the song
+    pub context_start: usize,
+    pub context_end: usize,
+    /// only populated when `search_score_breakdown` is enabled
+    pub breakdown: Option<ScoreBreakdown>,
+    /// samples between frames, for the `*_sample` methods
+    pub window_stride: usize,
+    /// the sample frame 0 is centered on, for the `*_sample` methods
+    pub frame_offset: usize,
+}
+
+impl QueryResult {
+    /// `key_start` as the registered audio's sample its window is centered on,
+    /// as `FeatureExtractor::frame_center` gives it.
+    pub fn key_start_sample(&self) -> usize { self.sample(self.key_start) }
+    pub fn key_end_sample(&self) -> usize { self.sample(self.key_end) }
+    /// `query_start` as the query audio's sample its window is centered on.
+    pub fn query_start_sample(&self) -> usize { self.sample(self.query_start) }
+    pub fn context_start_sample(&self) -> usize { self.sample(self.context_start) }
+    pub fn context_end_sample(&self) -> usize { self.sample(self.context_end) }
+
+    fn sample(&self, frame: usize) -> usize { frame * self.window_stride + self.frame_offset }
+}
+
+/// Splits a beam's score into its parts: `score = (raw_distance_sum + penalty) / (frames + search_length_penalty)`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ScoreBreakdown {
+    pub raw_distance_sum: u32,
+    pub penalty: u32,
+    pub frames: u32,
+}
+
+/// Diagnostics gathered while a query runs.
+pub struct QueryStats {
+    /// number of query features consumed so far
+    pub frames: usize,
+    /// the most beams any one song held after trimming
+    pub peak_beams: usize,
+    /// whether any song had candidates pruned by `search_beam_count`
+    pub beam_saturated: bool,
+    /// key frames compared against query features, summed over songs and frames
+    pub scanned_frames: usize,
+}
+
+/// A step of the beam search, reported to the callback set by `Query::on_trace`.
+#[cfg(feature = "trace")]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TraceEvent {
+    /// `count` new beams survived trimming in `uuid` at query frame `frame`
+    Seeded { uuid: Uuid, frame: usize, count: usize },
+    /// `count` candidates were trimmed from `uuid` to fit its beam budget
+    Pruned { uuid: Uuid, frame: usize, count: usize },
+    /// a final candidate, as `finalize` yields it
+    Candidate { uuid: Uuid, score: f32, key_start: usize, key_end: usize },
+}
+
+/// A database's songs in an explicit on-disk layout, independent of any struct's
+/// field order. All integers are little-endian:
+///
+/// ```text
+/// magic "SRFD" | version u32 | config hash u64 | song count u32
+/// per song: uuid [u8; 16] | feature count u32 | features [u64]
+/// ```
+pub struct FeatureDatabaseFile {
+    /// `FeatureExtractorConfiguration::config_hash` of the extractor that made the features
+    pub config_hash: u64,
+    pub songs: Vec<(Uuid, Vec<Feature>)>,
+}
+
+/// Why a `FeatureDatabaseFile` couldn't be read.
+#[derive(Debug)]
+pub enum FileError {
+    /// reading failed, including the file ending early
+    Io(std::io::Error),
+    /// the file doesn't start with the `SRFD` magic
+    BadMagic,
+    /// the file was written in a layout this build doesn't know
+    UnsupportedVersion(u32),
+}
+
+impl std::fmt::Display for FileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "couldn't read feature database: {err}"),
+            Self::BadMagic => write!(f, "not a feature database file"),
+            Self::UnsupportedVersion(v) => write!(f, "feature database version {v} is not supported (expected {FILE_VERSION})"),
+        }
+    }
+}
+
+impl std::error::Error for FileError {}
+
+impl From<std::io::Error> for FileError {
+    fn from(err: std::io::Error) -> Self { Self::Io(err) }
+}
+
+impl FeatureDatabaseFile {
+    pub fn write_to(&self, w: &mut impl Write) -> std::io::Result<()> {
+        w.write_all(&FILE_MAGIC)?;
+        w.write_all(&FILE_VERSION.to_le_bytes())?;
+        w.write_all(&self.config_hash.to_le_bytes())?;
+        w.write_all(&(self.songs.len() as u32).to_le_bytes())?;
+
+        for (uuid, features) in &self.songs {
+            w.write_all(uuid.as_bytes())?;
+            w.write_all(&(features.len() as u32).to_le_bytes())?;
+            for feature in features { w.write_all(&feature.as_ref().to_le_bytes())?; }
+        }
+
+        Ok(())
+    }
+
+    pub fn read_from(r: &mut impl Read) -> Result<Self, FileError> {
+        fn bytes<const N: usize>(r: &mut impl Read) -> std::io::Result<[u8; N]> {
+            let mut buf = [0; N];
+            r.read_exact(&mut buf)?;
+            Ok(buf)
+        }
+
+        if bytes::<4>(r)? != FILE_MAGIC { return Err(FileError::BadMagic) }
+
+        let version = u32::from_le_bytes(bytes(r)?);
+        if version != FILE_VERSION { return Err(FileError::UnsupportedVersion(version)) }
+
+        let config_hash = u64::from_le_bytes(bytes(r)?);
+        let song_count = u32::from_le_bytes(bytes(r)?);
+
+        // counts come from the file, so grow as data actually arrives
+        let mut songs = Vec::new();
+        for _ in 0..song_count {
+            let uuid = Uuid::from_bytes(bytes(r)?);
+            let len = u32::from_le_bytes(bytes(r)?);
+
+            let features = (0..len)
+                .map(|_| Ok(Feature::from(u64::from_le_bytes(bytes(r)?))))
+                .collect::<std::io::Result<Vec<Feature>>>()?;
+            songs.push((uuid, features));
+        }
+
+        Ok(Self { config_hash, songs })
+    }
+}
+
+/// Features made under different extractor settings than a database expects, so
+/// their distances would be meaningless.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FingerprintMismatch {
+    pub expected: u64,
+    pub found: u64,
+}
+
+impl std::fmt::Display for FingerprintMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "features were extracted with config fingerprint {:016x}, but the database expects {:016x}; re-extract them with the current settings", self.found, self.expected)
+    }
+}
+
+impl std::error::Error for FingerprintMismatch {}
+
+/// Footprint of a database's index.
+pub struct DatabaseStats {
+    pub song_count: usize,
+    pub total_features: usize,
+    pub longest_song: usize,
+    pub shortest_song: usize,
+    /// rough bytes held by features and per-song map entries
+    pub memory_bytes: usize,
 }
 
-struct Fraction { n: u32, d: u32 }
+/// A beam's score, `n / d`, held as `search_score_repr` asks.
+#[derive(Clone)]
+enum Fraction {
+    Rational { n: u32, d: u32 },
+    RunningMean { mean: f32, d: f32 },
+}
 
 impl Fraction {
-    pub fn to_f32(&self) -> f32 { self.n as f32 / self.d as f32 }
+    fn new(n: u32, d: u32, repr: ScoreRepr) -> Self {
+        match repr {
+            ScoreRepr::Rational => Self::Rational { n, d },
+            ScoreRepr::RunningMean => Self::RunningMean { mean: n as f32 / d as f32, d: d as f32 },
+        }
+    }
+
+    pub fn to_f32(&self) -> f32 {
+        match *self {
+            Self::Rational { n, d } => n as f32 / d as f32,
+            Self::RunningMean { mean, .. } => mean,
+        }
+    }
+
+    /// the summed distance and penalty, `n`
+    fn numerator(&self) -> u32 {
+        match *self {
+            Self::Rational { n, .. } => n,
+            Self::RunningMean { mean, d } => (mean * d).round() as u32,
+        }
+    }
+
+    /// adds `n` to the numerator and `d` to the denominator
+    fn add(&mut self, n: u32, d: u32) {
+        match self {
+            Self::Rational { n: sum, d: count } => {
+                *sum += n;
+                *count += d;
+            }
+            Self::RunningMean { mean, d: count } => {
+                *count += d as f32;
+                if *count > 0.0 { *mean += (n as f32 - d as f32 * *mean) / *count; }
+            }
+        }
+    }
+
+    /// takes `n` and `d` back out, the inverse of `add` for already decayed terms
+    fn remove(&mut self, n: f32, d: f32) {
+        match self {
+            Self::Rational { n: sum, d: count } => {
+                *sum = (*sum as f32 - n).round().max(0.0) as u32;
+                *count = (*count as f32 - d).round().max(0.0) as u32;
+            }
+            Self::RunningMean { mean, d: count } => {
+                let total = *mean * *count - n;
+                *count = (*count - d).max(0.0);
+                if *count > 0.0 { *mean = total.max(0.0) / *count; }
+            }
+        }
+    }
+
+    /// scales both terms so earlier contributions fade relative to new ones
+    fn decay(&mut self, factor: f32) {
+        match self {
+            Self::Rational { n, d } => {
+                *n = (*n as f32 * factor).round() as u32;
+                *d = (*d as f32 * factor).round() as u32;
+            }
+            Self::RunningMean { d, .. } => *d *= factor,
+        }
+    }
 }
 
 impl PartialEq for Fraction {
     fn eq(&self, other: &Self) -> bool {
-        self.n * other.d == other.n * self.d
+        self.cmp(other) == Ordering::Equal
     }
 }
 impl Eq for Fraction {}
@@ -64,16 +509,25 @@ impl PartialOrd for Fraction {
 }
 impl Ord for Fraction {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        let selfscore = self.n * other.d;
-        let otherscore = other.n * self.d;
-        selfscore.cmp(&otherscore)
+        match (self, other) {
+            (Self::Rational { n, d }, Self::Rational { n: other_n, d: other_d }) => {
+                let selfscore = *n as u64 * *other_d as u64;
+                let otherscore = *other_n as u64 * *d as u64;
+                selfscore.cmp(&otherscore)
+            }
+            _ => self.to_f32().total_cmp(&other.to_f32()),
+        }
     }
 }
 
-#[derive(PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
 struct Beam {
     query_start: usize,
     path: Vec<usize>,
+    penalty: u32,
+    /// each path frame's numerator, denominator and jump penalty as added, kept
+    /// only under `search_max_query_frames` so rolled-off frames can be taken back out
+    steps: Vec<(u32, u32, u32)>,
 }
 
 impl Beam {
@@ -82,208 +536,996 @@ impl Beam {
 }
 
 
+/// A song's features as held by the database.
+enum Song {
+    Plain(Vec<Feature>),
+    Compressed(CompressedSong),
+}
+
+enum SongIter<'a> {
+    Plain(std::slice::Iter<'a, Feature>),
+    Compressed(CompressedIter<'a>),
+}
+
+impl Song {
+    fn len(&self) -> usize {
+        match self {
+            Self::Plain(features) => features.len(),
+            Self::Compressed(song) => song.len(),
+        }
+    }
+
+    /// Distances from `feature` to the stored features in each of `spans`, which
+    /// must be sorted and disjoint, concatenated. Batched for plain songs; a
+    /// compressed one is decoded in a single pass over all the spans.
+    fn distances(&self, feature: &Feature, spans: &[Range<usize>]) -> Vec<u32> {
+        match self {
+            Self::Plain(features) => spans.iter().flat_map(|span| feature.distance_to_slice(&features[span.clone()])).collect(),
+            Self::Compressed(song) => {
+                let (mut iter, mut pos) = (song.iter(), 0);
+                let mut distances = Vec::with_capacity(spans.iter().map(Range::len).sum());
+                // an empty `take` never reaches the `skip`, so empty spans mustn't move `pos`
+                for span in spans.iter().filter(|span| !span.is_empty()) {
+                    distances.extend(iter.by_ref().skip(span.start - pos).take(span.len()).map(|key_feature| feature.distance(&key_feature)));
+                    pos = span.end;
+                }
+                distances
+            }
+        }
+    }
+
+    fn iter(&self) -> SongIter<'_> {
+        match self {
+            Self::Plain(features) => SongIter::Plain(features.iter()),
+            Self::Compressed(song) => SongIter::Compressed(song.iter()),
+        }
+    }
+
+    fn memory_bytes(&self) -> usize {
+        match self {
+            Self::Plain(features) => features.len() * std::mem::size_of::<Feature>(),
+            Self::Compressed(song) => song.byte_len(),
+        }
+    }
+}
+
+impl Iterator for SongIter<'_> {
+    type Item = Feature;
+
+    fn next(&mut self) -> Option<Feature> {
+        match self {
+            Self::Plain(iter) => iter.next().copied(),
+            Self::Compressed(iter) => iter.next(),
+        }
+    }
+}
+
+/// A song with the key frames its beams may occupy, and the beams themselves.
+type SongBeams<'a> = (&'a Uuid, &'a Song, Range<usize>, Vec<(Fraction, Beam)>);
+
 pub struct Query<'a> {
     database: &'a Database,
     head: usize,
-    song_beams: Vec<(&'a Uuid, &'a [Feature], Vec<(Fraction, Beam)>)>,
+    song_beams: Vec<SongBeams<'a>>,
+    peak_beams: usize,
+    saturated: bool,
+    scanned_frames: usize,
+    /// loudest query frame so far, for `search_energy_weight`
+    peak_energy: f32,
+    #[cfg(feature = "trace")]
+    tracer: Option<Box<dyn FnMut(TraceEvent) + 'a>>,
 }
 
 pub struct Database {
     cfg: DatabaseConfiguration,
-    database: HashMap<Uuid, Vec<Feature>>
+    database: HashMap<Uuid, Song>,
+    /// songs registered with a nonzero prior
+    priors: HashMap<Uuid, f32>,
+    /// source frame of each stored feature, for songs registered with gaps
+    frames: HashMap<Uuid, Vec<usize>>,
+    /// each key frame's `rarity`, per song, kept under `search_idf_seeding`
+    rarity: HashMap<Uuid, Vec<f32>>,
+    /// most songs held before inserting evicts the least recently used
+    capacity: Option<usize>,
+    /// tick at which each song was last inserted or returned by a query; atomic so
+    /// queries, which only borrow the database, can bump it
+    last_used: HashMap<Uuid, AtomicU64>,
+    clock: AtomicU64,
+    /// every song OR-reduced over `search_coarse_factor` frames, if set
+    coarse: Option<Box<Database>>,
 }
 
+/// Appends features to one song as they are extracted; see `Database::insert_streaming`.
+pub struct StreamingInsert<'a> {
+    features: &'a mut Vec<Feature>,
+    /// the song's `rarity`, filled in by `finish` under `search_idf_seeding`
+    rarity: Option<&'a mut Vec<f32>>,
+}
 
-impl Database {
-    pub fn insert(&mut self, key: Uuid, features: Vec<Feature>) {
-        self.database.insert(key, features);
+impl StreamingInsert<'_> {
+    /// Appends `features` directly after everything pushed so far.
+    pub fn push(&mut self, features: &[Feature]) {
+        self.features.extend_from_slice(features);
     }
 
-    pub fn new_query<'a>(&'a self) -> Query<'a> {
-        let beams = self.database
-            .iter()
-            .map(|(uuid, features)|
-                (uuid, features.as_slice(), Vec::with_capacity(self.cfg.search_beam_count)))
-            .collect();
-
-        Query { song_beams: beams, database: self, head: 0 }
+    /// Ends the stream, returning the song's length in frames.
+    pub fn finish(self) -> usize {
+        self.features.shrink_to_fit();
+        if let Some(slot) = self.rarity { *slot = rarity(self.features); }
+        self.features.len()
     }
 }
 
-impl From<DatabaseConfiguration> for Database {
-    fn from(cfg: DatabaseConfiguration) -> Self {
-        Self { cfg, database: HashMap::new() }
+
+impl Database {
+    /// Stores `features` under `key`, replacing any song already there. Returns the
+    /// song evicted to make room, if the database is at capacity.
+    pub fn insert(&mut self, key: Uuid, features: Vec<Feature>) -> Option<Uuid> {
+        let evicted = self.admit(key);
+        self.insert_derived(key, &features);
+        self.database.insert(key, Song::Plain(features));
+        evicted
     }
-}
 
-impl<'a> Query<'a> {
+    /// Like `insert`, for features tagged with the frame they came from, such as
+    /// onset-gated ones. Results report key positions as those frames.
+    pub fn insert_indexed(&mut self, key: Uuid, features: &[(usize, Feature)]) -> Option<Uuid> {
+        let (frames, features): (Vec<usize>, Vec<Feature>) = features.iter().copied().unzip();
 
-    pub fn update(&mut self, new_feature: Feature) {
+        let evicted = self.insert(key, features);
+        self.frames.insert(key, frames);
+        evicted
+    }
 
-        // allows us to lazily allocate a new beam
-        #[derive(PartialEq, Eq, PartialOrd, Ord)]
-        enum Candidate {
-            Existing(Beam),
-            Seed(usize)
-        }
+    /// Like `insert`, with a prior in `[0, 1]` for how likely the song is to be
+    /// queried. Its seeding penalty is lowered by `prior * search_prior_weight`,
+    /// which tips close calls toward likelier songs.
+    pub fn insert_with_prior(&mut self, key: Uuid, features: Vec<Feature>, prior: f32) -> Option<Uuid> {
+        let evicted = self.insert(key, features);
+        if prior > 0.0 { self.priors.insert(key, prior.min(1.0)); }
+        evicted
+    }
 
-        impl Candidate {
-            fn to_beam(self, query_start: usize) -> Beam {
-                match self {
-                    Self::Existing(beam) => beam,
-                    Self::Seed(key_start) => Beam { query_start, path: vec![key_start] }
-                }
-            }
+    /// Stores what's computed once per song from its features: the coarse tier and,
+    /// under `search_idf_seeding`, each frame's rarity.
+    fn insert_derived(&mut self, key: Uuid, features: &[Feature]) {
+        if let (Some(coarse), Some(factor)) = (&mut self.coarse, self.cfg.search_coarse_factor) {
+            coarse.insert(key, coarsen(features, factor));
         }
+        if self.cfg.search_idf_seeding { self.rarity.insert(key, rarity(features)); }
+    }
 
-        let cfg = &self.database.cfg;
+    /// Clears what's known about `key` ahead of storing it, and evicts the least
+    /// recently used other song if a new one would exceed the capacity.
+    fn admit(&mut self, key: Uuid) -> Option<Uuid> {
+        self.priors.remove(&key);
+        self.frames.remove(&key);
+        self.rarity.remove(&key);
+        if let Some(coarse) = &mut self.coarse { coarse.remove(&key); }
+
+        let full = self.capacity.is_some_and(|capacity| self.database.len() >= capacity);
+        let evicted = if full && !self.database.contains_key(&key) {
+            let oldest = self.database.keys().min_by_key(|uuid| self.last_used.get(uuid).map(|used| used.load(Relaxed))).copied();
+            oldest.inspect(|uuid| self.remove(uuid))
+        } else {
+            None
+        };
 
-        /*
-        for each song, timewarp existing beams and seed new ones using the new feature.
-        perform automatic merging/matching  of songs using end/start tables
-        */
+        self.last_used.entry(key).or_default();
+        self.touch(&key);
+        evicted
+    }
 
-        for (uuid, features, beams) in self.song_beams.iter_mut() {
+    fn remove(&mut self, key: &Uuid) {
+        self.database.remove(key);
+        self.priors.remove(key);
+        self.frames.remove(key);
+        self.rarity.remove(key);
+        self.last_used.remove(key);
+        if let Some(coarse) = &mut self.coarse { coarse.remove(key); }
+    }
 
-            // seed recombination table
-            let scores: Vec<u32> = features
-                .iter()
-                .map(|key_feature| new_feature.distance(key_feature))
-                .collect();
+    /// Marks a song as just used, so capacity eviction reaches it last. Queries
+    /// call this for each song they return.
+    pub fn touch(&self, key: &Uuid) {
+        if let Some(used) = self.last_used.get(key) {
+            used.store(self.clock.fetch_add(1, Relaxed) + 1, Relaxed);
+        }
+    }
 
-            let mut recomb_table: HashMap<usize, (Fraction, Candidate)> = HashMap::new();
+    fn prior_bonus(&self, key: &Uuid) -> u32 {
+        self.priors.get(key).map_or(0, |prior| (prior * self.cfg.search_prior_weight as f32).round() as u32)
+    }
 
-            // combine with existing beams
-            for (mut score, mut beam) in beams.drain(..) { // get beam
+    /// Starts registering `key` from features that arrive in chunks, replacing any
+    /// song already stored under it. Frames are indexed in the order they are pushed.
+    /// A song evicted to make room is dropped without being reported. Streamed songs
+    /// get no coarse tier, so they always make `coarse_shortlist`.
+    pub fn insert_streaming(&mut self, key: Uuid) -> StreamingInsert<'_> {
+        self.admit(key);
+        let song = self.database.entry(key).insert_entry(Song::Plain(Vec::new())).into_mut();
+        let rarity = self.cfg.search_idf_seeding.then(|| self.rarity.entry(key).or_default());
 
-                // extend beam
+        let Song::Plain(features) = song else { unreachable!() };
 
-                let head = beam.key_end();
+        StreamingInsert { features, rarity }
+    }
 
-                let start = head+1;
-                let end = (start+cfg.search_window_size).min(scores.len());
+    /// Stores `features` as bit-packed XOR deltas, trading a sequential decode per
+    /// query step for a much smaller footprint on sustained material.
+    pub fn insert_compressed(&mut self, key: Uuid, features: &[Feature]) -> Option<Uuid> {
+        let evicted = self.admit(key);
+        self.insert_derived(key, features);
+        self.database.insert(key, Song::Compressed(features.into()));
+        evicted
+    }
 
-                let min = scores[start..end]
-                    .iter()
-                    .enumerate()
-                    .min_by_key(|(_, &d)| d);
+    /// A registered song's length in seconds, as the span of audio its frames
+    /// cover, or `None` if `uuid` is unknown.
+    pub fn duration_of(&self, uuid: &Uuid) -> Option<f32> {
+        let song = self.database.get(uuid)?;
+        let frames = self.frames.get(uuid).and_then(|frames| frames.last()).map_or(song.len(), |last| last + 1);
+        if frames == 0 { return Some(0.0) }
 
-                if let Some((offset, distance)) = min {
-                    beam.path.push(start + offset);
-                    score.n += distance;
-                    score.d += 1;
-                }
-                
-                let entry = recomb_table.entry(beam.key_end());
+        let samples = (frames - 1) * self.cfg.window_stride + self.cfg.window_size;
+        Some(samples as f32 / self.cfg.sample_rate as f32)
+    }
 
-                match entry {
-                    Vacant(entry) => { entry.insert((score, Candidate::Existing(beam))); }
-                    Occupied(entry) => { // presumably the entry is another competing beam
-                        let (other_score, other_beam) = entry.into_mut();
+    /// Every song's features, for writing out; frame maps and priors are not kept.
+    pub fn to_file(&self, config_hash: u64) -> FeatureDatabaseFile {
+        let mut songs: Vec<(Uuid, Vec<Feature>)> = self.database
+            .iter()
+            .map(|(uuid, song)| (*uuid, song.iter().collect()))
+            .collect();
+        songs.sort_unstable_by_key(|(uuid, _)| *uuid);
 
-                        if score.cmp(other_score) == Ordering::Less { // if this beam is stronger, insert
-                            *other_score = score;
-                            *other_beam = Candidate::Existing(beam);
-                        }
-                    }
-                }
-            }
+        FeatureDatabaseFile { config_hash, songs }
+    }
 
-            // seed new beams
-            for (key_start, distance) in scores.into_iter().enumerate() {
-                let score = Fraction { n: cfg.search_score_penalty + distance, d: cfg.search_length_penalty + 1 };
+    /// Registers every song in `file`, replacing songs with the same uuid. The
+    /// file's `config_hash` isn't checked; see `import_database`.
+    pub fn insert_file(&mut self, file: FeatureDatabaseFile) {
+        for (uuid, features) in file.songs { self.insert(uuid, features); }
+    }
 
-                let entry = recomb_table.entry(key_start);
+    /// `config_hash` of the extractor settings this database was configured with.
+    pub fn config_fingerprint(&self) -> u64 {
+        self.cfg.config_fingerprint
+    }
 
-                match entry {
-                    Vacant(entry) => { entry.insert((score, Candidate::Seed(key_start))); }
-                    Occupied(entry) => { // presumably the entry is another competing beam
-                        let (other_score, other_beam) = entry.into_mut();
+    /// Like `insert`, for features extracted elsewhere under the settings hashed to
+    /// `config_fingerprint`, refusing them if those differ from this database's.
+    pub fn register_features(&mut self, key: Uuid, features: Vec<Feature>, config_fingerprint: u64) -> Result<Option<Uuid>, FingerprintMismatch> {
+        self.check_fingerprint(config_fingerprint)?;
+        Ok(self.insert(key, features))
+    }
 
-                        if score.cmp(other_score) == Ordering::Less { // if this beam is stronger, insert
-                            *other_score = score;
-                            *other_beam = Candidate::Seed(key_start);
-                        }
-                    }
-                }
-            }
+    /// Like `insert_file`, refusing the whole file if its `config_hash` differs from
+    /// this database's fingerprint. Returns how many songs were registered.
+    pub fn import_database(&mut self, file: FeatureDatabaseFile) -> Result<usize, FingerprintMismatch> {
+        self.check_fingerprint(file.config_hash)?;
 
-            let mut heap: BinaryHeap<_> = recomb_table
-                .into_values()
-                .collect();
+        let count = file.songs.len();
+        self.insert_file(file);
+        Ok(count)
+    }
 
-            // trim heap size, removing high scoring elements until size is OK.
-            while heap.len() > cfg.search_beam_count { heap.pop(); }
+    fn check_fingerprint(&self, found: u64) -> Result<(), FingerprintMismatch> {
+        let expected = self.cfg.config_fingerprint;
+        if found != expected { return Err(FingerprintMismatch { expected, found }) }
+        Ok(())
+    }
 
-            // convert hashmap into maxheap
-            *beams = heap
-                .drain()
-                .map(|(score, cand)| (score, cand.to_beam(self.head)))
-                .collect();
-        }
-        
-        self.head += 1;
+    /// A registered song's stored features, or `None` if `uuid` is unknown.
+    pub fn features(&self, uuid: &Uuid) -> Option<Vec<Feature>> {
+        self.database.get(uuid).map(|song| song.iter().collect())
     }
 
-    pub fn finalize(self) -> Vec<QueryResult> {
-        // get minheap
-        let mut heap: BinaryHeap<(Fraction, &Uuid, Beam)> = self.song_beams
-            .into_iter()
-            .flat_map(|(uuid, _, beams)| beams
-                .into_iter()
-                .map(move |(score, beam)| (score, uuid, beam)))
-            .collect();
+    /// How many songs are registered.
+    pub fn song_count(&self) -> usize {
+        self.database.len()
+    }
 
-        /*
+    pub fn stats(&self) -> DatabaseStats {
+        let lengths = self.database.values().map(Song::len);
+        let total_features: usize = lengths.clone().sum();
+        let entry_size = std::mem::size_of::<Uuid>() + std::mem::size_of::<Song>();
+
+        DatabaseStats {
+            song_count: self.database.len(),
+            total_features,
+            longest_song: lengths.clone().max().unwrap_or(0),
+            shortest_song: lengths.min().unwrap_or(0),
+            memory_bytes: self.database.values().map(Song::memory_bytes).sum::<usize>() + self.database.capacity() * entry_size,
+        }
+    }
 
+    /// A starting point for `search_beam_count`: a quarter of the mean song length in
+    /// frames, times `1 + log2(song count)`, between `MIN_SUGGESTED_BEAMS` and the
+    /// longest song's length. Beams recombine on their end frame, so a song never holds
+    /// more beams than it has frames, and longer songs offer more plausible seed
+    /// positions to keep alive; more songs mean more near misses competing. Raise it if
+    /// queries report `QueryStats::beam_saturated` and results look wrong.
+    pub fn suggest_beam_count(&self) -> usize {
+        let stats = self.stats();
+        if stats.song_count == 0 { return MIN_SUGGESTED_BEAMS }
 
-        let mut beams = self.song_beams.into_sorted_vec();
-        beams.reverse();
+        let mean_len = stats.total_features as f32 / stats.song_count as f32;
+        let suggested = mean_len / 4.0 * (1.0 + (stats.song_count as f32).log2());
 
-        let mut results = Vec::new();
+        (suggested.round() as usize).min(stats.longest_song).max(MIN_SUGGESTED_BEAMS)
+    }
 
-        // we need to merge contiguous beams
+    /// Finds pairs of songs whose sets of distinct features have a Jaccard similarity
+    /// of at least `threshold`, most similar first. Catches the same audio registered
+    /// under more than one uuid.
+    pub fn find_duplicates(&self, threshold: f32) -> Vec<(Uuid, Uuid, f32)> {
+        let mut songs: Vec<(&Uuid, HashSet<u64>)> = self.database
+            .iter()
+            .map(|(uuid, song)| (uuid, song.iter().map(|f| *f.as_ref()).collect()))
+            .collect();
+        songs.sort_unstable_by_key(|(uuid, _)| **uuid);
 
-        while let Some(beam) = beams.pop() {
-            // invalidate overlapping beams
-            beams.retain(|other_beam| {
-                let intersction_start = beam.start().max(other_beam.start());
-                let intersection_end = beam.end().min(other_beam.end());
-                let intersection = (intersection_end as isize - intersction_start as isize).max(0);
-                let union = other_beam.end() - other_beam.start();
+        let mut duplicates = Vec::new();
 
-                let overlap = if union != 0 { intersection as f32 / union as f32 } else { 0.0 };
-                
-                overlap < self.database.cfg.search_nonmax_overlap
-            });
+        for (i, (a, a_set)) in songs.iter().enumerate() {
+            for (b, b_set) in &songs[i+1..] {
+                let intersection = a_set.intersection(b_set).count();
+                let union = a_set.len() + b_set.len() - intersection;
+                let similarity = if union != 0 { intersection as f32 / union as f32 } else { 1.0 };
 
-            results.push(beam.into());
+                if similarity >= threshold { duplicates.push((**a, **b, similarity)); }
+            }
         }
 
-        results*/
+        duplicates.sort_by(|a, b| b.2.total_cmp(&a.2));
+        duplicates
+    }
 
-        let beams: Vec<_> = heap
-            .into_sorted_vec()
-            .into_iter()
-            .map(|(score, uuid, beam)| QueryResult { 
-                uuid: *uuid, 
-                score: score.to_f32(), 
-                key_start: beam.key_start(),
-                key_end: beam.key_end(),
-                query_start: beam.query_start
+    /// Cheaply ranks songs by how many of the query's coarse feature tokens they
+    /// share (a histogram intersection) and returns the `top_n` best, for a full
+    /// beam search over just those.
+    pub fn prefilter(&self, query_features: &[Feature], top_n: usize) -> Vec<Uuid> {
+        let histogram = |features: &mut dyn Iterator<Item = Feature>| {
+            let mut counts: HashMap<u64, u32> = HashMap::new();
+            for feature in features { *counts.entry(self.cfg.coarse_token(feature)).or_default() += 1; }
+            counts
+        };
+
+        let query = histogram(&mut query_features.iter().copied());
+
+        let mut ranked: Vec<(u32, Uuid)> = self.database
+            .iter()
+            .map(|(uuid, song)| {
+                let song = histogram(&mut song.iter());
+                let shared = query.iter().map(|(token, &count)| count.min(song.get(token).copied().unwrap_or(0))).sum();
+                (shared, *uuid)
             })
             .collect();
-        //beams.reverse();
 
-        beams
+        ranked.sort_unstable_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+        ranked.into_iter().take(top_n).map(|(_, uuid)| uuid).collect()
     }
-}
-
+
+    /// Runs a beam search of `query_features`, reduced like the stored songs, over
+    /// the coarse tier and returns the `top_n` best distinct songs, plus any song
+    /// without a coarse tier. `None` unless `search_coarse_factor` is set.
+    pub fn coarse_shortlist(&self, query_features: &[Feature], top_n: usize) -> Option<Vec<Uuid>> {
+        let (coarse, factor) = (self.coarse.as_ref()?, self.cfg.search_coarse_factor?.max(1));
+
+        // the query's reduction lines up with the key's at only one phase, so try each
+        let mut results: Vec<QueryResult> = (0..factor.min(query_features.len().max(1)))
+            .flat_map(|phase| {
+                let mut q = coarse.new_query();
+                for feature in coarsen(&query_features[phase..], factor) { q.update(feature); }
+                q.finalize()
+            })
+            .collect();
+        results.sort_by(|a, b| a.score.total_cmp(&b.score));
+
+        let mut shortlist: Vec<Uuid> = Vec::with_capacity(top_n);
+        for res in results {
+            if shortlist.len() == top_n { break }
+            if !shortlist.contains(&res.uuid) { shortlist.push(res.uuid); }
+        }
+        shortlist.extend(self.database.keys().filter(|uuid| !coarse.database.contains_key(uuid)));
+
+        Some(shortlist)
+    }
+
+    pub fn new_query<'a>(&'a self) -> Query<'a> {
+        let beams = self.database
+            .iter()
+            .map(|(uuid, song)|
+                (uuid, song, 0..song.len(), Vec::with_capacity(self.cfg.search_beam_count)))
+            .collect();
+
+        Query::new(self, beams)
+    }
+
+    /// Like `new_query`, but only matches the songs in `ranges`, and only within their
+    /// `(start, end)` key frame windows (end exclusive). Beams are neither seeded nor
+    /// extended outside a song's window. For songs with a frame map, such as onset-gated
+    /// ones, the window is in source frames and covers the features taken within it.
+    pub fn new_query_ranged<'a>(&'a self, ranges: HashMap<Uuid, (usize, usize)>) -> Query<'a> {
+        let beams = ranges
+            .into_iter()
+            .filter_map(|(uuid, (start, end))| {
+                let (uuid, song) = self.database.get_key_value(&uuid)?;
+                let (start, end) = match self.frames.get(uuid) {
+                    Some(frames) => (frames.partition_point(|&frame| frame < start), frames.partition_point(|&frame| frame < end)),
+                    None => (start, end),
+                };
+                let end = end.min(song.len());
+
+                Some((uuid, song, start.min(end)..end, Vec::with_capacity(self.cfg.search_beam_count)))
+            })
+            .collect();
+
+        Query::new(self, beams)
+    }
+
+    /// Runs the beam search for `features` against a single song only, returning
+    /// its best beam. Returns `None` if the song is not registered or nothing matched.
+    pub fn align_to(&self, uuid: &Uuid, features: &[Feature]) -> Option<QueryResult> {
+        let (uuid, key) = self.database.get_key_value(uuid)?;
+
+        self.align(uuid, key, features.iter().copied().enumerate())
+    }
+
+    /// Like `align_to`, for features tagged with the query frame they came from, such
+    /// as onset-gated ones; results report `query_start` as that frame.
+    pub fn align_to_indexed(&self, uuid: &Uuid, features: &[(usize, Feature)]) -> Option<QueryResult> {
+        let (uuid, key) = self.database.get_key_value(uuid)?;
+
+        self.align(uuid, key, features.iter().copied())
+    }
+
+    /// Aligns `features` against an unregistered `key`, as if it were the only song
+    /// in the database. The result's uuid is nil.
+    pub fn align_unregistered(&self, key: &[Feature], features: &[Feature]) -> Option<QueryResult> {
+        self.align(&Uuid::nil(), &Song::Plain(key.to_vec()), features.iter().copied().enumerate())
+    }
+
+    fn align(&self, uuid: &Uuid, key: &Song, features: impl Iterator<Item = (usize, Feature)>) -> Option<QueryResult> {
+        let mut q = Query::new(self, vec![(uuid, key, 0..key.len(), Vec::with_capacity(self.cfg.search_beam_count))]);
+
+        for (frame, feature) in features { q.update_at(feature, frame); }
+
+        q.finalize().into_iter().next()
+    }
+}
+
+/// Each frame's inverse document frequency within `song`, `ln(len / count)` of its
+/// feature scaled by `ln(len)` into `[0, 1]`: 1 for a feature heard once, 0 for one
+/// heard throughout. Songs of one frame get a neutral 0.5.
+fn rarity(song: &[Feature]) -> Vec<f32> {
+    let len = song.len();
+    if len < 2 { return vec![0.5; len] }
+
+    let mut counts: HashMap<u64, u32> = HashMap::new();
+    for feature in song { *counts.entry(*feature.as_ref()).or_default() += 1; }
+
+    let norm = (len as f32).ln();
+    song.iter()
+        .map(|feature| (len as f32 / counts[feature.as_ref()] as f32).ln() / norm)
+        .collect()
+}
+
+/// ORs together each run of `factor` features, so a coarse feature keeps every bin
+/// that was raised anywhere in its span.
+fn coarsen(features: &[Feature], factor: usize) -> Vec<Feature> {
+    features
+        .chunks(factor.max(1))
+        .map(|chunk| Feature::from(chunk.iter().fold(0, |acc, feature| acc | *feature.as_ref())))
+        .collect()
+}
+
+/// Row-major `N×N` matrix over `clips` whose entry `(i, j)` is the best score of clip
+/// `i` aligned against clip `j`, or infinity if nothing aligns. Lower is more alike.
+pub fn similarity_matrix(extractor: &FeatureExtractor, db_cfg: &DatabaseConfiguration, clips: &[Vec<f32>]) -> Vec<f32> {
+    let database = Database::from(db_cfg.clone());
+    let features: Vec<Vec<Feature>> = clips.iter().map(|clip| extractor.features(clip)).collect();
+
+    features
+        .iter()
+        .flat_map(|query| features.iter().map(|key| {
+            database.align_unregistered(key, query).map_or(f32::INFINITY, |res| res.score)
+        }))
+        .collect()
+}
+
+impl From<DatabaseConfiguration> for Database {
+    /// Panics on an invalid configuration; see `try_new`.
+    fn from(cfg: DatabaseConfiguration) -> Self {
+        Self::try_new(cfg).expect("invalid database configuration")
+    }
+}
+
+impl Database {
+    pub fn try_new(cfg: DatabaseConfiguration) -> Result<Self, ConfigError> {
+        cfg.validate()?;
+
+        // the coarse tier is a plain database of its own over the reduced features
+        let coarse = match cfg.search_coarse_factor {
+            Some(_) => Some(Box::new(Database::try_new(DatabaseConfiguration { search_coarse_factor: None, ..cfg.clone() })?)),
+            None => None,
+        };
+
+        Ok(Self {
+            cfg,
+            database: HashMap::new(),
+            priors: HashMap::new(),
+            frames: HashMap::new(),
+            rarity: HashMap::new(),
+            capacity: None,
+            last_used: HashMap::new(),
+            clock: AtomicU64::new(0),
+            coarse,
+        })
+    }
+
+    /// A database holding at most `max_songs`; inserting a new song beyond that
+    /// evicts the one least recently inserted or returned by a query. Panics on an invalid
+    /// configuration, like `from`.
+    pub fn with_capacity(cfg: DatabaseConfiguration, max_songs: usize) -> Self {
+        Self { capacity: Some(max_songs), ..Self::from(cfg) }
+    }
+}
+
+impl<'a> Query<'a> {
+    fn new(database: &'a Database, song_beams: Vec<SongBeams<'a>>) -> Self {
+        Query {
+            database,
+            head: 0,
+            song_beams,
+            peak_beams: 0,
+            saturated: false,
+            scanned_frames: 0,
+            peak_energy: 0.0,
+            #[cfg(feature = "trace")]
+            tracer: None,
+        }
+    }
+
+    /// Reports seeding, pruning and final candidates to `tracer` as the search runs.
+    #[cfg(feature = "trace")]
+    pub fn on_trace(&mut self, tracer: impl FnMut(TraceEvent) + 'a) {
+        self.tracer = Some(Box::new(tracer));
+    }
+
+    /// Feeds the next query feature. Past `search_max_query_frames`, beams keep only
+    /// their most recent frames when `search_decay` is fading old contributions out;
+    /// without decay the feature is ignored, see `is_full`.
+    pub fn update(&mut self, new_feature: Feature) {
+        self.update_at(new_feature, self.head)
+    }
+
+    /// Like `update`, for a feature taken from query frame `frame`; beams seeded
+    /// by it report that frame as their `query_start`.
+    pub fn update_at(&mut self, new_feature: Feature, frame: usize) {
+        self.advance(new_feature, frame, None, None)
+    }
+
+    /// Like `update_at`, also passing the frame's energy. With `search_energy_weight`,
+    /// each frame's distance counts in proportion to its energy relative to the
+    /// loudest frame so far, so quiet, noisy stretches barely move the score.
+    pub fn update_with_energy(&mut self, new_feature: Feature, frame: usize, energy: f32) {
+        self.advance(new_feature, frame, Some(energy), None)
+    }
+
+    /// Like `update_with_energy`, also passing the frame's spectral contrast in
+    /// `[0, 1]`, which sets its seed penalty under `search_contrast_seeding`. A
+    /// contrast of 0.5 leaves the penalty as is.
+    pub fn update_with_contrast(&mut self, new_feature: Feature, frame: usize, energy: f32, contrast: f32) {
+        self.advance(new_feature, frame, Some(energy), Some(contrast))
+    }
+
+    fn advance(&mut self, new_feature: Feature, frame: usize, energy: Option<f32>, contrast: Option<f32>) {
+
+        // allows us to lazily allocate a new beam
+        #[derive(PartialEq, Eq, PartialOrd, Ord)]
+        enum Candidate {
+            Existing(Beam),
+            /// key frame, scaled seed penalty and the seed frame's step, if tracked
+            Seed(usize, u32, Option<(u32, u32, u32)>)
+        }
+
+        impl Candidate {
+            fn to_beam(self, query_start: usize) -> Beam {
+                match self {
+                    Self::Existing(beam) => beam,
+                    Self::Seed(key_start, penalty, step) => Beam { query_start, path: vec![key_start], penalty, steps: step.into_iter().collect() }
+                }
+            }
+        }
+
+        let cfg = &self.database.cfg;
+        let scale = cfg.score_scale();
+
+        let max_frames = cfg.search_max_query_frames.unwrap_or(usize::MAX);
+        let tracks_steps = cfg.search_max_query_frames.is_some();
+        if self.is_full() { return }
+
+        // this frame's share of the fixed-point scale
+        let weight = match energy {
+            Some(energy) if cfg.search_energy_weight => {
+                self.peak_energy = self.peak_energy.max(energy);
+                let relative = if self.peak_energy > 0.0 { energy / self.peak_energy } else { 0.0 };
+                // at least 1, so a beam seeded in silence never has a zero denominator
+                ((relative * scale as f32).round() as u32).max(1)
+            }
+            _ => scale,
+        };
+
+        let seed_factor = match contrast {
+            Some(contrast) if cfg.search_contrast_seeding => 2.0 * (1.0 - contrast.clamp(0.0, 1.0)),
+            _ => 1.0,
+        };
+
+        /*
+        for each song, timewarp existing beams and seed new ones using the new feature.
+        perform automatic merging/matching  of songs using end/start tables
+        */
+
+        let mut heaps = Vec::with_capacity(self.song_beams.len());
+
+        for (uuid, features, range, beams) in self.song_beams.iter_mut() {
+            let frame_map = self.database.frames.get(*uuid);
+
+            // key frames scored this step, as sorted disjoint spans: the whole range, or
+            // with `search_max_key_scan` the union of the windows centred on where each
+            // beam expects to go next. Every `KEY_SCAN_RESEED_INTERVAL` frames the whole
+            // range is scanned regardless, so seeds can start anywhere and a wrong early
+            // lock can still be recovered from.
+            let spans = match cfg.search_max_key_scan {
+                Some(cap) if range.len() > cap && !beams.is_empty() && !self.head.is_multiple_of(KEY_SCAN_RESEED_INTERVAL) => {
+                    let mut windows: Vec<Range<usize>> = beams
+                        .iter()
+                        .map(|(_, beam)| {
+                            let start = (beam.key_end() + 1).saturating_sub(cap / 2).clamp(range.start, range.end - cap);
+                            start..start + cap
+                        })
+                        .collect();
+                    windows.sort_unstable_by_key(|window| window.start);
+
+                    let mut spans: Vec<Range<usize>> = Vec::new();
+                    for window in windows {
+                        match spans.last_mut() {
+                            Some(last) if window.start <= last.end => last.end = last.end.max(window.end),
+                            _ => spans.push(window),
+                        }
+                    }
+                    spans
+                }
+                _ => vec![range.clone()],
+            };
+
+            // seed recombination table; scores[offsets[i] + k] is the distance at key frame spans[i].start + k
+            let scores = features.distances(&new_feature, &spans);
+            let offsets: Vec<usize> = spans.iter().scan(0, |offset, span| {
+                let start = *offset;
+                *offset += span.len();
+                Some(start)
+            }).collect();
+            self.scanned_frames += scores.len();
+
+            let mut recomb_table: HashMap<(usize, usize), (Fraction, Candidate)> = HashMap::new();
+
+            // combine with existing beams
+            for (score, beam) in beams.drain(..) { // get beam
+
+                // extend beam
+
+                let head = beam.key_end();
+
+                // look behind the expected position, but never step back past the head
+                let expected = head+1;
+                let lookback = expected.saturating_sub(cfg.search_window_back).max(head);
+
+                // the scanned span the beam's head is in; the beam is lost if there is none
+                let i = spans.partition_point(|span| span.end <= head);
+                let Some(span) = spans.get(i) else { continue };
+                let start = lookback.max(span.start);
+                // with a frame map the window reaches `search_window_size` source frames past the expected one
+                let window_end = match frame_map.and_then(|frames| Some((frames, *frames.get(expected)?))) {
+                    Some((frames, expected_frame)) => frames.partition_point(|&frame| frame < expected_frame + cfg.search_window_size),
+                    None => expected + cfg.search_window_size,
+                };
+                let end = window_end.min(span.end);
+
+                // the beam has fallen outside the scanned spans
+                if start > end { continue }
+
+                // frames skipped past the expected one
+                let jump = |offset: usize| (start + offset).saturating_sub(expected) as u32 * cfg.search_jump_penalty * scale;
+
+                let window = &scores[offsets[i] + start - span.start..offsets[i] + end - span.start];
+                let rank = |&(offset, &d): &(usize, &u32)| (d * weight + jump(offset), d, (start + offset).abs_diff(expected));
+                let min = window.iter().enumerate().min_by_key(rank);
+
+                // with `search_branch` the runner-up step is followed too, as a beam of its own
+                let runner_up = min
+                    .filter(|_| cfg.search_branch)
+                    .and_then(|(min_offset, _)| window.iter().enumerate().filter(|&(offset, _)| offset != min_offset).min_by_key(rank));
+                let branch = runner_up.map(|step| (score.clone(), beam.clone(), Some(step)));
+
+                for (mut score, mut beam, step) in std::iter::once((score, beam, min)).chain(branch) {
+                    if let Some((offset, &distance)) = step {
+                        beam.path.push(start + offset);
+
+                        if cfg.decays() {
+                            score.decay(cfg.search_decay);
+                            beam.penalty = (beam.penalty as f32 * cfg.search_decay).round() as u32;
+                        }
+
+                        score.add(distance * weight + jump(offset), weight);
+                        beam.penalty += jump(offset);
+                        if tracks_steps { beam.steps.push((distance * weight + jump(offset), weight, jump(offset))); }
+                    }
+
+                    // hopeless beams give up their slot
+                    let distance_sum = score.numerator().saturating_sub(beam.penalty) as f32 / scale as f32;
+                    if cfg.search_max_mean_distance.is_some_and(|cap| distance_sum > cap * beam.path.len() as f32) { continue }
+
+                    // roll off frames whose contributions have decayed away
+                    if beam.path.len() > max_frames {
+                        let excess = beam.path.len() - max_frames;
+                        let len = beam.steps.len();
+                        for (age, (n, d, penalty)) in beam.steps.drain(..excess).enumerate().map(|(i, step)| (len - 1 - i, step)) {
+                            let factor = cfg.search_decay.powi(age as i32);
+                            score.remove(n as f32 * factor, d as f32 * factor);
+                            beam.penalty = beam.penalty.saturating_sub((penalty as f32 * factor).round() as u32);
+                        }
+                        beam.path.drain(..excess);
+                        beam.query_start += excess;
+                    }
+
+                    let entry = recomb_table.entry(cfg.recomb_key(beam.key_end(), beam.query_start));
+
+                    match entry {
+                        Vacant(entry) => { entry.insert((score, Candidate::Existing(beam))); }
+                        Occupied(entry) => { // presumably the entry is another competing beam
+                            let (other_score, other_beam) = entry.into_mut();
+
+                            if score.cmp(other_score) == Ordering::Less { // if this beam is stronger, insert
+                                *other_score = score;
+                                *other_beam = Candidate::Existing(beam);
+                            }
+                        }
+                    }
+                }
+            }
+
+            // seed new beams
+            let seed_penalty = cfg.seed_penalty(features.len()).saturating_sub(self.database.prior_bonus(uuid));
+            let seed_penalty = seed_penalty as f32 * seed_factor;
+            let rarity = self.database.rarity.get(uuid);
+            for (key_start, distance) in spans.iter().flat_map(|span| span.clone()).zip(scores) {
+                if cfg.search_max_mean_distance.is_some_and(|cap| (distance * weight) as f32 > cap * scale as f32) { continue }
+
+                let idf_factor = rarity.and_then(|rarity| rarity.get(key_start)).map_or(1.0, |rarity| 1.5 - rarity);
+                let penalty = (seed_penalty * idf_factor).round() as u32 * scale;
+                let score = Fraction::new(penalty + distance * weight, cfg.search_length_penalty * scale + weight, cfg.search_score_repr);
+                // the seed penalty and length prior belong to the beam, not its first frame
+                let step = tracks_steps.then_some((distance * weight, weight, 0));
+
+                let entry = recomb_table.entry(cfg.recomb_key(key_start, frame));
+
+                match entry {
+                    Vacant(entry) => { entry.insert((score, Candidate::Seed(key_start, penalty, step))); }
+                    Occupied(entry) => { // presumably the entry is another competing beam
+                        let (other_score, other_beam) = entry.into_mut();
+
+                        if score.cmp(other_score) == Ordering::Less { // if this beam is stronger, insert
+                            *other_score = score;
+                            *other_beam = Candidate::Seed(key_start, penalty, step);
+                        }
+                    }
+                }
+            }
+
+            let heap: BinaryHeap<_> = recomb_table
+                .into_values()
+                .collect();
+
+            heaps.push(heap);
+        }
+
+        let best_scores: Vec<Option<f32>> = heaps
+            .iter()
+            .map(|heap| heap.iter().map(|(score, _)| score).min().map(Fraction::to_f32))
+            .collect();
+        let budgets = cfg.beam_budgets(&best_scores);
+
+        for (((_uuid, _, _, beams), mut heap), budget) in self.song_beams.iter_mut().zip(heaps).zip(budgets) {
+            // trim heap size, removing high scoring elements until size is OK.
+            if heap.len() > budget { self.saturated = true; }
+
+            #[cfg(feature = "trace")]
+            let pruned = heap.len().saturating_sub(budget);
+
+            while heap.len() > budget { heap.pop(); }
+            self.peak_beams = self.peak_beams.max(heap.len());
+
+            #[cfg(feature = "trace")]
+            if let Some(tracer) = &mut self.tracer {
+                let uuid = **_uuid;
+                let seeded = heap.iter().filter(|(_, cand)| matches!(cand, Candidate::Seed(..))).count();
+                if seeded > 0 { tracer(TraceEvent::Seeded { uuid, frame, count: seeded }); }
+                if pruned > 0 { tracer(TraceEvent::Pruned { uuid, frame, count: pruned }); }
+            }
+
+            // convert hashmap into maxheap
+            *beams = heap
+                .drain()
+                .map(|(score, cand)| (score, cand.to_beam(frame)))
+                .collect();
+        }
+        
+        self.head += 1;
+    }
+
+    /// Current number of live beams per song, without consuming the query.
+    pub fn song_beam_counts(&self) -> Vec<(Uuid, usize)> {
+        self.song_beams
+            .iter()
+            .map(|(uuid, _, _, beams)| (**uuid, beams.len()))
+            .collect()
+    }
+
+    /// Whether `search_max_query_frames` was reached without `search_decay`, so
+    /// further updates are ignored and the query should be finalized.
+    pub fn is_full(&self) -> bool {
+        let cfg = &self.database.cfg;
+        cfg.search_max_query_frames.is_some_and(|cap| self.head >= cap) && !cfg.decays()
+    }
+
+    pub fn stats(&self) -> QueryStats {
+        QueryStats {
+            frames: self.head,
+            peak_beams: self.peak_beams,
+            beam_saturated: self.saturated,
+            scanned_frames: self.scanned_frames,
+        }
+    }
+
+    pub fn finalize(self) -> Vec<QueryResult> {
+        self.finalize_iter().collect()
+    }
+
+    /// Results gathered per song, each group best-first and the groups ordered by
+    /// their best member.
+    pub fn finalize_grouped(self) -> Vec<(Uuid, Vec<QueryResult>)> {
+        let mut groups: Vec<(Uuid, Vec<QueryResult>)> = Vec::new();
+        let mut index: HashMap<Uuid, usize> = HashMap::new();
+
+        for result in self.finalize_iter() {
+            let i = *index.entry(result.uuid).or_insert_with(|| {
+                groups.push((result.uuid, Vec::new()));
+                groups.len() - 1
+            });
+            groups[i].1.push(result);
+        }
+
+        groups
+    }
+
+    /// The best result for each region of the query, in the order the regions occur.
+    /// A result's region runs `key_end - key_start` frames from its `query_start`;
+    /// results starting inside a better result's region are dropped.
+    pub fn finalize_by_query_time(self) -> Vec<QueryResult> {
+        let mut kept: Vec<QueryResult> = Vec::new();
+
+        for result in self.finalize_iter() {
+            let covered = kept.iter().any(|other| {
+                (other.query_start..=other.query_start + (other.key_end - other.key_start)).contains(&result.query_start)
+            });
+            if !covered { kept.push(result); }
+        }
+
+        kept.sort_by_key(|result| result.query_start);
+        kept
+    }
+
+    /// Yields results best-first, popping from the heap lazily so callers that only
+    /// want the top few don't pay to sort every beam.
+    pub fn finalize_iter(self) -> impl Iterator<Item = QueryResult> + 'a {
+        let breakdown = self.database.cfg.search_score_breakdown;
+        let scale = self.database.cfg.score_scale();
+        let context = self.database.cfg.search_context_frames;
+        let (window_stride, frame_offset) = (self.database.cfg.window_stride, self.database.cfg.frame_offset);
+        let database = self.database;
+        let frames = &self.database.frames;
+        #[cfg(feature = "trace")]
+        let mut tracer = self.tracer;
+
+        // get minheap
+        let mut heap: BinaryHeap<Reverse<(Fraction, &Uuid, Beam, usize)>> = self.song_beams
+            .into_iter()
+            .flat_map(|(uuid, song, _, beams)| beams
+                .into_iter()
+                .map(move |(score, beam)| Reverse((score, uuid, beam, song.len()))))
+            .collect();
+
+        /*
+
+
+        let mut beams = self.song_beams.into_sorted_vec();
+        beams.reverse();
+
+        let mut results = Vec::new();
+
+        // we need to merge contiguous beams
+
+        while let Some(beam) = beams.pop() {
+            // invalidate overlapping beams
+            beams.retain(|other_beam| {
+                let intersction_start = beam.start().max(other_beam.start());
+                let intersection_end = beam.end().min(other_beam.end());
+                let intersection = (intersection_end as isize - intersction_start as isize).max(0);
+                let union = other_beam.end() - other_beam.start();
+
+                let overlap = if union != 0 { intersection as f32 / union as f32 } else { 0.0 };
+                
+                overlap <= self.database.cfg.search_nonmax_overlap.clamp(0.0, 1.0)
+            });
+
+            results.push(beam.into());
+        }
+
+        results*/
+
+        // beams pop best-first, so each song's first few are its best
+        let per_song = self.database.cfg.search_max_results_per_song.unwrap_or(usize::MAX);
+        let mut taken: HashMap<&Uuid, usize> = HashMap::new();
+
+        let results = std::iter::from_fn(move || heap.pop())
+            .filter(move |Reverse((_, uuid, _, _))| {
+                let count = taken.entry(*uuid).or_default();
+                *count += 1;
+                *count <= per_song
+            })
+            .map(move |Reverse((score, uuid, beam, song_len))| {
+                database.touch(uuid);
+                let frame = |i: usize| frames.get(uuid).map_or(i, |frames| frames[i]);
+                let (key_start, key_end) = (frame(beam.key_start()), frame(beam.key_end()));
+
+                QueryResult {
+                    uuid: *uuid,
+                    score: score.to_f32(),
+                    key_start,
+                    key_end,
+                    query_start: beam.query_start,
+                    // in source frames, so gaps in the frame map don't widen it
+                    context_start: key_start.saturating_sub(context).max(frame(0)),
+                    context_end: (key_end + context).min(frame(song_len - 1)),
+                    breakdown: breakdown.then(|| ScoreBreakdown {
+                        raw_distance_sum: score.numerator().saturating_sub(beam.penalty) / scale,
+                        penalty: beam.penalty / scale,
+                        frames: beam.path.len() as u32,
+                    }),
+                    window_stride,
+                    frame_offset,
+                }
+            });
+
+        #[cfg(feature = "trace")]
+        let results = results.inspect(move |result| {
+            if let Some(tracer) = &mut tracer {
+                tracer(TraceEvent::Candidate { uuid: result.uuid, score: result.score, key_start: result.key_start, key_end: result.key_end });
+            }
+        });
+
+        results
+    }
+}
+
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{config::SessionConfiguration, fingerprint::FeatureExtractor};
+    use crate::{config::SessionConfiguration, fingerprint::FeatureExtractor, tests::{synth_catalogue, synth_melody, synthesize_tone, MELODY}};
     use std::path::Path;
     use std::time::Instant;
     use url::Url;
@@ -359,6 +1601,13 @@ mod tests {
         let mut database = Database {
             cfg: db_cfg,
             database: HashMap::new(),
+            priors: HashMap::new(),
+            frames: HashMap::new(),
+            rarity: HashMap::new(),
+            capacity: None,
+            last_used: HashMap::new(),
+            clock: AtomicU64::new(0),
+            coarse: None,
         };
 
         // Load all WAV files from the key directory
@@ -421,6 +1670,1350 @@ mod tests {
         assert!(!results.is_empty(), "Should find at least one match");
     }
 
+    #[test]
+    fn test_search_synthesized_tones() {
+        let config = SessionConfiguration::default();
+        let (extractor_cfg, db_cfg) = config.into_child_configs();
+        let extractor: FeatureExtractor = extractor_cfg.into();
+        let mut database: Database = db_cfg.into();
+
+        // one steady tone per pitch class from A3 up
+        let tones: Vec<Vec<f32>> = (0..12)
+            .map(|semitone| synthesize_tone(220.0 * 2f32.powf(semitone as f32 / 12.0), 4.0, config.sample_rate))
+            .collect();
+        for (i, tone) in tones.iter().enumerate() {
+            database.insert(Uuid::from_u128(i as u128), extractor.features(tone));
+        }
+
+        for i in [0, 4, 7, 11] {
+            let clip = &tones[i][config.sample_rate..config.sample_rate * 3];
+            let mut q = database.new_query();
+            for feature in extractor.features(clip) { q.update(feature); }
+
+            let best = q.finalize().into_iter().next().unwrap();
+            assert_eq!(best.uuid, Uuid::from_u128(i as u128));
+        }
+    }
+
+    #[test]
+    fn test_similarity_matrix() {
+        let config = SessionConfiguration::default();
+        let (extractor_cfg, db_cfg) = config.into_child_configs();
+        let extractor: FeatureExtractor = extractor_cfg.into();
+
+        let clips = vec![
+            synth_melody(&MELODY[..6], 0.5, config.sample_rate),
+            synth_melody(&[293.7, 246.9, 440.0, 261.6, 392.0, 329.6], 0.5, config.sample_rate),
+            synth_melody(&[523.3, 493.9, 440.0, 392.0, 349.2, 329.6], 0.5, config.sample_rate),
+        ];
+
+        let matrix = similarity_matrix(&extractor, &db_cfg, &clips);
+        let n = clips.len();
+        assert_eq!(matrix.len(), n * n);
+
+        for i in 0..n {
+            let row = &matrix[i * n..(i + 1) * n];
+            assert!((0..n).filter(|&j| j != i).all(|j| row[i] < row[j]), "row {i}: {row:?}");
+        }
+    }
+
+    #[test]
+    fn test_align_to_own_song() {
+        let config = SessionConfiguration::default();
+        let (extractor_cfg, db_cfg) = config.into_child_configs();
+        let extractor: FeatureExtractor = extractor_cfg.into();
+        let mut database: Database = db_cfg.into();
+
+        let audio = synth_melody(&MELODY, 0.5, config.sample_rate);
+        let key = extractor.features(&audio);
+
+        let uuid = Uuid::new_v4();
+        database.insert(uuid, key.clone());
+        database.insert(Uuid::new_v4(), extractor.features(&synth_melody(&[110.0, 155.6], 2.0, config.sample_rate)));
+
+        let clip = &key[4..14];
+        let result = database.align_to(&uuid, clip).expect("own song should align");
+
+        assert_eq!(result.uuid, uuid);
+        assert_eq!(result.query_start, 0);
+        assert_eq!(result.key_end - result.key_start + 1, clip.len(), "match should be diagonal");
+
+        let distance: u32 = clip.iter()
+            .zip(&key[result.key_start..=result.key_end])
+            .map(|(a, b)| a.distance(b))
+            .sum();
+        assert_eq!(distance, 0);
+
+        assert!(database.align_to(&Uuid::new_v4(), clip).is_none());
+    }
+
+    #[test]
+    fn test_beam_saturation() {
+        let audio = synth_melody(&MELODY[..6], 0.5, SessionConfiguration::default().sample_rate);
+
+        let run = |beam_count: usize| {
+            let config = SessionConfiguration { search_beam_count: beam_count, ..Default::default() };
+            let (extractor_cfg, db_cfg) = config.into_child_configs();
+            let extractor: FeatureExtractor = extractor_cfg.into();
+            let mut database: Database = db_cfg.into();
+
+            let features = extractor.features(&audio);
+            database.insert(Uuid::new_v4(), features.clone());
+
+            let mut query = database.new_query();
+            for feature in &features[..8] { query.update(*feature); }
+            query.stats()
+        };
+
+        let tiny = run(2);
+        assert!(tiny.beam_saturated);
+        assert_eq!(tiny.peak_beams, 2);
+        assert_eq!(tiny.frames, 8);
+
+        let large = run(10_000);
+        assert!(!large.beam_saturated);
+        assert!(large.peak_beams < 10_000);
+    }
+
+    #[test]
+    fn test_log_length_penalty() {
+        let sample_rate = SessionConfiguration::default().sample_rate;
+        let jingle = synth_melody(&[261.6, 392.0, 329.6], 0.5, sample_rate);
+        let mut track = synth_melody(&[110.0, 146.8, 196.0, 220.0, 174.6, 130.8], 4.0, sample_rate);
+        track.extend_from_slice(&jingle);
+
+        let run = |mode: PenaltyMode| {
+            let config = SessionConfiguration { search_penalty_mode: mode, ..Default::default() };
+            let (extractor_cfg, db_cfg) = config.into_child_configs();
+            let extractor: FeatureExtractor = extractor_cfg.into();
+            let mut database: Database = db_cfg.into();
+
+            let short_key = extractor.features(&jingle);
+            let long_key = extractor.features(&track);
+            let (short, long) = (Uuid::new_v4(), Uuid::new_v4());
+
+            let penalties = (database.cfg.seed_penalty(short_key.len()), database.cfg.seed_penalty(long_key.len()));
+            database.insert(short, short_key.clone());
+            database.insert(long, long_key);
+
+            let short_score = database.align_to(&short, &short_key).unwrap().score;
+            let long_score = database.align_to(&long, &short_key).unwrap().score;
+            (penalties, short_score, long_score)
+        };
+
+        // a fixed penalty treats both keys alike regardless of length
+        let ((short_penalty, long_penalty), _, _) = run(PenaltyMode::Fixed);
+        assert_eq!(short_penalty, long_penalty);
+
+        // relative penalties charge the long key for its many extra seed positions
+        let ((short_penalty, long_penalty), short_score, long_score) = run(PenaltyMode::LogLength);
+        assert!(short_penalty < long_penalty);
+        assert!(long_penalty < SessionConfiguration::default().search_score_penalty);
+        assert!(short_score < long_score);
+    }
+
+    #[test]
+    fn test_database_stats() {
+        let (_, db_cfg) = SessionConfiguration::default().into_child_configs();
+        let mut database: Database = db_cfg.into();
+
+        let empty = database.stats();
+        assert_eq!((empty.song_count, empty.total_features, empty.longest_song, empty.shortest_song), (0, 0, 0, 0));
+
+        database.insert(Uuid::new_v4(), vec![Feature::from(1); 10]);
+        database.insert(Uuid::new_v4(), vec![Feature::from(2); 25]);
+
+        let stats = database.stats();
+        assert_eq!(stats.song_count, 2);
+        assert_eq!(stats.total_features, 35);
+        assert_eq!(stats.longest_song, 25);
+        assert_eq!(stats.shortest_song, 10);
+        assert!(stats.memory_bytes >= 35 * 8);
+    }
+
+    #[test]
+    fn test_score_breakdown() {
+        let config = SessionConfiguration { search_score_breakdown: true, ..Default::default() };
+        let (extractor_cfg, db_cfg) = config.into_child_configs();
+        let extractor: FeatureExtractor = extractor_cfg.into();
+        let mut database: Database = db_cfg.into();
+
+        let key = extractor.features(&synth_melody(&MELODY, 0.75, config.sample_rate));
+        let uuid = Uuid::new_v4();
+        database.insert(uuid, key.clone());
+
+        // penalty's share of the final score: penalty / (frames + length penalty)
+        let penalty_share = |result: &QueryResult| {
+            let b = result.breakdown.expect("breakdown enabled");
+            let d = b.frames + config.search_length_penalty;
+            assert_eq!((b.raw_distance_sum + b.penalty) as f32 / d as f32, result.score);
+            b.penalty as f32 / d as f32
+        };
+
+        let long = database.align_to(&uuid, &key[2..30]).unwrap();
+        let seed = database.align_to(&uuid, &key[2..3]).unwrap();
+
+        assert_eq!(long.breakdown.unwrap().frames, 28);
+        assert_eq!(seed.breakdown.unwrap().frames, 1);
+        assert!(penalty_share(&long) < 0.2 * penalty_share(&seed));
+
+        let (_, plain_cfg) = SessionConfiguration::default().into_child_configs();
+        let mut plain: Database = plain_cfg.into();
+        plain.insert(uuid, key.clone());
+        assert!(plain.align_to(&uuid, &key[2..30]).unwrap().breakdown.is_none());
+    }
+
+    #[test]
+    fn test_search_decay() {
+        let sample_rate = SessionConfiguration::default().sample_rate;
+        let song_a = synth_melody(&MELODY[..6], 0.75, sample_rate);
+        let song_b = synth_melody(&[110.0, 146.8, 196.0, 164.8, 220.0, 130.8, 174.6, 123.5, 155.6, 185.0], 1.0, sample_rate);
+
+        let best_match = |decay: f32| {
+            let config = SessionConfiguration { search_decay: decay, ..Default::default() };
+            let (extractor_cfg, db_cfg) = config.into_child_configs();
+            let extractor: FeatureExtractor = extractor_cfg.into();
+            let mut database: Database = db_cfg.into();
+
+            let (key_a, key_b) = (extractor.features(&song_a), extractor.features(&song_b));
+            let (a, b) = (Uuid::from_u128(1), Uuid::from_u128(2));
+
+            // a long stretch of b, after which the stream drifts into a
+            let query: Vec<Feature> = key_b[..20].iter().chain(&key_a[4..12]).copied().collect();
+
+            database.insert(a, key_a);
+            database.insert(b, key_b);
+
+            let mut q = database.new_query();
+            for feature in query { q.update(feature); }
+            q.finalize()[0].uuid
+        };
+
+        assert_eq!(best_match(1.0), Uuid::from_u128(2));
+        assert_eq!(best_match(0.7), Uuid::from_u128(1));
+    }
+
+    #[test]
+    fn test_find_duplicates() {
+        let config = SessionConfiguration::default();
+        let (extractor_cfg, db_cfg) = config.into_child_configs();
+        let extractor: FeatureExtractor = extractor_cfg.into();
+        let mut database: Database = db_cfg.into();
+
+        let song = extractor.features(&synth_melody(&MELODY[..6], 0.75, config.sample_rate));
+        let other = extractor.features(&synth_melody(&[110.0, 155.6, 207.7, 123.5], 1.0, config.sample_rate));
+
+        let (a, b, c) = (Uuid::from_u128(1), Uuid::from_u128(2), Uuid::from_u128(3));
+        database.insert(a, song.clone());
+        database.insert(b, song);
+        database.insert(c, other);
+
+        let duplicates = database.find_duplicates(0.9);
+        assert_eq!(duplicates.len(), 1);
+        assert_eq!((duplicates[0].0, duplicates[0].1), (a, b));
+        assert_eq!(duplicates[0].2, 1.0);
+    }
+
+    #[test]
+    fn test_search_window_back() {
+        let sample_rate = SessionConfiguration::default().sample_rate;
+        let audio = synth_melody(&[261.6, 329.6, 392.0, 523.3, 440.0, 349.2, 293.7, 246.9, 196.0, 220.0], 0.5, sample_rate);
+
+        let score = |back: usize| {
+            let config = SessionConfiguration { search_window_back: back, ..Default::default() };
+            let (extractor_cfg, db_cfg) = config.into_child_configs();
+            let extractor: FeatureExtractor = extractor_cfg.into();
+            let mut database: Database = db_cfg.into();
+
+            let key = extractor.features(&audio);
+
+            // the performer lingers on a couple of frames
+            let mut query = key[4..24].to_vec();
+            query.insert(12, key[11]);
+            query.insert(6, key[9]);
+            query.insert(6, key[9]);
+
+            let uuid = Uuid::new_v4();
+            database.insert(uuid, key);
+            database.align_to(&uuid, &query).unwrap().score
+        };
+
+        let strict = score(0);
+        let relaxed = score(1);
+        assert!(relaxed < strict, "relaxed {relaxed} strict {strict}");
+        assert_eq!(score(3), relaxed, "paths stay monotonic however far back we look");
+    }
+
+    #[test]
+    fn test_search_jump_penalty() {
+        let notes: Vec<Feature> = (0..6).map(|i| Feature::from(0xffu64 << (i * 8))).collect();
+        let rest = Feature::from(u64::MAX);
+
+        // the same notes, held one frame each or separated by rests
+        let smooth = notes.clone();
+        let jumpy: Vec<Feature> = notes.iter().flat_map(|&note| [note, rest]).collect();
+
+        let score = |jump_penalty: u32, key: &[Feature]| {
+            let config = SessionConfiguration { search_jump_penalty: jump_penalty, ..Default::default() };
+            let database: Database = config.into_child_configs().1.into();
+            database.align_unregistered(key, &notes).unwrap().score
+        };
+
+        assert_eq!(score(0, &smooth), score(0, &jumpy));
+        assert!(score(4, &smooth) < score(4, &jumpy));
+        assert_eq!(score(4, &smooth), score(0, &smooth));
+    }
+
+    #[test]
+    fn test_song_beam_counts() {
+        let config = SessionConfiguration { search_beam_count: 5, ..Default::default() };
+        let (extractor_cfg, db_cfg) = config.into_child_configs();
+        let extractor: FeatureExtractor = extractor_cfg.into();
+        let mut database: Database = db_cfg.into();
+
+        let key = extractor.features(&synth_melody(&MELODY[..6], 0.75, config.sample_rate));
+        let (a, b) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        database.insert(a, key.clone());
+        database.insert(b, vec![Feature::from(0b1011); 3]);
+
+        let mut query = database.new_query();
+        assert!(query.song_beam_counts().iter().all(|&(_, count)| count == 0));
+
+        for feature in &key[..6] { query.update(*feature); }
+
+        let mut counts = query.song_beam_counts();
+        counts.sort_unstable();
+        assert_eq!(counts.len(), 2);
+        assert_eq!(counts[0], (a, 5));
+        assert_eq!(counts[1], (b, 3));
+    }
+
+    #[test]
+    fn test_insert_compressed() {
+        let config = SessionConfiguration::default();
+        let (extractor_cfg, db_cfg) = config.into_child_configs();
+        let extractor: FeatureExtractor = extractor_cfg.into();
+        let (_, compressed_cfg) = config.into_child_configs();
+        let mut plain: Database = db_cfg.into();
+        let mut compressed: Database = compressed_cfg.into();
+
+        let songs = [
+            synth_melody(&MELODY[..6], 1.5, config.sample_rate),
+            synth_melody(&[110.0, 155.6, 207.7, 123.5], 2.0, config.sample_rate),
+        ];
+
+        for (i, song) in songs.iter().enumerate() {
+            let features = extractor.features(song);
+            compressed.insert_compressed(Uuid::from_u128(i as u128), &features);
+            plain.insert(Uuid::from_u128(i as u128), features);
+        }
+
+        let (plain_stats, compressed_stats) = (plain.stats(), compressed.stats());
+        assert_eq!(plain_stats.total_features, compressed_stats.total_features);
+        assert!(compressed_stats.memory_bytes < plain_stats.memory_bytes,
+            "compressed {} vs plain {}", compressed_stats.memory_bytes, plain_stats.memory_bytes);
+
+        let query = extractor.features(&songs[0][config.sample_rate..config.sample_rate * 5]);
+        let search = |database: &Database| {
+            let mut q = database.new_query();
+            for feature in &query { q.update(*feature); }
+            q.finalize()
+        };
+
+        // equal-score beams may recombine in either order, so compare scores rather than paths
+        let (plain_results, compressed_results) = (search(&plain), search(&compressed));
+        let (p, c) = (&plain_results[0], &compressed_results[0]);
+        assert_eq!((p.uuid, p.score, p.key_end, p.query_start), (c.uuid, c.score, c.key_end, c.query_start));
+
+        let scores = |results: &[QueryResult]| {
+            let mut scores: Vec<(Uuid, u32)> = results.iter().map(|r| (r.uuid, r.score.to_bits())).collect();
+            scores.sort_unstable();
+            scores
+        };
+        assert_eq!(scores(&plain_results), scores(&compressed_results));
+    }
+
+    #[test]
+    fn test_finalize_iter() {
+        let config = SessionConfiguration::default();
+        let (extractor_cfg, db_cfg) = config.into_child_configs();
+        let extractor: FeatureExtractor = extractor_cfg.into();
+        let mut database: Database = db_cfg.into();
+
+        let key = extractor.features(&synth_melody(&MELODY[..6], 0.75, config.sample_rate));
+        database.insert(Uuid::from_u128(1), key.clone());
+        database.insert(Uuid::from_u128(2), key[10..].to_vec());
+
+        // recombination ties depend on hash order, so compare one query's two outputs
+        let mut query = database.new_query();
+        for feature in &key[3..15] { query.update(*feature); }
+
+        let by_score = |results: &[QueryResult]| -> Vec<u32> { results.iter().map(|r| r.score.to_bits()).collect() };
+
+        let lazy: Vec<QueryResult> = query.finalize_iter().collect();
+        assert!(lazy.windows(2).all(|w| w[0].score <= w[1].score));
+
+        let mut query = database.new_query();
+        for feature in &key[3..15] { query.update(*feature); }
+        let eager = query.finalize();
+
+        assert_eq!(by_score(&lazy), by_score(&eager));
+    }
+
+    #[test]
+    fn test_new_query_ranged() {
+        let config = SessionConfiguration::default();
+        let (extractor_cfg, db_cfg) = config.into_child_configs();
+        let extractor: FeatureExtractor = extractor_cfg.into();
+        let mut database: Database = db_cfg.into();
+
+        let verse = extractor.features(&synth_melody(&MELODY[..6], 0.75, config.sample_rate));
+        let chorus = extractor.features(&synth_melody(&[110.0, 155.6, 207.7, 123.5], 1.0, config.sample_rate));
+        let key: Vec<Feature> = verse.iter().chain(&chorus).copied().collect();
+
+        let (song, other) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        database.insert(song, key);
+        database.insert(other, verse.clone());
+
+        let query = &verse[4..20];
+        let search = |mut q: Query| {
+            for feature in query { q.update(*feature); }
+            q.finalize()
+        };
+
+        let unranged = search(database.new_query());
+        assert!(unranged[0].key_end < verse.len(), "the verse is the best match");
+
+        let window = (verse.len() + 2, verse.len() + 20);
+        let ranged = search(database.new_query_ranged(HashMap::from([(song, window)])));
+        assert!(!ranged.is_empty());
+        assert!(ranged.iter().all(|r| r.uuid == song && r.key_start >= window.0 && r.key_end < window.1));
+        assert!(ranged[0].score > unranged[0].score);
+    }
+
+    #[test]
+    fn test_search_max_key_scan() {
+        let sample_rate = SessionConfiguration::default().sample_rate;
+        let noise = crate::tests::noise(sample_rate * 60, 7);
+        let melody = synth_melody(&MELODY, 0.5, sample_rate);
+
+        let search = |search_max_key_scan: Option<usize>, search_beam_count: usize| {
+            let config = SessionConfiguration { search_max_key_scan, search_beam_count, ..Default::default() };
+            let (extractor_cfg, db_cfg) = config.into_child_configs();
+            let extractor: FeatureExtractor = extractor_cfg.into();
+            let mut database: Database = db_cfg.into();
+
+            // a long track with the melody buried in the middle
+            let filler = extractor.features(&noise);
+            let tune = extractor.features(&melody);
+            let key: Vec<Feature> = [&filler, &filler, &tune, &filler, &filler].into_iter().flatten().copied().collect();
+            database.insert(Uuid::from_u128(1), key);
+
+            let mut q = database.new_query();
+            for feature in &tune[2..18] { q.update(*feature); }
+            let scanned = q.stats().scanned_frames;
+
+            (q.finalize().into_iter().next().unwrap(), scanned, filler.len() * 2)
+        };
+
+        // few enough beams that their windows leave most of the key unscanned
+        let (full, full_scanned, offset) = search(None, 32);
+        let (capped, capped_scanned, _) = search(Some(16), 32);
+
+        assert_eq!(full.key_start, offset + 2);
+        assert_eq!(capped.key_start, full.key_start);
+        assert_eq!(capped.key_end, full.key_end);
+        assert_eq!(capped.score, full.score);
+        assert!(capped_scanned < full_scanned / 2, "{capped_scanned} of {full_scanned}");
+    }
+
+    #[test]
+    fn test_song_distances_spans() {
+        let features: Vec<Feature> = crate::tests::random_u64s(5).take(300).map(Feature::from).collect();
+        let plain = Song::Plain(features.clone());
+        let compressed = Song::Compressed(CompressedSong::from(features.as_slice()));
+        let query = Feature::from(0x0123_4567_89ab_cdef);
+
+        for spans in [vec![0..150, 150..300], vec![10..40, 40..41, 120..200, 299..300], vec![5..5, 250..260]] {
+            let expected: Vec<u32> = spans.iter().flat_map(|span| features[span.clone()].iter().map(|key| query.distance(key))).collect();
+            assert_eq!(plain.distances(&query, &spans), expected);
+            assert_eq!(compressed.distances(&query, &spans), expected);
+        }
+    }
+
+    #[test]
+    fn test_contrast_seeding() {
+        let config = SessionConfiguration { search_score_breakdown: true, search_contrast_seeding: true, ..Default::default() };
+        let (extractor_cfg, db_cfg) = config.into_child_configs();
+        let extractor: FeatureExtractor = extractor_cfg.into();
+        let mut database: Database = db_cfg.into();
+
+        let key = extractor.features(&synth_melody(&[261.6, 329.6, 392.0, 523.3], 0.75, config.sample_rate));
+        database.insert(Uuid::from_u128(1), key.clone());
+
+        // the same single frame seeded with different contrasts
+        let seed_penalty = |contrast: f32| {
+            let mut q = database.new_query();
+            q.update_with_contrast(key[3], 0, 1.0, contrast);
+            q.finalize()[0].breakdown.unwrap().penalty
+        };
+
+        let base = config.search_score_penalty;
+        assert_eq!(seed_penalty(0.5), base);
+        assert!(seed_penalty(0.9) < base && base < seed_penalty(0.1));
+
+        // without the option contrast is ignored
+        let (_, plain_cfg) = SessionConfiguration { search_score_breakdown: true, ..Default::default() }.into_child_configs();
+        let mut plain: Database = plain_cfg.into();
+        plain.insert(Uuid::from_u128(1), key.clone());
+        let mut q = plain.new_query();
+        q.update_with_contrast(key[3], 0, 1.0, 0.9);
+        assert_eq!(q.finalize()[0].breakdown.unwrap().penalty, base);
+    }
+
+    #[test]
+    fn test_idf_seeding() {
+        let config = SessionConfiguration { search_score_breakdown: true, search_idf_seeding: true, ..Default::default() };
+        let (_, db_cfg) = config.into_child_configs();
+        let mut database: Database = db_cfg.into();
+
+        // a sustained tone throughout, broken by one distinct frame
+        let (common, rare) = (Feature::from(0b1111), Feature::from(0b1111 << 20));
+        let mut key = vec![common; 64];
+        key[40] = rare;
+        database.insert(Uuid::from_u128(1), key.clone());
+
+        // the same exact match seeded on one key frame or the other
+        let seeded = |frame: usize, feature: Feature| {
+            let mut q = database.new_query_ranged(HashMap::from([(Uuid::from_u128(1), (frame, frame + 1))]));
+            q.update(feature);
+            let best = q.finalize().into_iter().next().unwrap();
+            (best.breakdown.unwrap().penalty, best.score)
+        };
+
+        let (common_penalty, common_score) = seeded(10, common);
+        let (rare_penalty, rare_score) = seeded(40, rare);
+        let base = config.search_score_penalty;
+        assert!(rare_penalty < base && base < common_penalty);
+        assert!(rare_score < common_score);
+
+        // rarity is worked out once per song as it's stored, not per query
+        assert_eq!(database.rarity[&Uuid::from_u128(1)], rarity(&key));
+        let mut stream = database.insert_streaming(Uuid::from_u128(2));
+        stream.push(&key);
+        stream.finish();
+        assert_eq!(database.rarity[&Uuid::from_u128(2)], database.rarity[&Uuid::from_u128(1)]);
+        database.remove(&Uuid::from_u128(1));
+        assert!(!database.rarity.contains_key(&Uuid::from_u128(1)));
+    }
+
+    #[test]
+    fn test_recomb_key() {
+        // a phrase heard twice in a row: the first match stalls on the key's last frame
+        // just as the second arrives there
+        let key: Vec<Feature> = (0..16).map(|i| Feature::from(0b111 << (3 * i))).collect();
+        let query: Vec<Feature> = key.iter().chain(&key).copied().collect();
+
+        let ends_on_last = |search_recomb_by| {
+            let config = SessionConfiguration { search_recomb_by, ..Default::default() };
+            let (_, db_cfg) = config.into_child_configs();
+            let mut database: Database = db_cfg.into();
+            database.insert(Uuid::from_u128(1), key.clone());
+
+            let mut q = database.new_query();
+            for &feature in &query { q.update(feature); }
+            let mut starts: Vec<usize> = q.finalize().into_iter().filter(|r| r.key_end == 15).map(|r| r.query_start).collect();
+            starts.sort();
+            starts
+        };
+
+        // recombining on the key frame alone leaves a single beam holding the slot
+        assert_eq!(ends_on_last(RecombKey::KeyEnd).len(), 1);
+
+        let bucketed = ends_on_last(RecombKey::KeyEndQueryBucket);
+        assert!(bucketed.contains(&0) && bucketed.contains(&16), "{bucketed:?}");
+    }
+
+    #[test]
+    fn test_max_results_per_song() {
+        // one phrase appearing three times in a song, split by unrelated frames
+        let phrase: Vec<Feature> = (0..12).map(|i| Feature::from(0b111 << (3 * i))).collect();
+        let filler = vec![Feature::from(0b1 << 63); 20];
+        let song: Vec<Feature> = [&phrase, &filler, &phrase, &filler, &phrase].into_iter().flatten().copied().collect();
+
+        let results = |search_max_results_per_song| {
+            let config = SessionConfiguration { search_max_results_per_song, ..Default::default() };
+            let (_, db_cfg) = config.into_child_configs();
+            let mut database: Database = db_cfg.into();
+            database.insert(Uuid::from_u128(1), song.clone());
+
+            let mut q = database.new_query();
+            for &feature in &phrase { q.update(feature); }
+            q.finalize()
+        };
+
+        let uncapped = results(None);
+        let perfect: HashSet<usize> = uncapped.iter().filter(|r| r.score == uncapped[0].score).map(|r| r.key_start).collect();
+        assert_eq!(perfect, HashSet::from([0, 32, 64]));
+
+        let capped = results(Some(1));
+        assert_eq!(capped.len(), 1);
+        assert_eq!(capped[0].score, uncapped[0].score);
+        assert!(perfect.contains(&capped[0].key_start));
+    }
+
+    #[test]
+    fn test_score_repr() {
+        // a long run of alternating distances settles on their mean without drifting
+        let mut rational = Fraction::new(0, 1, ScoreRepr::Rational);
+        let mut mean = Fraction::new(0, 1, ScoreRepr::RunningMean);
+        for i in 0..1_000_000 {
+            let distance = if i % 2 == 0 { 5 } else { 7 };
+            rational.add(distance, 1);
+            mean.add(distance, 1);
+        }
+        assert!((mean.to_f32() - rational.to_f32()).abs() < 1e-3, "{} vs {}", mean.to_f32(), rational.to_f32());
+        assert!(mean.numerator().abs_diff(rational.numerator()) <= rational.numerator() / 1_000_000);
+
+        // and a long query ranks the songs the same either way
+        let sample_rate = SessionConfiguration::default().sample_rate;
+        let notes = [261.6, 329.6, 392.0, 523.3, 440.0, 349.2, 293.7, 246.9, 220.0, 293.7, 392.0, 493.9];
+        let songs: Vec<Vec<f32>> = (0..4)
+            .map(|shift| {
+                let melody: Vec<f32> = (0..48).map(|i| notes[(i * (shift + 1) + shift) % notes.len()]).collect();
+                synth_melody(&melody, 0.5, sample_rate)
+            })
+            .collect();
+
+        let ranked = |search_score_repr| {
+            let config = SessionConfiguration { search_score_repr, ..Default::default() };
+            let (extractor_cfg, db_cfg) = config.into_child_configs();
+            let extractor: FeatureExtractor = extractor_cfg.into();
+            let mut database: Database = db_cfg.into();
+            for (i, song) in songs.iter().enumerate() { database.insert(Uuid::from_u128(i as u128 + 1), extractor.features(song)); }
+
+            let mut q = database.new_query();
+            for feature in extractor.features(&songs[2]) { q.update(feature); }
+            let mut best: Vec<(Uuid, f32)> = Vec::new();
+            for result in q.finalize() {
+                if !best.iter().any(|(uuid, _)| *uuid == result.uuid) { best.push((result.uuid, result.score)); }
+            }
+            best
+        };
+
+        let (rational, mean) = (ranked(ScoreRepr::Rational), ranked(ScoreRepr::RunningMean));
+        assert_eq!(rational[0].0, Uuid::from_u128(3));
+        assert_eq!(rational.iter().map(|r| r.0).collect::<Vec<_>>(), mean.iter().map(|r| r.0).collect::<Vec<_>>());
+        for ((_, a), (_, b)) in rational.iter().zip(&mean) {
+            assert!((a - b).abs() <= 1e-3 * a.max(1.0), "{a} vs {b}");
+        }
+    }
+
+    #[test]
+    fn test_search_branch() {
+        let sample_rate = SessionConfiguration::default().sample_rate;
+        let notes = [261.6, 329.6, 392.0, 523.3, 440.0, 349.2, 293.7, 246.9, 220.0, 293.7, 392.0, 493.9, 329.6, 261.6, 440.0, 349.2];
+        let note_secs = 0.4;
+        // the melody at source time `t`, with each note's phase starting from zero
+        let melody_at = |t: f32| {
+            let note = ((t / note_secs) as usize).min(notes.len() - 1);
+            (std::f32::consts::TAU * notes[note] * (t - note as f32 * note_secs)).sin() * 0.5
+        };
+
+        let key: Vec<f32> = (0..(notes.len() as f32 * note_secs * sample_rate as f32) as usize)
+            .map(|i| melody_at(i as f32 / sample_rate as f32))
+            .collect();
+        // a performance that speeds up steadily, from the score's tempo to 1.2x
+        let accel = 0.2 / 8.0;
+        let query: Vec<f32> = (0..sample_rate * 4)
+            .map(|i| { let t = i as f32 / sample_rate as f32; melody_at(t + accel * t * t) })
+            .collect();
+
+        let search = |branch: bool| {
+            let config = SessionConfiguration { search_branch: branch, ..Default::default() };
+            let (extractor_cfg, db_cfg) = config.into_child_configs();
+            let extractor: FeatureExtractor = extractor_cfg.into();
+            let mut database: Database = db_cfg.into();
+            database.insert(Uuid::from_u128(1), extractor.features(&key));
+
+            database.align_to(&Uuid::from_u128(1), &extractor.features(&query)).unwrap()
+        };
+
+        let (single, branched) = (search(false), search(true));
+        assert!(branched.score < single.score, "single-step score {}, branched {}", single.score, branched.score);
+        assert!(branched.key_end - branched.key_start >= single.key_end - single.key_start);
+    }
+
+    #[test]
+    fn test_search_max_mean_distance() {
+        let sample_rate = SessionConfiguration::default().sample_rate;
+        let songs = synth_catalogue(6, sample_rate);
+
+        let search = |cap: Option<f32>| {
+            let config = SessionConfiguration { search_max_mean_distance: cap, ..Default::default() };
+            let (extractor_cfg, db_cfg) = config.into_child_configs();
+            let extractor: FeatureExtractor = extractor_cfg.into();
+            let mut database: Database = db_cfg.into();
+            for (i, song) in songs.iter().enumerate() {
+                database.insert(Uuid::from_u128(i as u128), extractor.features(song));
+            }
+
+            let mut q = database.new_query();
+            for feature in extractor.features(&songs[1][sample_rate / 2..sample_rate * 3]) { q.update(feature); }
+            let beams: usize = q.song_beam_counts().iter().map(|&(_, count)| count).sum();
+
+            (q.finalize().into_iter().next().unwrap(), beams)
+        };
+
+        let (full, full_beams) = search(None);
+        let (capped, capped_beams) = search(Some(4.0));
+
+        assert_eq!(capped.uuid, Uuid::from_u128(1));
+        assert_eq!(capped, full);
+        assert!(capped_beams * 4 < full_beams, "{full_beams} live beams uncapped, {capped_beams} capped");
+    }
+
+    #[test]
+    fn test_score_bounds() {
+        let config = SessionConfiguration::default();
+        let (extractor_cfg, db_cfg) = config.into_child_configs();
+        let extractor: FeatureExtractor = extractor_cfg.into();
+        let database: Database = db_cfg.clone().into();
+
+        let key = extractor.features(&synth_melody(&MELODY[..6], 0.75, config.sample_rate));
+        let (best, worst) = db_cfg.score_bounds(key.len());
+
+        let perfect = database.align_unregistered(&key, &key).unwrap().score;
+        assert!((perfect - best).abs() < 1e-6, "self-match {perfect}, best bound {best}");
+
+        let noise: Vec<Feature> = crate::tests::random_u64s(7).take(key.len()).map(Feature::from).collect();
+        let score = database.align_unregistered(&key, &noise).unwrap().score;
+        assert!(best < score && score <= worst, "{score} outside [{best}, {worst}]");
+    }
+
+    #[test]
+    fn test_feature_database_file() {
+        let config = SessionConfiguration::default();
+        let (extractor_cfg, db_cfg) = config.into_child_configs();
+        let hash = extractor_cfg.config_hash();
+        let mut database: Database = db_cfg.clone().into();
+
+        let songs: Vec<(Uuid, Vec<Feature>)> = (0..3u64)
+            .map(|i| (Uuid::from_u128(i as u128 + 1), (0..i * 5).map(|j| Feature::from(j * 0x0123_4567 + i)).collect()))
+            .collect();
+        for (uuid, features) in &songs { database.insert(*uuid, features.clone()); }
+
+        let mut bytes = Vec::new();
+        database.to_file(hash).write_to(&mut bytes).unwrap();
+        assert_eq!(&bytes[..4], b"SRFD");
+
+        let file = FeatureDatabaseFile::read_from(&mut bytes.as_slice()).unwrap();
+        assert_eq!(file.config_hash, hash);
+        let mut loaded: Database = db_cfg.into();
+        loaded.insert_file(file);
+        for (uuid, features) in &songs {
+            let stored: Vec<u64> = loaded.features(uuid).unwrap().iter().map(|f| *f.as_ref()).collect();
+            assert_eq!(stored, features.iter().map(|f| *f.as_ref()).collect::<Vec<_>>());
+        }
+
+        // every truncation errors rather than panicking
+        for len in 0..bytes.len() {
+            assert!(matches!(FeatureDatabaseFile::read_from(&mut &bytes[..len]), Err(FileError::Io(_))), "{len} bytes");
+        }
+
+        let mut bad = bytes.clone();
+        bad[0] = b'X';
+        assert!(matches!(FeatureDatabaseFile::read_from(&mut bad.as_slice()), Err(FileError::BadMagic)));
+        bad = bytes.clone();
+        bad[4] = 2;
+        assert!(matches!(FeatureDatabaseFile::read_from(&mut bad.as_slice()), Err(FileError::UnsupportedVersion(2))));
+
+        // a huge claimed length fails on the missing data, not on allocation
+        bad = bytes[..20].to_vec();
+        bad.extend(Uuid::nil().as_bytes());
+        bad.extend(u32::MAX.to_le_bytes());
+        bad[16..20].copy_from_slice(&1u32.to_le_bytes());
+        assert!(matches!(FeatureDatabaseFile::read_from(&mut bad.as_slice()), Err(FileError::Io(_))));
+
+        let other = SessionConfiguration { chroma_smoothing: 0.5, ..Default::default() }.into_child_configs().0;
+        assert_ne!(other.config_hash(), hash);
+    }
+
+    #[test]
+    fn test_config_fingerprint() {
+        let (extractor_cfg, db_cfg) = SessionConfiguration::default().into_child_configs();
+        let (old_extractor_cfg, old_db_cfg) = SessionConfiguration { chroma_q_factor: 10.0, ..Default::default() }.into_child_configs();
+        let mut database: Database = db_cfg.into();
+        assert_eq!(database.config_fingerprint(), extractor_cfg.config_hash());
+
+        let features: Vec<Feature> = (0..8u64).map(Feature::from).collect();
+        let current = extractor_cfg.config_hash();
+        let stale = old_extractor_cfg.config_hash();
+
+        assert!(database.register_features(Uuid::from_u128(1), features.clone(), current).is_ok());
+        let err = database.register_features(Uuid::from_u128(2), features.clone(), stale).unwrap_err();
+        assert_eq!(err, FingerprintMismatch { expected: current, found: stale });
+        assert!(database.features(&Uuid::from_u128(2)).is_none());
+
+        // a whole database built under the old settings is refused too
+        let mut old: Database = old_db_cfg.into();
+        old.insert(Uuid::from_u128(3), features);
+        let file = old.to_file(old.config_fingerprint());
+        assert_eq!(database.import_database(file).unwrap_err().found, stale);
+        assert_eq!(database.import_database(old.to_file(current)), Ok(1));
+    }
+
+    #[test]
+    fn test_result_samples() {
+        let config = SessionConfiguration::default();
+        let (extractor_cfg, db_cfg) = config.into_child_configs();
+        let extractor: FeatureExtractor = extractor_cfg.into();
+        let mut database: Database = db_cfg.into();
+
+        let song = extractor.features(&synth_melody(&MELODY, 0.75, config.sample_rate));
+        database.insert(Uuid::from_u128(1), song.clone());
+
+        let mut q = database.new_query();
+        for feature in &song[6..18] { q.update(*feature); }
+        let result = q.finalize().into_iter().next().unwrap();
+
+        assert!(result.key_start > 0);
+        assert_eq!(result.key_start_sample(), extractor.frame_center(result.key_start));
+        assert_eq!(result.key_end_sample(), extractor.frame_center(result.key_end));
+        assert_eq!(result.query_start_sample(), extractor.frame_center(result.query_start));
+    }
+
+    #[test]
+    fn test_suggest_beam_count() {
+        let (_, db_cfg) = SessionConfiguration::default().into_child_configs();
+        let features = |len: u64| (0..len).map(Feature::from).collect::<Vec<_>>();
+
+        let mut small: Database = db_cfg.clone().into();
+        assert_eq!(small.suggest_beam_count(), MIN_SUGGESTED_BEAMS);
+        small.insert(Uuid::from_u128(1), features(40));
+
+        let mut large: Database = db_cfg.into();
+        for i in 0..50 { large.insert(Uuid::from_u128(i + 1), features(1200)); }
+
+        // 40 frames suggest the floor; 50 songs of 1200 frames, 300 * (1 + log2 50)
+        assert_eq!(small.suggest_beam_count(), MIN_SUGGESTED_BEAMS);
+        assert_eq!(large.suggest_beam_count(), 1200);
+        assert!(large.suggest_beam_count() > small.suggest_beam_count());
+    }
+
+    #[test]
+    fn test_duration_of() {
+        let config = SessionConfiguration::default();
+        let (extractor_cfg, db_cfg) = config.into_child_configs();
+        let extractor: FeatureExtractor = extractor_cfg.into();
+        let mut database: Database = db_cfg.into();
+
+        let audio = synth_melody(&MELODY, 0.5, config.sample_rate);
+        let uuid = Uuid::from_u128(1);
+        database.insert(uuid, extractor.features(&audio));
+
+        let expected = audio.len() as f32 / config.sample_rate as f32;
+        let duration = database.duration_of(&uuid).unwrap();
+        assert!((duration - expected).abs() <= config.stride_dt(), "{duration}s for {expected}s of audio");
+        assert!(database.duration_of(&Uuid::from_u128(2)).is_none());
+
+        // gapped songs count up to their last frame
+        database.insert_indexed(uuid, &[(0, Feature::from(0)), (9, Feature::from(1))]);
+        let span = (9 * config.window_stride + config.window_size) as f32 / config.sample_rate as f32;
+        assert_eq!(database.duration_of(&uuid), Some(span));
+    }
+
+    #[test]
+    fn test_with_capacity() {
+        let config = SessionConfiguration::default();
+        let (extractor_cfg, db_cfg) = config.into_child_configs();
+        let extractor: FeatureExtractor = extractor_cfg.into();
+        let mut database = Database::with_capacity(db_cfg, 2);
+
+        let melodies = [
+            [261.6, 329.6, 392.0, 523.3],
+            [293.7, 246.9, 440.0, 261.6],
+            [523.3, 493.9, 440.0, 392.0],
+            [110.0, 155.6, 207.7, 123.5],
+        ];
+        let songs: Vec<Vec<Feature>> = melodies.iter()
+            .map(|melody| extractor.features(&synth_melody(melody, 0.75, config.sample_rate)))
+            .collect();
+        let uuid = |i: usize| Uuid::from_u128(i as u128);
+
+        assert_eq!(database.insert(uuid(0), songs[0].clone()), None);
+        assert_eq!(database.insert(uuid(1), songs[1].clone()), None);
+        assert_eq!(database.insert(uuid(1), songs[1].clone()), None, "replacing doesn't evict");
+
+        // song 0 is oldest, until a query returns it
+        let mut q = database.new_query();
+        for feature in &songs[0][1..6] { q.update(*feature); }
+        assert_eq!(q.finalize_iter().next().unwrap().uuid, uuid(0));
+        assert_eq!(database.insert(uuid(2), songs[2].clone()), Some(uuid(1)));
+        assert_eq!(database.insert_compressed(uuid(3), &songs[3]), Some(uuid(0)));
+        assert_eq!(database.stats().song_count, 2);
+        assert!(database.features(&uuid(0)).is_none());
+
+        let mut q = database.new_query();
+        for feature in &songs[2][1..6] { q.update(*feature); }
+        assert_eq!(q.finalize()[0].uuid, uuid(2));
+    }
+
+    #[test]
+    fn test_finalize_grouped() {
+        let config = SessionConfiguration::default();
+        let (extractor_cfg, db_cfg) = config.into_child_configs();
+        let extractor: FeatureExtractor = extractor_cfg.into();
+        let mut database: Database = db_cfg.into();
+
+        // the verse repeats in one song and appears once in another
+        let verse = extractor.features(&synth_melody(&MELODY[..6], 0.75, config.sample_rate));
+        let chorus = extractor.features(&synth_melody(&[110.0, 155.6, 207.7, 123.5], 1.0, config.sample_rate));
+        let (repeated, once) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        database.insert(repeated, [&verse, &chorus, &verse].into_iter().flatten().copied().collect());
+        database.insert(once, verse.clone());
+
+        let search = || {
+            let mut q = database.new_query();
+            for feature in &verse[4..20] { q.update(*feature); }
+            q
+        };
+        let flat = search().finalize();
+        let grouped = search().finalize_grouped();
+
+        assert_eq!(grouped.len(), 2);
+        assert_eq!(grouped.iter().map(|(_, results)| results.len()).sum::<usize>(), flat.len());
+        for (uuid, results) in &grouped {
+            assert!(results.iter().all(|r| r.uuid == *uuid));
+            assert!(results.windows(2).all(|w| w[0].score <= w[1].score));
+        }
+        assert!(grouped.windows(2).all(|w| w[0].1[0].score <= w[1].1[0].score));
+        assert_eq!(grouped[0].1[0].score, flat[0].score);
+    }
+
+    #[test]
+    fn test_finalize_by_query_time() {
+        let config = SessionConfiguration::default();
+        let (extractor_cfg, db_cfg) = config.into_child_configs();
+        let extractor: FeatureExtractor = extractor_cfg.into();
+        let mut database: Database = db_cfg.into();
+
+        let verse = extractor.features(&synth_melody(&MELODY[..6], 0.75, config.sample_rate));
+        let chorus = extractor.features(&synth_melody(&[110.0, 155.6, 207.7, 123.5], 1.0, config.sample_rate));
+        let (verse_uuid, chorus_uuid) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        database.insert(verse_uuid, verse.clone());
+        database.insert(chorus_uuid, chorus.clone());
+
+        // the chorus is heard first, then the verse
+        let mut q = database.new_query();
+        for feature in chorus[2..14].iter().chain(&verse[2..14]) { q.update(*feature); }
+        let results = q.finalize_by_query_time();
+
+        assert!(results.windows(2).all(|w| w[0].query_start < w[1].query_start));
+        assert_eq!((results[0].uuid, results[0].query_start), (chorus_uuid, 0));
+        assert_eq!((results[1].uuid, results[1].query_start), (verse_uuid, 12));
+    }
+
+    #[test]
+    fn test_adaptive_beams() {
+        let sample_rate = SessionConfiguration::default().sample_rate;
+        let winner = synth_melody(&MELODY[..6], 0.75, sample_rate);
+        let distractors: Vec<Vec<f32>> = (0..12)
+            .map(|i| {
+                let root = 110.0 * 2f32.powf(i as f32 / 12.0);
+                synth_melody(&[root, root * 1.5, root * 1.19, root * 1.78], 1.0, sample_rate)
+            })
+            .collect();
+
+        let run = |adaptive: bool| {
+            let config = SessionConfiguration { search_beam_count: 20, search_adaptive_beams: adaptive, ..Default::default() };
+            let (extractor_cfg, db_cfg) = config.into_child_configs();
+            let extractor: FeatureExtractor = extractor_cfg.into();
+            let mut database: Database = db_cfg.into();
+
+            let key = extractor.features(&winner);
+            database.insert(Uuid::from_u128(0), key.clone());
+            for (i, distractor) in distractors.iter().enumerate() {
+                database.insert(Uuid::from_u128(i as u128 + 1), extractor.features(distractor));
+            }
+
+            let mut query = database.new_query();
+            for feature in &key[4..20] { query.update(*feature); }
+
+            let distractor_beams: usize = query.song_beam_counts()
+                .into_iter()
+                .filter(|&(uuid, _)| uuid != Uuid::from_u128(0))
+                .map(|(_, count)| count)
+                .sum();
+            let best = query.finalize().into_iter().next().unwrap();
+            (distractor_beams, best.uuid, best.score)
+        };
+
+        let (fixed_beams, fixed_uuid, fixed_score) = run(false);
+        let (adaptive_beams, adaptive_uuid, adaptive_score) = run(true);
+
+        assert_eq!(fixed_uuid, Uuid::from_u128(0));
+        assert_eq!(adaptive_uuid, Uuid::from_u128(0));
+        assert!(adaptive_score <= fixed_score);
+        assert!(adaptive_beams * 2 < fixed_beams, "adaptive {adaptive_beams} fixed {fixed_beams}");
+    }
+
+    #[test]
+    fn test_max_query_frames() {
+        let sample_rate = SessionConfiguration::default().sample_rate;
+        let melody = synth_melody(&MELODY[..6], 0.75, sample_rate);
+        let other = synth_melody(&[110.0, 155.6, 207.7, 123.5], 1.0, sample_rate);
+
+        let run = |config: SessionConfiguration| {
+            let (extractor_cfg, db_cfg) = config.into_child_configs();
+            let extractor: FeatureExtractor = extractor_cfg.into();
+            let mut database: Database = db_cfg.into();
+
+            // a long recording, streamed in full
+            let key = extractor.features(&melody.repeat(12));
+            database.insert(Uuid::from_u128(1), key.clone());
+            database.insert(Uuid::from_u128(2), extractor.features(&other));
+
+            let mut query = database.new_query();
+            for feature in &key { query.update(*feature); }
+            let accepted = query.stats().frames;
+
+            let longest_path = query.song_beams
+                .iter()
+                .flat_map(|(_, _, _, beams)| beams.iter().map(|(_, beam)| beam.path.len()))
+                .max()
+                .unwrap();
+            (accepted, longest_path, query.finalize()[0].uuid)
+        };
+
+        let (accepted, longest_path, _) = run(SessionConfiguration::default());
+        assert!(longest_path > 100, "unbounded paths grow with the stream");
+        assert_eq!(accepted, longest_path);
+
+        // with decay the query keeps running on a bounded window
+        let (accepted, longest_path, best) = run(SessionConfiguration {
+            search_max_query_frames: Some(24), search_decay: 0.9, ..Default::default()
+        });
+        assert!(accepted > 100);
+        assert!(longest_path <= 24);
+        assert_eq!(best, Uuid::from_u128(1));
+
+        // without decay it stops taking features and asks to be finalized
+        let (accepted, longest_path, best) = run(SessionConfiguration { search_max_query_frames: Some(24), ..Default::default() });
+        assert_eq!(accepted, 24);
+        assert!(longest_path <= 24);
+        assert_eq!(best, Uuid::from_u128(1));
+    }
+
+    #[test]
+    fn test_max_query_frames_roll_off() {
+        let config = SessionConfiguration {
+            search_max_query_frames: Some(24), search_decay: 0.999, search_score_breakdown: true, ..Default::default()
+        };
+        let (extractor_cfg, db_cfg) = config.into_child_configs();
+        let extractor: FeatureExtractor = extractor_cfg.into();
+        let mut database: Database = db_cfg.into();
+
+        let melody = synth_melody(&MELODY[..6], 0.75, config.sample_rate);
+        let key = extractor.features(&melody.repeat(4));
+        database.insert(Uuid::from_u128(1), key.clone());
+
+        // every query frame is one bit off its key frame
+        let mut query = database.new_query();
+        for (i, feature) in key.iter().enumerate() { query.update(Feature::from(feature.as_ref() ^ 1 << (i % 64))); }
+        let best = query.finalize().into_iter().next().unwrap();
+
+        // the breakdown covers only the frames the beam kept
+        let breakdown = best.breakdown.unwrap();
+        assert_eq!(breakdown.frames, 24);
+        assert!(breakdown.raw_distance_sum <= 24, "{breakdown:?}");
+    }
+
+    #[test]
+    fn test_insert_streaming() {
+        let config = SessionConfiguration::default();
+        let (extractor_cfg, db_cfg) = config.into_child_configs();
+        let extractor: FeatureExtractor = extractor_cfg.into();
+        let (_, streamed_cfg) = config.into_child_configs();
+        let mut whole: Database = db_cfg.into();
+        let mut streamed: Database = streamed_cfg.into();
+
+        let audio = synth_melody(&MELODY, 0.75, config.sample_rate);
+        let uuid = Uuid::from_u128(1);
+        whole.insert(uuid, extractor.features(&audio));
+
+        // stale contents under the same key are replaced
+        streamed.insert(uuid, vec![Feature::from(1); 3]);
+
+        let mut extraction = extractor.streaming();
+        let mut insert = streamed.insert_streaming(uuid);
+        for chunk in audio.chunks(3001) {
+            insert.push(&extraction.push(chunk));
+        }
+        insert.push(&extraction.finish());
+        assert_eq!(insert.finish(), whole.database[&uuid].len());
+
+        let stored = |database: &Database| -> Vec<u64> { database.database[&uuid].iter().map(|f| *f.as_ref()).collect() };
+        assert_eq!(stored(&streamed), stored(&whole));
+
+        let clip = extractor.features(&audio[config.sample_rate * 2..config.sample_rate * 4]);
+        let (a, b) = (whole.align_to(&uuid, &clip).unwrap(), streamed.align_to(&uuid, &clip).unwrap());
+        assert_eq!((a.score, a.key_end, a.query_start), (b.score, b.key_end, b.query_start));
+    }
+
+    #[test]
+    fn test_energy_weighted_silent_start() {
+        let config = SessionConfiguration { search_energy_weight: true, search_length_penalty: 0, ..Default::default() };
+        let (extractor_cfg, db_cfg) = config.into_child_configs();
+        let extractor: FeatureExtractor = extractor_cfg.into();
+        let mut database: Database = db_cfg.into();
+
+        let song = synth_melody(&MELODY[..6], 0.75, config.sample_rate);
+        database.insert(Uuid::from_u128(1), extractor.features(&song));
+
+        // silent frames weigh nothing, but mustn't leave beams with a zero denominator
+        let mut clip = vec![0.0; config.sample_rate];
+        clip.extend_from_slice(&song[config.sample_rate..config.sample_rate * 3]);
+        let frames = extractor.features_with_energy(&clip);
+        assert_eq!(frames[0].1, 0.0);
+
+        for len in [4, frames.len()] {
+            let mut q = database.new_query();
+            for (frame, &(feature, energy)) in frames[..len].iter().enumerate() { q.update_with_energy(feature, frame, energy); }
+
+            let results = q.finalize();
+            assert!(!results.is_empty());
+            assert!(results.iter().all(|result| result.score.is_finite()), "{len} frames");
+        }
+    }
+
+    #[test]
+    fn test_context_window() {
+        let config = SessionConfiguration { search_context_frames: 6, ..Default::default() };
+        let (extractor_cfg, db_cfg) = config.into_child_configs();
+        let extractor: FeatureExtractor = extractor_cfg.into();
+        let mut database: Database = db_cfg.into();
+
+        let key = extractor.features(&synth_melody(&MELODY, 0.75, config.sample_rate));
+        let uuid = Uuid::new_v4();
+        database.insert(uuid, key.clone());
+        let last = key.len() - 1;
+
+        let context = |clip: &[Feature]| {
+            let result = database.align_to(&uuid, clip).unwrap();
+            (result.key_start, result.key_end, result.context_start, result.context_end)
+        };
+
+        let (start, end, context_start, context_end) = context(&key[10..20]);
+        assert_eq!((context_start, context_end), (start - 6, end + 6));
+
+        // clamped at the start of the song
+        let (start, end, context_start, context_end) = context(&key[2..12]);
+        assert!(start < 6);
+        assert_eq!((context_start, context_end), (0, end + 6));
+
+        // and at its end
+        let (start, end, context_start, context_end) = context(&key[last - 11..]);
+        assert!(end + 6 > last);
+        assert_eq!((context_start, context_end), (start - 6, last));
+    }
+
+    #[test]
+    fn test_context_window_onset_gated() {
+        let config = SessionConfiguration { search_context_frames: 6, onset_gated: true, ..Default::default() };
+        let (extractor_cfg, db_cfg) = config.into_child_configs();
+        let extractor: FeatureExtractor = extractor_cfg.into();
+        let mut database: Database = db_cfg.into();
+
+        // plucked notes, each a decaying tone followed by a rest, so only attacks are kept
+        let (note_len, rest_len) = (config.sample_rate * 6 / 10, config.sample_rate * 3 / 10);
+        let song: Vec<f32> = [261.6, 392.0, 329.6, 523.3, 440.0, 293.7, 349.2, 246.9]
+            .iter()
+            .flat_map(|&f| (0..note_len + rest_len).map(move |i| {
+                if i >= note_len { return 0.0 }
+                let t = i as f32 / config.sample_rate as f32;
+                (std::f32::consts::TAU * f * t).sin() * (-4.0 * t).exp()
+            }))
+            .collect();
+
+        let key = extractor.features_indexed(&song);
+        let uuid = Uuid::new_v4();
+        database.insert_indexed(uuid, &key);
+        assert!(key.windows(2).all(|pair| pair[1].0 - pair[0].0 > 1));
+
+        // the padding is six frames of audio, not six onsets
+        let result = database.align_to_indexed(&uuid, &key[3..6]).unwrap();
+        assert_eq!((result.key_start, result.key_end), (key[3].0, key[5].0));
+        assert_eq!((result.context_start, result.context_end), (result.key_start - 6, result.key_end + 6));
+    }
+
+    #[test]
+    fn test_song_priors() {
+        let config = SessionConfiguration::default();
+        let (extractor_cfg, db_cfg) = config.into_child_configs();
+        let extractor: FeatureExtractor = extractor_cfg.into();
+        let mut database: Database = db_cfg.into();
+
+        let song = synth_melody(&MELODY[..6], 0.75, config.sample_rate);
+        let mut cover = song.clone();
+        cover[config.sample_rate * 2..].iter_mut().for_each(|s| *s *= 0.8);
+
+        let (original, popular) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        database.insert(original, extractor.features(&song));
+        database.insert_with_prior(popular, extractor.features(&cover), 1.0);
+
+        // a near-identical recording wins on its prior
+        let clip = extractor.features(&song[0..config.sample_rate * 3]);
+        let best = |database: &Database, clip: &[Feature]| {
+            let mut q = database.new_query();
+            for feature in clip { q.update(*feature); }
+            q.finalize()[0].uuid
+        };
+        assert_eq!(best(&database, &clip), popular);
+
+        // but not against a clearly better match
+        let distractor = extractor.features(&synth_melody(&[261.6, 329.6, 392.0, 110.0, 146.8, 196.0], 0.75, config.sample_rate));
+        database.insert(popular, Vec::new());
+        database.insert_with_prior(Uuid::from_u128(3), distractor, 1.0);
+        assert_eq!(best(&database, &clip), original);
+
+        assert_eq!(database.prior_bonus(&Uuid::from_u128(3)), config.search_prior_weight);
+        assert_eq!(database.prior_bonus(&popular), 0, "re-inserting clears the prior");
+    }
+
+    #[test]
+    fn test_try_new() {
+        let cfg = |config: SessionConfiguration| config.into_child_configs().1;
+
+        assert!(Database::try_new(cfg(SessionConfiguration::default())).is_ok());
+        assert_eq!(Database::try_new(cfg(SessionConfiguration { search_beam_count: 0, ..Default::default() })).err(), Some(ConfigError::BeamCount));
+        assert_eq!(Database::try_new(cfg(SessionConfiguration { search_decay: 1.5, ..Default::default() })).err(), Some(ConfigError::Decay(1.5)));
+
+        for overlap in [-0.1, 1.5] {
+            let config = SessionConfiguration { search_nonmax_overlap: overlap, ..Default::default() };
+            assert_eq!(Database::try_new(cfg(config)).err(), Some(ConfigError::NonmaxOverlap(overlap)));
+        }
+        let nan = SessionConfiguration { search_nonmax_overlap: f32::NAN, ..Default::default() };
+        assert!(matches!(Database::try_new(cfg(nan)), Err(ConfigError::NonmaxOverlap(_))));
+        for overlap in [0.0, 1.0] {
+            assert!(Database::try_new(cfg(SessionConfiguration { search_nonmax_overlap: overlap, ..Default::default() })).is_ok());
+        }
+
+        let panicked = std::panic::catch_unwind(|| Database::from(cfg(SessionConfiguration { search_decay: 0.0, ..Default::default() })));
+        assert!(panicked.is_err());
+    }
+
+    #[test]
+    fn test_prefilter() {
+        let config = SessionConfiguration::default();
+        let (extractor_cfg, db_cfg) = config.into_child_configs();
+        let extractor: FeatureExtractor = extractor_cfg.into();
+        let mut database: Database = db_cfg.into();
+
+        // a catalogue of melodies built from the same scale
+        let songs = synth_catalogue(24, config.sample_rate);
+
+        for (i, song) in songs.iter().enumerate() {
+            database.insert(Uuid::from_u128(i as u128), extractor.features(song));
+        }
+
+        for i in [0, 7, 13, 22] {
+            // clips start off the key's window grid
+            let start = config.sample_rate / 2 + 301;
+            let clip = extractor.features(&songs[i][start..start + config.sample_rate * 2]);
+
+            let candidates = database.prefilter(&clip, 3);
+            assert_eq!(candidates.len(), 3);
+            assert!(candidates.contains(&Uuid::from_u128(i as u128)), "song {i} not in {candidates:?}");
+        }
+
+        assert_eq!(database.prefilter(&[], 100).len(), songs.len());
+    }
+
+    #[test]
+    fn test_coarse_token_wide() {
+        // more bins than a u16 has bits; each fits in the 64-bit feature
+        let config = SessionConfiguration { chroma_bins_per_octave: 24, quantizer_bits_per_bin: 2, ..Default::default() };
+        let (_, db_cfg) = config.into_child_configs();
+
+        assert_eq!(db_cfg.coarse_token(Feature::from(u64::MAX)), (1 << 24) - 1);
+        assert_eq!(db_cfg.coarse_token(Feature::from(0b10 << 46)), 1 << 23);
+    }
+
+    #[test]
+    fn test_coarse_shortlist() {
+        let config = SessionConfiguration { search_coarse_factor: Some(4), ..Default::default() };
+        let (extractor_cfg, db_cfg) = config.into_child_configs();
+        let extractor: FeatureExtractor = extractor_cfg.into();
+        let mut database: Database = db_cfg.into();
+
+        let songs = synth_catalogue(24, config.sample_rate);
+
+        for (i, song) in songs.iter().enumerate() {
+            database.insert(Uuid::from_u128(i as u128), extractor.features(song));
+        }
+
+        for i in [0, 7, 13, 22] {
+            // clips start off both the window grid and the coarse grid
+            let start = config.sample_rate / 2 + 301;
+            let clip = extractor.features(&songs[i][start..start + config.sample_rate * 2]);
+
+            let shortlist = database.coarse_shortlist(&clip, 3).unwrap();
+            assert_eq!(shortlist.len(), 3);
+            assert!(shortlist.contains(&Uuid::from_u128(i as u128)), "song {i} not in {shortlist:?}");
+        }
+
+        // streamed songs have no coarse tier and are never filtered out
+        let streamed = Uuid::from_u128(100);
+        database.insert_streaming(streamed).push(&extractor.features(&songs[0]));
+        assert!(database.coarse_shortlist(&extractor.features(&songs[5]), 3).unwrap().contains(&streamed));
+
+        let plain: Database = SessionConfiguration::default().into_child_configs().1.into();
+        assert!(plain.coarse_shortlist(&[], 3).is_none());
+    }
+
+    #[test]
+    fn test_features() {
+        let mut database: Database = SessionConfiguration::default().into_child_configs().1.into();
+        let (plain, compressed) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let features: Vec<Feature> = [0b1011u64, 0, u64::MAX].into_iter().map(Feature::from).collect();
+
+        database.insert(plain, features.clone());
+        database.insert_compressed(compressed, &features);
+
+        for uuid in [plain, compressed] {
+            let stored: Vec<u64> = database.features(&uuid).unwrap().iter().map(|f| *f.as_ref()).collect();
+            assert_eq!(stored, [0b1011, 0, u64::MAX]);
+        }
+        assert!(database.features(&Uuid::from_u128(3)).is_none());
+    }
+
+    #[cfg(feature = "trace")]
+    #[test]
+    fn test_trace_events() {
+        let config = SessionConfiguration { search_beam_count: 4, ..Default::default() };
+        let (_, db_cfg) = config.into_child_configs();
+        let mut database: Database = db_cfg.into();
+
+        let key: Vec<Feature> = (0..10u64).map(|i| Feature::from(i * 0x0101)).collect();
+        database.insert(Uuid::from_u128(1), key.clone());
+
+        let events = std::cell::RefCell::new(Vec::new());
+        let mut q = database.new_query();
+        q.on_trace(|event| events.borrow_mut().push(event));
+        for feature in &key[2..5] { q.update(*feature); }
+        let results = q.finalize();
+
+        let events = events.into_inner();
+        let count = |f: fn(&TraceEvent) -> bool| events.iter().filter(|e| f(e)).count();
+
+        // every frame offers 10 seeds plus the surviving beams, trimmed to 4
+        assert_eq!(count(|e| matches!(e, TraceEvent::Pruned { .. })), 3);
+        assert!(events.iter().all(|e| !matches!(e, TraceEvent::Pruned { count, .. } if *count < 6)));
+        assert!(matches!(events[0], TraceEvent::Seeded { frame: 0, count: 4, .. }));
+        assert_eq!(count(|e| matches!(e, TraceEvent::Candidate { .. })), results.len());
+    }
+
     #[test]
     fn test_feature_distance() {
         let f1 = Feature::from(0b1010u64);
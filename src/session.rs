@@ -0,0 +1,1576 @@
+use crate::{fingerprint, resample_linear, search, Database, DatabaseConfiguration, FeatureExtractor, FeatureExtractorConfiguration, QueryResult, SessionConfiguration};
+use wasm_bindgen::prelude::*;
+use std::{collections::{HashMap, HashSet}, sync::Arc};
+
+/// Why a string can't be used as a song's key.
+#[derive(Debug)]
+pub enum SongKeyError {
+    /// not a uuid at all
+    Malformed(uuid::Error),
+    /// the all-zero uuid, which is almost always a placeholder that would collide
+    Nil,
+}
+
+impl std::fmt::Display for SongKeyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Malformed(err) => write!(f, "malformed song uuid: {err}"),
+            Self::Nil => write!(f, "the nil uuid can't be used as a song key"),
+        }
+    }
+}
+
+impl std::error::Error for SongKeyError {}
+
+/// Parses the uuid a song is registered under.
+fn parse_song_key(uuid: &str) -> Result<uuid::Uuid, SongKeyError> {
+    let uuid = uuid::Uuid::try_parse(uuid).map_err(SongKeyError::Malformed)?;
+    if uuid.is_nil() { return Err(SongKeyError::Nil) }
+
+    Ok(uuid)
+}
+
+/// Why planar channel data can't be registered or searched.
+#[derive(Debug)]
+pub enum PlanarError {
+    /// the song key is unusable
+    Key(SongKeyError),
+    /// no channels were given
+    NoChannels,
+    /// a channel's length differs from the first's
+    Ragged { channel: usize, len: usize, expected: usize },
+}
+
+impl std::fmt::Display for PlanarError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Key(err) => err.fmt(f),
+            Self::NoChannels => write!(f, "planar audio needs at least one channel"),
+            Self::Ragged { channel, len, expected } => write!(f, "channel {channel} has {len} samples, expected {expected}"),
+        }
+    }
+}
+
+impl std::error::Error for PlanarError {}
+
+impl From<SongKeyError> for PlanarError {
+    fn from(err: SongKeyError) -> Self { Self::Key(err) }
+}
+
+/// Averages equal-length planar channels into one mono channel.
+fn downmix_planar(channels: &[Box<[f32]>]) -> Result<Vec<f32>, PlanarError> {
+    let (first, rest) = channels.split_first().ok_or(PlanarError::NoChannels)?;
+    if let Some((channel, other)) = rest.iter().enumerate().find(|(_, other)| other.len() != first.len()) {
+        return Err(PlanarError::Ragged { channel: channel + 1, len: other.len(), expected: first.len() })
+    }
+
+    let scale = 1.0 / channels.len() as f32;
+    Ok((0..first.len()).map(|i| channels.iter().map(|channel| channel[i]).sum::<f32>() * scale).collect())
+}
+
+/// Frames two `scan` matches' alignments may differ by and still be merged.
+const SCAN_ALIGNMENT_SLACK: usize = 2;
+
+/// Chroma energy at or below which `explain` counts a frame as silent.
+const SILENT_FRAME_ENERGY: f32 = 1e-6;
+
+#[wasm_bindgen]
+pub struct Session {
+    extractor: Arc<FeatureExtractor>,
+    db: Database,
+    /// the rate `register` and `search` expect audio at
+    sample_rate: u32,
+    stride_dt: f32,
+    score_threshold: f32,
+    /// `search` feeds every `query_hop`th query feature to the search
+    query_hop: usize,
+    /// beam search only the best songs by `Database::prefilter`
+    prefilter: Option<usize>,
+    /// and only the best songs of a coarse pass, with `search_coarse_factor`
+    coarse_shortlist: Option<usize>,
+    /// results from different songs starting within this many seconds of a better
+    /// one are folded into it
+    dedup_window: f32,
+    /// RMS below which registered audio's leading and trailing silence is dropped
+    trim_threshold: Option<f32>,
+    /// log search trace events to the console
+    #[cfg(feature = "trace")]
+    trace: bool,
+    #[cfg(feature = "metrics")]
+    metrics: crate::metrics::SessionMetrics,
+}
+
+#[cfg(feature = "trace")]
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_namespace = console, js_name = log)]
+    fn console_log(message: &str);
+}
+
+#[wasm_bindgen]
+#[derive(Clone)]
+pub struct SessionQueryResult {
+    uuid: String,
+
+    #[wasm_bindgen(readonly)]
+    pub score: f32,
+
+    #[wasm_bindgen(js_name = keyStart, readonly)]
+    pub key_start: f32,
+
+    #[wasm_bindgen(js_name = keyEnd, readonly)]
+    pub key_end: f32,
+
+    #[wasm_bindgen(js_name = queryStart, readonly)]
+    pub query_start: f32,
+
+    /// the matched span padded by `searchContextFrames`, clamped to the song
+    #[wasm_bindgen(js_name = contextStart, readonly)]
+    pub context_start: f32,
+
+    #[wasm_bindgen(js_name = contextEnd, readonly)]
+    pub context_end: f32,
+
+    /// chroma bins the query sits above the key; 0 outside of transposed searches
+    #[wasm_bindgen(readonly)]
+    pub transposition: i32,
+
+    /// other songs matching the same query region, folded into this result by
+    /// `dedupQueryWindow`
+    #[wasm_bindgen(readonly)]
+    pub duplicates: usize,
+
+    /// of the audio the times are in, for the `*Sample` getters
+    sample_rate: u32,
+}
+
+/// Matches from `searchWithFrames`, best first, with how much of the query they cover.
+#[wasm_bindgen]
+pub struct SessionSearchResults {
+    results: Vec<SessionQueryResult>,
+
+    /// features extracted from the query audio
+    #[wasm_bindgen(js_name = queryFrames, readonly)]
+    pub query_frames: usize,
+}
+
+/// One song's matches from `searchGrouped`, best first.
+#[wasm_bindgen]
+pub struct SessionSongMatches {
+    uuid: String,
+    results: Vec<SessionQueryResult>,
+}
+
+/// Why `explain`'s query did or didn't produce a match, in order of precedence.
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExplainReason {
+    /// no songs are registered
+    EmptyDatabase,
+    /// the clip was too short for a single window, or silent throughout
+    NoFeatures,
+    /// no song aligned with the query at all
+    NoAlignment,
+    /// the best match scored worse than `search_score_threshold`
+    AboveThreshold,
+    Matched,
+}
+
+/// Diagnostics from `explain`; scores are on the search scale, lower is better.
+#[wasm_bindgen]
+pub struct ExplainReport {
+    #[wasm_bindgen(readonly)]
+    pub reason: ExplainReason,
+
+    /// features extracted from the query audio
+    #[wasm_bindgen(js_name = queryFrames, readonly)]
+    pub query_frames: usize,
+
+    /// query features with more than silence's energy
+    #[wasm_bindgen(js_name = audibleFrames, readonly)]
+    pub audible_frames: usize,
+
+    #[wasm_bindgen(js_name = songCount, readonly)]
+    pub song_count: usize,
+
+    /// infinite when nothing aligned
+    #[wasm_bindgen(js_name = bestScore, readonly)]
+    pub best_score: f32,
+
+    #[wasm_bindgen(js_name = scoreThreshold, readonly)]
+    pub score_threshold: f32,
+}
+
+#[wasm_bindgen]
+pub struct SessionStats {
+    #[wasm_bindgen(js_name = songCount, readonly)]
+    pub song_count: usize,
+
+    #[wasm_bindgen(js_name = totalFeatures, readonly)]
+    pub total_features: usize,
+
+    #[wasm_bindgen(js_name = longestSong, readonly)]
+    pub longest_song: f32,
+
+    #[wasm_bindgen(js_name = shortestSong, readonly)]
+    pub shortest_song: f32,
+
+    #[wasm_bindgen(js_name = memoryBytes, readonly)]
+    pub memory_bytes: usize,
+}
+
+#[wasm_bindgen]
+impl SessionQueryResult {
+    #[wasm_bindgen(getter)]
+    pub fn uuid(&self) -> String {
+        self.uuid.clone()
+    }
+
+    /// `keyStart` as a sample index into the registered audio.
+    #[wasm_bindgen(getter, js_name = keyStartSample)]
+    pub fn key_start_sample(&self) -> usize { self.to_sample(self.key_start) }
+
+    #[wasm_bindgen(getter, js_name = keyEndSample)]
+    pub fn key_end_sample(&self) -> usize { self.to_sample(self.key_end) }
+
+    /// `queryStart` as a sample index into the query audio.
+    #[wasm_bindgen(getter, js_name = queryStartSample)]
+    pub fn query_start_sample(&self) -> usize { self.to_sample(self.query_start) }
+
+    #[wasm_bindgen(getter, js_name = contextStartSample)]
+    pub fn context_start_sample(&self) -> usize { self.to_sample(self.context_start) }
+
+    #[wasm_bindgen(getter, js_name = contextEndSample)]
+    pub fn context_end_sample(&self) -> usize { self.to_sample(self.context_end) }
+
+    /// times are window centres, so this lands back on the frame's `frame_center`
+    fn to_sample(&self, seconds: f32) -> usize {
+        (seconds * self.sample_rate as f32).round() as usize
+    }
+}
+
+#[wasm_bindgen]
+impl SessionSearchResults {
+    /// Hands over the matches, consuming the wrapper rather than copying them out.
+    #[wasm_bindgen(js_name = intoResults)]
+    pub fn into_results(self) -> Vec<SessionQueryResult> {
+        self.results
+    }
+}
+
+#[wasm_bindgen]
+impl SessionSongMatches {
+    #[wasm_bindgen(getter)]
+    pub fn uuid(&self) -> String {
+        self.uuid.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn results(&self) -> Vec<SessionQueryResult> {
+        self.results.clone()
+    }
+}
+
+#[wasm_bindgen]
+impl Session {
+
+    fn resample(audio: &[f32], fs_in: u32, fs_out: u32) -> Vec<f32> {
+        resample_linear(audio, fs_in, fs_out)
+    }
+
+    /// Builds a session from a partial configuration object; omitted options keep
+    /// their defaults, and no object at all means every default.
+    #[wasm_bindgen(constructor)]
+    pub fn new(cfg: JsValue) -> Result<Session, JsError> {
+        if cfg.is_undefined() || cfg.is_null() { return Ok(SessionConfiguration::default().into()) }
+
+        let cfg: SessionConfiguration = serde_wasm_bindgen::from_value(cfg)?;
+
+        Ok(cfg.into())
+    }
+
+    /// Registers `audio` under `uuid`, returning how many features were extracted.
+    /// `audio` must already be at the configured `sampleRate`.
+    #[wasm_bindgen]
+    pub fn register(&mut self, uuid: String, audio: &[f32]) -> Result<usize, JsError> {
+        let uuid = parse_song_key(&uuid)?;
+
+        Ok(self.insert_features(uuid, self.register_features(audio)))
+    }
+
+    /// Registers `audio` under a v4-shaped uuid derived from `seed` and returns it,
+    /// so reproducible pipelines and snapshot tests get the same keys every run.
+    #[wasm_bindgen(js_name = registerAutoSeeded)]
+    pub fn register_auto_seeded(&mut self, audio: &[f32], seed: u64) -> String {
+        // splitmix64
+        let mut state = seed;
+        let mut next = || {
+            state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let z = (state ^ (state >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            let z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            z ^ (z >> 31)
+        };
+        let bytes = ((next() as u128) << 64 | next() as u128).to_le_bytes();
+        let uuid = uuid::Builder::from_random_bytes(bytes).into_uuid();
+
+        self.insert_features(uuid, self.register_features(audio));
+        uuid.to_string()
+    }
+
+    /// Like `register`, for 16-bit PCM such as decoded WAV data.
+    #[wasm_bindgen(js_name = registerI16)]
+    pub fn register_i16(&mut self, uuid: String, audio: &[i16]) -> Result<usize, JsError> {
+        let uuid = parse_song_key(&uuid)?;
+
+        Ok(self.insert_features(uuid, self.register_features(&fingerprint::pcm_i16_to_f32(audio))))
+    }
+
+    /// Like `register`, for two-channel audio combined by `stereoMode`. Silence is
+    /// not trimmed.
+    #[wasm_bindgen(js_name = registerStereo)]
+    pub fn register_stereo(&mut self, uuid: String, left: &[f32], right: &[f32]) -> Result<usize, JsError> {
+        let uuid = parse_song_key(&uuid)?;
+        let features = self.extractor.frames_stereo(left, right)
+            .into_iter()
+            .map(|(frame, feature, _, _)| (frame, feature))
+            .collect();
+
+        Ok(self.insert_features(uuid, features))
+    }
+
+    /// Like `search`, for two-channel audio combined by `stereoMode`.
+    #[wasm_bindgen(js_name = searchStereo)]
+    pub fn search_stereo(&mut self, left: &[f32], right: &[f32]) -> Vec<SessionQueryResult> {
+        let features = self.extractor.frames_stereo(left, right);
+
+        self.search_features(features).results
+    }
+
+    /// Like `register`, for an `AudioBuffer`'s planar channel data, averaged to mono.
+    /// Every channel must be the same length.
+    #[wasm_bindgen(js_name = registerPlanar)]
+    pub fn register_planar_js(&mut self, uuid: String, channels: Vec<js_sys::Float32Array>) -> Result<usize, JsError> {
+        Ok(self.register_planar(uuid, channels.iter().map(|channel| channel.to_vec().into()).collect())?)
+    }
+
+    /// Like `search`, for an `AudioBuffer`'s planar channel data, averaged to mono.
+    #[wasm_bindgen(js_name = searchPlanar)]
+    pub fn search_planar_js(&mut self, channels: Vec<js_sys::Float32Array>) -> Result<Vec<SessionQueryResult>, JsError> {
+        Ok(self.search_planar(channels.iter().map(|channel| channel.to_vec().into()).collect())?)
+    }
+
+    /// Searches for `audio`, which must already be at the configured `sampleRate`;
+    /// see `searchRaw` for audio at any other rate.
+    pub fn search(&mut self, audio: &[f32]) -> Vec<SessionQueryResult> {
+        self.search_with_frames(audio).results
+    }
+
+    /// Like `search`, also reporting how many features the query audio gave.
+    #[wasm_bindgen(js_name = searchWithFrames)]
+    pub fn search_with_frames(&mut self, audio: &[f32]) -> SessionSearchResults {
+        let features = self.extractor.frames(audio);
+
+        self.search_features(features)
+    }
+
+    /// Like `search`, for audio at `input_rate`, which is resampled to the configured
+    /// rate first if it differs.
+    #[wasm_bindgen(js_name = searchRaw)]
+    pub fn search_raw(&mut self, audio: &[f32], input_rate: u32) -> Vec<SessionQueryResult> {
+        if input_rate == self.sample_rate { return self.search(audio) }
+
+        self.search(&Self::resample(audio, input_rate, self.sample_rate))
+    }
+
+    /// Like `search`, with each song's matching regions gathered together. Songs
+    /// are ordered by their best match.
+    #[wasm_bindgen(js_name = searchGrouped)]
+    pub fn search_grouped(&mut self, audio: &[f32]) -> Vec<SessionSongMatches> {
+        self.query(self.extractor.frames(audio))
+            .finalize_grouped()
+            .into_iter()
+            .map(|(uuid, results)| SessionSongMatches {
+                uuid: uuid.to_string(),
+                results: results.into_iter().map(|res| self.to_session_result(res)).collect(),
+            })
+            .collect()
+    }
+
+    /// Like `search`, keeping the best match for each region of the query and
+    /// ordering them by `queryStart`, as a scrobbling UI lists what it heard.
+    #[wasm_bindgen(js_name = searchByQueryTime)]
+    pub fn search_by_query_time(&mut self, audio: &[f32]) -> Vec<SessionQueryResult> {
+        self.query(self.extractor.frames(audio))
+            .finalize_by_query_time()
+            .into_iter()
+            .map(|res| self.to_session_result(res))
+            .collect()
+    }
+
+    /// Like `search`, for 16-bit PCM such as decoded WAV data.
+    #[wasm_bindgen(js_name = searchI16)]
+    pub fn search_i16(&mut self, audio: &[i16]) -> Vec<SessionQueryResult> {
+        let features = self.extractor.frames(&fingerprint::pcm_i16_to_f32(audio));
+
+        self.search_features(features).results
+    }
+
+    /// Like `search`, but only matches the songs in `uuids`, each within its
+    /// `starts[i]..ends[i]` window in seconds.
+    #[wasm_bindgen(js_name = searchRanged)]
+    pub fn search_ranged(&mut self, audio: &[f32], uuids: Vec<String>, starts: &[f32], ends: &[f32]) -> Result<Vec<SessionQueryResult>, JsError> {
+        let mut ranges = HashMap::new();
+
+        for ((uuid, start), end) in uuids.iter().zip(starts).zip(ends) {
+            let window = (self.time_frame(*start) as usize, self.time_frame(*end).ceil() as usize);
+            ranges.insert(uuid::Uuid::try_parse(uuid)?, window);
+        }
+
+        let mut q = self.db.new_query_ranged(ranges);
+        for (frame, feature, energy, contrast) in self.extractor.frames(audio) {
+            q.update_with_contrast(feature, frame, energy, contrast);
+        }
+
+        Ok(q.finalize().into_iter()
+            .map(|res| self.to_session_result(res))
+            .collect())
+    }
+
+    /// The features stored for `uuid`, or `undefined` if it isn't registered.
+    /// Features can't be re-derived under a new configuration without the original
+    /// audio, so after changing extractor settings (see
+    /// `SessionConfiguration::requires_reextraction`) tracks must be registered again.
+    #[wasm_bindgen(js_name = featuresOf)]
+    pub fn features_of(&self, uuid: String) -> Option<Vec<u64>> {
+        let uuid = uuid::Uuid::try_parse(&uuid).ok()?;
+
+        self.db.features(&uuid).map(|features| features.iter().map(|f| *f.as_ref()).collect())
+    }
+
+    /// `audio`'s features, one per frame, without touching the database; for drawing
+    /// a fingerprint strip alongside the waveform.
+    #[wasm_bindgen(js_name = queryFeatures)]
+    pub fn query_features(&self, audio: &[f32]) -> Vec<u64> {
+        self.extractor.features(audio).iter().map(|f| *f.as_ref()).collect()
+    }
+
+    /// A registered song's length in seconds, or `undefined` if it isn't registered.
+    #[wasm_bindgen(js_name = durationOf)]
+    pub fn duration_of(&self, uuid: String) -> Option<f32> {
+        let uuid = uuid::Uuid::try_parse(&uuid).ok()?;
+
+        self.db.duration_of(&uuid)
+    }
+
+    /// Extracts `audio`'s features as a compact, versioned base64 string that can
+    /// stand in for the audio in `registerFromBase64` and `searchFromBase64`.
+    #[wasm_bindgen(js_name = featuresToBase64)]
+    pub fn features_to_base64(&self, audio: &[f32]) -> String {
+        let features = self.extractor.features(audio);
+
+        fingerprint::CompressedSong::from(&features[..]).to_base64()
+    }
+
+    #[wasm_bindgen(js_name = registerFromBase64)]
+    pub fn register_from_base64(&mut self, uuid: String, fingerprint: &str) -> Result<usize, JsError> {
+        let uuid = parse_song_key(&uuid)?;
+
+        Ok(self.insert_features(uuid, Self::decode_base64(fingerprint)?.into_iter().enumerate().collect()))
+    }
+
+    #[wasm_bindgen(js_name = searchFromBase64)]
+    pub fn search_from_base64(&mut self, fingerprint: &str) -> Result<Vec<SessionQueryResult>, JsError> {
+        // fingerprints carry no energy or contrast, so every frame counts fully and
+        // seeds at the usual penalty
+        let features = Self::decode_base64(fingerprint)?
+            .into_iter()
+            .enumerate()
+            .map(|(frame, feature)| (frame, feature, 1.0, 0.5))
+            .collect();
+
+        Ok(self.search_features(features).results)
+    }
+
+    /// An FNV-1a hash of the features extracted from `audio`, for use as a cache key:
+    /// audio that extracts identically hashes identically.
+    #[wasm_bindgen(js_name = fingerprintHash)]
+    pub fn fingerprint_hash(&self, audio: &[f32]) -> u64 {
+        fingerprint::fnv1a(self.extractor.features(audio).iter().map(|feature| *feature.as_ref()))
+    }
+
+    /// Forces lazy FFT and filterbank initialization ahead of the first `register`/`search`.
+    pub fn warmup(&self) {
+        self.extractor.warmup();
+    }
+
+    /// Logs each search's seeding, pruning and final candidates to `console.log`.
+    #[cfg(feature = "trace")]
+    #[wasm_bindgen(js_name = setTrace)]
+    pub fn set_trace(&mut self, enabled: bool) {
+        self.trace = enabled;
+    }
+
+    /// Summarizes the registered songs; song lengths are in seconds.
+    pub fn stats(&self) -> SessionStats {
+        let stats = self.db.stats();
+
+        SessionStats {
+            song_count: stats.song_count,
+            total_features: stats.total_features,
+            longest_song: stats.longest_song as f32 * self.stride_dt,
+            shortest_song: stats.shortest_song as f32 * self.stride_dt,
+            memory_bytes: stats.memory_bytes,
+        }
+    }
+
+    /// Reports why a query matches or doesn't: an empty database, a clip with no
+    /// usable features, no alignment, or a best score cut by the threshold.
+    pub fn explain(&self, audio: &[f32]) -> ExplainReport {
+        let frames = self.extractor.frames(audio);
+        let query_frames = frames.len();
+        let audible_frames = frames.iter().filter(|&&(_, _, energy, _)| energy > SILENT_FRAME_ENERGY).count();
+        let song_count = self.db.stats().song_count;
+
+        let best = if song_count > 0 { self.query(frames).finalize_iter().next() } else { None };
+        let best_score = best.map_or(f32::INFINITY, |res| res.score);
+
+        let reason = if song_count == 0 {
+            ExplainReason::EmptyDatabase
+        } else if audible_frames == 0 {
+            ExplainReason::NoFeatures
+        } else if best_score.is_infinite() {
+            ExplainReason::NoAlignment
+        } else if best_score > self.score_threshold {
+            ExplainReason::AboveThreshold
+        } else {
+            ExplainReason::Matched
+        };
+
+        ExplainReport { reason, query_frames, audible_frames, song_count, best_score, score_threshold: self.score_threshold }
+    }
+
+    /// Returns the single best match, or `None` when nothing scores within
+    /// `search_score_threshold` (lower scores are better).
+    pub fn identify(&mut self, audio: &[f32]) -> Option<SessionQueryResult> {
+        self.search(audio)
+            .into_iter()
+            .next()
+            .filter(|res| res.score <= self.score_threshold)
+    }
+
+    /// The top result of `search` if it scores within `search_score_threshold`;
+    /// the same as `identify`, under the name the one-answer flow looks for.
+    #[wasm_bindgen(js_name = bestMatch)]
+    pub fn best_match(&mut self, audio: &[f32]) -> Option<SessionQueryResult> {
+        self.identify(audio)
+    }
+
+    /// Returns the best match only if it beats the best match from any other song
+    /// by at least `min_margin` (lower scores are better). A lone candidate song
+    /// always clears the margin.
+    #[wasm_bindgen(js_name = identifyWithMargin)]
+    pub fn identify_with_margin(&mut self, audio: &[f32], min_margin: f32) -> Option<SessionQueryResult> {
+        let mut results = self.query(self.extractor.frames(audio)).finalize_iter();
+
+        let best = results.next()?;
+        let runner_up = results.find(|res| res.uuid != best.uuid);
+
+        runner_up
+            .is_none_or(|other| other.score - best.score >= min_margin)
+            .then(|| self.to_session_result(best))
+    }
+
+    /// Finds every registered track embedded in a long recording, such as a DJ set,
+    /// by searching windows of `window_sec` every `hop_sec`. Each window contributes
+    /// its best match within `search_score_threshold`; matches of the same track at
+    /// the same alignment are merged, keeping the best. Results are in order of
+    /// `queryStart`, which is measured from the start of `audio`.
+    pub fn scan(&mut self, audio: &[f32], window_sec: f32, hop_sec: f32) -> Vec<SessionQueryResult> {
+        let frames = self.extractor.frames(audio);
+        let window = ((window_sec / self.stride_dt).round() as usize).max(1);
+        let hop = ((hop_sec / self.stride_dt).round() as usize).max(1);
+
+        let mut found: Vec<QueryResult> = Vec::new();
+        for start in (0..frames.len().saturating_sub(window) + 1).step_by(hop) {
+            let end = (start + window).min(frames.len());
+            let Some(res) = self.query(frames[start..end].to_vec()).finalize_iter().next() else { continue };
+            if res.score > self.score_threshold { continue }
+
+            // the same track at the same alignment, give or take a frame of jitter
+            let offset = |r: &QueryResult| r.query_start as isize - r.key_start as isize;
+            match found.iter_mut().find(|other| other.uuid == res.uuid && offset(other).abs_diff(offset(&res)) <= SCAN_ALIGNMENT_SLACK) {
+                Some(other) if res.score < other.score => *other = res,
+                Some(_) => {}
+                None => found.push(res),
+            }
+        }
+
+        found.sort_by_key(|res| res.query_start);
+        found.into_iter().map(|res| self.to_session_result(res)).collect()
+    }
+
+    /// Searches with the query's chroma rotated by every shift in `-max_shift..=max_shift`
+    /// and returns the best match overall, tagged with the detected transposition.
+    #[wasm_bindgen(js_name = searchTransposed)]
+    pub fn search_transposed(&mut self, audio: &[f32], max_shift: usize) -> Option<SessionQueryResult> {
+        let max_shift = max_shift as isize;
+
+        self.extractor.features_transposed(audio, -max_shift..=max_shift)
+            .into_iter()
+            .filter_map(|(shift, features)| {
+                let mut q = self.db.new_query();
+                for (frame, feature) in features.into_iter() { q.update_at(feature, frame); }
+
+                q.finalize().into_iter().next().map(|res| SessionQueryResult {
+                    transposition: -shift as i32,
+                    ..self.to_session_result(res)
+                })
+            })
+            .min_by(|a, b| a.score.total_cmp(&b.score))
+    }
+
+    /// Scores how well `b` matches somewhere in `a`, without registering either.
+    /// Lower is more alike, on the same scale as search scores; infinite if nothing aligns.
+    pub fn compare(&self, a: &[f32], b: &[f32]) -> f32 {
+        let key = self.extractor.features(a);
+        let query = self.extractor.features(b);
+
+        self.db.align_unregistered(&key, &query).map_or(f32::INFINITY, |res| res.score)
+    }
+
+    #[wasm_bindgen(js_name = alignTo)]
+    pub fn align_to(&self, uuid: String, audio: &[f32]) -> Result<Option<SessionQueryResult>, JsError> {
+        let uuid = uuid::Uuid::try_parse(&uuid)?;
+
+        let features = self.extractor.features_indexed(audio);
+
+        Ok(self.db.align_to_indexed(&uuid, &features).map(|res| self.to_session_result(res)))
+    }
+}
+
+impl From<SessionConfiguration> for Session {
+    fn from(cfg: SessionConfiguration) -> Self {
+        let extractor = FeatureExtractor::from(FeatureExtractorConfiguration::from(&cfg));
+
+        Self::with_shared_extractor(Arc::new(extractor), &cfg)
+    }
+}
+
+impl Session {
+    /// Builds a session around an extractor shared with other sessions, so a server
+    /// spinning up many over one model builds the chroma matrix and FFT plan once.
+    /// The extractor settings in `cfg` are ignored in favour of `extractor`'s.
+    pub fn with_shared_extractor(extractor: Arc<FeatureExtractor>, cfg: &SessionConfiguration) -> Self {
+        Self {
+            sample_rate: extractor.sample_rate() as u32,
+            stride_dt: extractor.stride_dt(),
+            extractor,
+            db: DatabaseConfiguration::from(cfg).into(),
+            score_threshold: cfg.search_score_threshold,
+            query_hop: cfg.search_query_hop.max(1),
+            prefilter: cfg.search_prefilter,
+            coarse_shortlist: cfg.search_coarse_factor.map(|_| cfg.search_coarse_shortlist),
+            dedup_window: cfg.dedup_query_window,
+            trim_threshold: cfg.trim_silence_threshold,
+            #[cfg(feature = "trace")]
+            trace: false,
+            #[cfg(feature = "metrics")]
+            metrics: Default::default(),
+        }
+    }
+
+    /// Like `register`, for planar channel data such as an `AudioBuffer`'s, averaged
+    /// to mono. Every channel must be the same length.
+    pub fn register_planar(&mut self, uuid: String, channels: Vec<Box<[f32]>>) -> Result<usize, PlanarError> {
+        let uuid = parse_song_key(&uuid)?;
+        let mono = downmix_planar(&channels)?;
+
+        Ok(self.insert_features(uuid, self.register_features(&mono)))
+    }
+
+    /// Like `search`, for planar channel data averaged to mono.
+    pub fn search_planar(&mut self, channels: Vec<Box<[f32]>>) -> Result<Vec<SessionQueryResult>, PlanarError> {
+        let mono = downmix_planar(&channels)?;
+
+        Ok(self.search(&mono))
+    }
+
+    /// Registrations and searches so far. `search`, `searchWithFrames`, `searchI16`,
+    /// `searchStereo` and `searchFromBase64` count as searches; the other search
+    /// variants don't.
+    #[cfg(feature = "metrics")]
+    pub fn metrics(&self) -> &crate::metrics::SessionMetrics {
+        &self.metrics
+    }
+
+    /// Stores frame-tagged features, keeping the frame map only when frames were skipped.
+    fn insert_features(&mut self, uuid: uuid::Uuid, features: Vec<(usize, fingerprint::Feature)>) -> usize {
+        let count = features.len();
+
+        if features.iter().enumerate().all(|(i, &(frame, _))| i == frame) {
+            self.db.insert(uuid, features.into_iter().map(|(_, feature)| feature).collect());
+        } else {
+            self.db.insert_indexed(uuid, &features);
+        }
+
+        #[cfg(feature = "metrics")]
+        self.metrics.record_registration(self.db.song_count());
+
+        count
+    }
+
+    /// Extracts a song's features, trimming silence first if `trim_silence_threshold`
+    /// is set. Frames stay numbered from the start of `audio`, so key times don't move.
+    fn register_features(&self, audio: &[f32]) -> Vec<(usize, fingerprint::Feature)> {
+        match self.trim_threshold {
+            Some(threshold) => self.extractor.features_indexed_trimmed(audio, threshold),
+            None => self.extractor.features_indexed(audio),
+        }
+    }
+
+    /// Runs a query over every `query_hop`th feature, against only the songs that
+    /// pass `search_prefilter` and the coarse pass of `search_coarse_factor`, if set.
+    fn query(&self, features: Vec<fingerprint::Frame>) -> search::Query<'_> {
+        let query: Vec<fingerprint::Feature> = features.iter().map(|&(_, feature, _, _)| feature).collect();
+        let prefiltered = self.prefilter.map(|top_n| self.db.prefilter(&query, top_n));
+        let shortlisted = self.coarse_shortlist.and_then(|top_n| self.db.coarse_shortlist(&query, top_n));
+
+        let candidates = match (prefiltered, shortlisted) {
+            (Some(prefiltered), Some(shortlisted)) => Some(prefiltered.into_iter().filter(|uuid| shortlisted.contains(uuid)).collect()),
+            (prefiltered, shortlisted) => prefiltered.or(shortlisted),
+        };
+
+        let mut q = match candidates {
+            Some(candidates) => self.db.new_query_ranged(candidates.into_iter().map(|uuid: uuid::Uuid| (uuid, (0, usize::MAX))).collect()),
+            None => self.db.new_query(),
+        };
+
+        #[cfg(feature = "trace")]
+        if self.trace { q.on_trace(|event| console_log(&format!("{event:?}"))); }
+
+        for (frame, feature, energy, contrast) in features.into_iter().step_by(self.query_hop) {
+            q.update_with_contrast(feature, frame, energy, contrast);
+        }
+
+        q
+    }
+
+    fn search_features(&mut self, features: Vec<fingerprint::Frame>) -> SessionSearchResults {
+        let query_frames = features.len();
+        #[cfg(feature = "metrics")]
+        let started = std::time::Instant::now();
+
+        let results = self.dedup(self.query(features).finalize().into_iter()
+            .map(|res| self.to_session_result(res))
+            .collect());
+
+        #[cfg(feature = "metrics")]
+        self.metrics.record_search(started.elapsed(), results.len());
+
+        SessionSearchResults { results, query_frames }
+    }
+
+    fn decode_base64(fingerprint: &str) -> Result<Vec<fingerprint::Feature>, JsError> {
+        let song = fingerprint::CompressedSong::from_base64(fingerprint)
+            .ok_or_else(|| JsError::new("malformed base64 fingerprint"))?;
+
+        Ok(song.iter().collect())
+    }
+
+    /// Seconds into the audio at the centre of frame `frame`'s window.
+    fn frame_time(&self, frame: usize) -> f32 {
+        self.extractor.frame_center(frame) as f32 / self.sample_rate as f32
+    }
+
+    /// The inverse of `frame_time`, as a fractional frame clamped at 0.
+    fn time_frame(&self, seconds: f32) -> f32 {
+        ((seconds - self.frame_time(0)) / self.stride_dt).max(0.0)
+    }
+
+    fn to_session_result(&self, res: QueryResult) -> SessionQueryResult {
+        SessionQueryResult {
+            uuid: res.uuid.to_string(),
+            score: res.score,
+            key_start: self.frame_time(res.key_start),
+            key_end: self.frame_time(res.key_end),
+            query_start: self.frame_time(res.query_start),
+            context_start: self.frame_time(res.context_start),
+            context_end: self.frame_time(res.context_end),
+            transposition: 0,
+            duplicates: 0,
+            sample_rate: self.sample_rate,
+        }
+    }
+
+    /// Folds results from other songs starting within `dedup_query_window` of a
+    /// better one into it, counting them in its `duplicates`. `results` must be
+    /// best first.
+    fn dedup(&self, results: Vec<SessionQueryResult>) -> Vec<SessionQueryResult> {
+        if self.dedup_window <= 0.0 { return results }
+
+        let mut kept: Vec<(SessionQueryResult, HashSet<String>)> = Vec::new();
+        for result in results {
+            let region = kept.iter_mut().find(|(best, _)| {
+                best.uuid != result.uuid && (best.query_start - result.query_start).abs() <= self.dedup_window
+            });
+
+            match region {
+                Some((_, songs)) => { songs.insert(result.uuid); }
+                None => kept.push((result, HashSet::new())),
+            }
+        }
+
+        kept.into_iter()
+            .map(|(result, songs)| SessionQueryResult { duplicates: songs.len(), ..result })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fingerprint::{EdgeMode, EnergyMeasure}, resample, tests::{noise, synth_melody, MELODY}};
+
+    #[test]
+    fn test_resample_entry_points_agree() {
+        let audio: Vec<f32> = (0..4410).map(|i| (i as f32 * 0.01).cos()).collect();
+
+        for (fs_in, fs_out) in [(44_100, 11_500), (11_500, 44_100), (48_000, 48_000), (0, 11_500)] {
+            assert_eq!(resample(&audio, fs_in, fs_out), Session::resample(&audio, fs_in, fs_out));
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn test_metrics() {
+        let config = SessionConfiguration::default();
+        let sample_rate = config.sample_rate;
+        let mut session = Session::from(config);
+
+        let melody = synth_melody(&MELODY, 0.75, sample_rate);
+        session.register(uuid::Uuid::new_v4().to_string(), &melody).unwrap();
+        session.register(uuid::Uuid::new_v4().to_string(), &noise(sample_rate * 4, 7)).unwrap();
+
+        let found = session.search(&melody[sample_rate..sample_rate * 4]).len();
+        session.search(&melody[sample_rate * 2..sample_rate * 5]);
+        session.search_grouped(&melody);
+
+        let metrics = session.metrics();
+        assert_eq!((metrics.registrations, metrics.searches, metrics.songs), (2, 2, 2));
+        assert!(found > 0);
+
+        let text = metrics.to_prometheus_text();
+        assert!(text.contains("\nsession_registrations_total 2\n"));
+        assert!(text.contains("\nsession_searches_total 2\n"));
+        assert!(text.contains("\nsession_songs 2\n"));
+        assert!(text.contains(&format!("\nsession_search_results_mean {}\n", metrics.mean_result_count())));
+    }
+
+    #[test]
+    fn test_search_by_query_time() {
+        let config = SessionConfiguration::default();
+        let sample_rate = config.sample_rate;
+        let mut session = Session::from(config);
+
+        let verse = synth_melody(&MELODY[..6], 0.75, sample_rate);
+        let chorus = synth_melody(&[110.0, 155.6, 207.7, 123.5], 1.0, sample_rate);
+        let (verse_uuid, chorus_uuid) = (uuid::Uuid::new_v4().to_string(), uuid::Uuid::new_v4().to_string());
+        session.register(verse_uuid.clone(), &verse).unwrap();
+        session.register(chorus_uuid.clone(), &chorus).unwrap();
+
+        let heard: Vec<f32> = chorus[sample_rate / 2..].iter().chain(&verse[sample_rate / 2..]).copied().collect();
+        let results = session.search_by_query_time(&heard);
+
+        assert!(results.windows(2).all(|w| w[0].query_start < w[1].query_start));
+        assert_eq!(results[0].uuid(), chorus_uuid);
+        assert!(results.iter().any(|res| res.uuid() == verse_uuid));
+    }
+
+    #[test]
+    fn test_dedup_query_window() {
+        let sample_rate = SessionConfiguration::default().sample_rate;
+        let melody = synth_melody(&MELODY, 0.75, sample_rate);
+        let clip = &melody[sample_rate..sample_rate * 4];
+
+        // the same recording under two keys, as a remaster might be
+        let search = |dedup_query_window| {
+            let mut session = Session::from(SessionConfiguration { dedup_query_window, ..Default::default() });
+            session.register(uuid::Uuid::from_u128(1).to_string(), &melody).unwrap();
+            session.register(uuid::Uuid::from_u128(2).to_string(), &melody).unwrap();
+            session.search(clip)
+        };
+
+        let plain = search(0.0);
+        assert_ne!(plain[0].uuid, plain[1].uuid);
+        assert_eq!(plain[0].query_start, plain[1].query_start);
+
+        let deduped = search(0.5);
+        assert_eq!(deduped[0].duplicates, 1);
+        assert!(deduped.iter().all(|res| (res.query_start - deduped[0].query_start).abs() > 0.5 || res.uuid == deduped[0].uuid));
+    }
+
+    #[test]
+    fn test_shared_extractor() {
+        let config = SessionConfiguration::default();
+        let sample_rate = config.sample_rate;
+        let extractor = Arc::new(FeatureExtractor::from(FeatureExtractorConfiguration::from(&config)));
+
+        let mut a = Session::with_shared_extractor(extractor.clone(), &config);
+        let mut b = Session::with_shared_extractor(extractor.clone(), &config);
+        assert!(Arc::ptr_eq(&a.extractor, &b.extractor));
+        assert_eq!(Arc::strong_count(&extractor), 3);
+        assert_eq!(a.stride_dt, config.stride_dt());
+
+        let melody = synth_melody(&MELODY[..6], 0.75, sample_rate);
+        let uuid = uuid::Uuid::new_v4().to_string();
+        a.register(uuid.clone(), &melody).unwrap();
+        b.register(uuid.clone(), &melody).unwrap();
+        assert_eq!(a.features_of(uuid.clone()), b.features_of(uuid.clone()));
+
+        // each keeps its own database
+        let other = uuid::Uuid::new_v4().to_string();
+        a.register(other.clone(), &noise(sample_rate * 2, 3)).unwrap();
+        assert!(b.features_of(other).is_none());
+    }
+
+    #[test]
+    fn test_query_features() {
+        let config = SessionConfiguration::default();
+        let sample_rate = config.sample_rate;
+        let mut session = Session::from(config);
+
+        let melody = synth_melody(&MELODY[..6], 0.75, sample_rate);
+        let features = session.query_features(&melody);
+        assert_eq!(features.len(), session.extractor.feature_count_for(melody.len()));
+        assert_eq!(session.stats().song_count, 0);
+
+        let uuid = uuid::Uuid::new_v4().to_string();
+        session.register(uuid.clone(), &melody).unwrap();
+        assert_eq!(session.features_of(uuid), Some(features));
+    }
+
+    #[test]
+    fn test_result_samples() {
+        let config = SessionConfiguration::default();
+        let sample_rate = config.sample_rate;
+        let mut session = Session::from(config);
+
+        let melody = synth_melody(&MELODY, 0.75, sample_rate);
+        session.register(uuid::Uuid::new_v4().to_string(), &melody).unwrap();
+
+        let res = session.identify(&melody[sample_rate * 2..sample_rate * 5]).unwrap();
+        let key_start = session.time_frame(res.key_start).round() as usize;
+        assert!(key_start > 0);
+        assert_eq!(res.key_start_sample(), session.extractor.frame_center(key_start));
+        assert_eq!(res.key_end_sample(), session.extractor.frame_center(session.time_frame(res.key_end).round() as usize));
+        assert_eq!(res.query_start_sample(), session.extractor.frame_center(session.time_frame(res.query_start).round() as usize));
+    }
+
+    #[test]
+    fn test_result_times_edge_modes() {
+        // whichever edge mode pads the start, a frame's time is its window's centre
+        let sample_rate = SessionConfiguration::default().sample_rate;
+        let melody = synth_melody(&MELODY, 0.75, sample_rate);
+        let uuid = uuid::Uuid::new_v4().to_string();
+
+        for edge_mode in [EdgeMode::Truncate, EdgeMode::ZeroPad, EdgeMode::Reflect] {
+            let mut session = Session::from(SessionConfiguration { edge_mode, ..Default::default() });
+            session.register(uuid.clone(), &melody).unwrap();
+
+            // the clip starts 2s into the song, so its times sit 2s behind the song's
+            let res = session.identify(&melody[sample_rate * 2..sample_rate * 5]).unwrap();
+            let offset = res.key_start - res.query_start;
+            assert!((offset - 2.0).abs() <= 2.0 * session.stride_dt, "{edge_mode:?}: offset {offset}");
+            assert_eq!(res.key_start_sample(), session.extractor.frame_center(session.time_frame(res.key_start).round() as usize));
+        }
+    }
+
+    #[test]
+    fn test_search_raw() {
+        let config = SessionConfiguration::default();
+        let sample_rate = config.sample_rate;
+        let mut session = Session::from(config);
+
+        let melody = synth_melody(&MELODY, 0.75, sample_rate);
+        let uuid = uuid::Uuid::new_v4().to_string();
+        session.register(uuid.clone(), &melody).unwrap();
+
+        let clip = &melody[sample_rate..sample_rate * 4];
+        // tied beams past the best may be pruned in either order, so compare the top
+        let best = |results: Vec<SessionQueryResult>| {
+            let res = &results[0];
+            (res.uuid(), res.score, res.key_start, res.query_start)
+        };
+        let expected = best(session.search(clip));
+        assert_eq!(best(session.search_raw(clip, sample_rate as u32)), expected);
+
+        // the same clip captured at 44.1 kHz is brought down to the configured rate
+        let hi_res = synth_melody(&MELODY, 0.75, 44_100);
+        let results = session.search_raw(&hi_res[44_100..44_100 * 4], 44_100);
+        assert_eq!(results[0].uuid(), uuid);
+        assert!((results[0].key_start - expected.2).abs() <= session.stride_dt);
+    }
+
+    #[test]
+    fn test_register_returns_feature_count() {
+        let mut session = Session::from(SessionConfiguration::default());
+
+        for len in [1000, 4096, 11_500 * 3] {
+            let audio: Vec<f32> = (0..len).map(|i| (i as f32 * 0.07).sin()).collect();
+            let count = session.register(uuid::Uuid::new_v4().to_string(), &audio).unwrap();
+            assert_eq!(count, session.extractor.feature_count_for(len));
+        }
+    }
+
+    #[test]
+    fn test_identify() {
+        let config = SessionConfiguration::default();
+        let sample_rate = config.sample_rate;
+        let mut session = Session::from(config);
+
+        let melody = synth_melody(&MELODY, 0.75, sample_rate);
+        let uuid = uuid::Uuid::new_v4().to_string();
+        session.register(uuid.clone(), &melody).unwrap();
+        session.register(uuid::Uuid::new_v4().to_string(), &synth_melody(&[110.0, 155.6, 207.7], 2.0, sample_rate)).unwrap();
+
+        let clip = &melody[sample_rate..sample_rate * 4];
+        let result = session.identify(clip).expect("registered clip should be identified");
+        assert_eq!(result.uuid(), uuid);
+
+        assert!(session.identify(&noise(sample_rate * 3, 7)).is_none());
+    }
+
+    #[test]
+    fn test_stereo_entry_points() {
+        let config = SessionConfiguration { stereo_mode: fingerprint::StereoMode::Fuse, ..Default::default() };
+        let sample_rate = config.sample_rate;
+        let mut session = Session::from(config);
+
+        let melody = synth_melody(&MELODY, 0.75, sample_rate);
+        let inverted: Vec<f32> = melody.iter().map(|s| -s).collect();
+        let uuid = uuid::Uuid::new_v4().to_string();
+        session.register_stereo(uuid.clone(), &melody, &inverted).unwrap();
+
+        let clip = sample_rate..sample_rate * 4;
+        let results = session.search_stereo(&melody[clip.clone()], &inverted[clip.clone()]);
+        assert_eq!(results[0].uuid(), uuid);
+        assert_eq!(session.search(&melody[clip])[0].uuid(), uuid);
+    }
+
+    #[test]
+    fn test_planar_entry_points() {
+        let config = SessionConfiguration::default();
+        let sample_rate = config.sample_rate;
+        let mut session = Session::from(config);
+
+        let left = synth_melody(&MELODY, 0.75, sample_rate);
+        let right = synth_melody(&[329.6, 392.0, 523.3, 659.3, 523.3, 440.0, 349.2, 293.7], 0.75, sample_rate);
+
+        // the same as interleaving the channels and downmixing each frame
+        let interleaved: Vec<f32> = left.iter().zip(&right).flat_map(|(&l, &r)| [l, r]).collect();
+        let downmixed: Vec<f32> = interleaved.chunks(2).map(|frame| 0.5 * (frame[0] + frame[1])).collect();
+        let channels = || vec![left.clone().into_boxed_slice(), right.clone().into_boxed_slice()];
+        assert_eq!(downmix_planar(&channels()).unwrap(), downmixed);
+        assert_eq!(session.query_features(&downmix_planar(&channels()).unwrap()), session.query_features(&downmixed));
+
+        let (planar, mixed) = (uuid::Uuid::new_v4().to_string(), uuid::Uuid::new_v4().to_string());
+        let registered = session.register_planar(planar.clone(), channels()).unwrap();
+        assert_eq!(session.register(mixed.clone(), &downmixed).unwrap(), registered);
+        assert_eq!(session.features_of(planar), session.features_of(mixed));
+
+        let clip = sample_rate..sample_rate * 4;
+        let clip_channels = vec![left[clip.clone()].into(), right[clip.clone()].into()];
+        let results = session.search_planar(clip_channels).unwrap();
+        assert_eq!(results[0].score, session.search(&downmixed[clip])[0].score);
+
+        let key = uuid::Uuid::new_v4().to_string();
+        assert!(matches!(session.register_planar(key.clone(), vec![]), Err(PlanarError::NoChannels)));
+        let ragged = vec![left[..10].into(), right[..9].into()];
+        assert!(matches!(session.register_planar(key, ragged), Err(PlanarError::Ragged { channel: 1, len: 9, expected: 10 })));
+        assert!(matches!(session.register_planar("not a uuid".into(), channels()), Err(PlanarError::Key(SongKeyError::Malformed(_)))));
+        assert!(matches!(session.search_planar(vec![]), Err(PlanarError::NoChannels)));
+    }
+
+    #[test]
+    fn test_fingerprint_hash() {
+        let config = SessionConfiguration::default();
+        let sample_rate = config.sample_rate;
+        let session = Session::from(config);
+
+        let melody = synth_melody(&[261.6, 329.6, 392.0, 523.3], 0.75, sample_rate);
+        let hash = session.fingerprint_hash(&melody);
+        assert_eq!(session.fingerprint_hash(&melody.clone()), hash);
+
+        // a click in one sample is broadband enough to reorder that frame's chroma
+        let mut clicked = melody.clone();
+        clicked[sample_rate] = 50.0;
+        assert_ne!(session.fingerprint_hash(&clicked), hash);
+        assert_ne!(session.fingerprint_hash(&melody[..sample_rate * 2]), hash);
+    }
+
+    #[test]
+    fn test_best_match() {
+        let config = SessionConfiguration::default();
+        let sample_rate = config.sample_rate;
+        let mut session = Session::from(config);
+
+        let melody = synth_melody(&MELODY, 0.75, sample_rate);
+        session.register(uuid::Uuid::new_v4().to_string(), &melody).unwrap();
+        session.register(uuid::Uuid::new_v4().to_string(), &synth_melody(&[110.0, 155.6, 207.7], 2.0, sample_rate)).unwrap();
+
+        let clip = &melody[sample_rate..sample_rate * 4];
+        let best = session.best_match(clip).expect("registered clip should match");
+        let top = &session.search(clip)[0];
+        assert_eq!((best.uuid(), best.score, best.key_start, best.query_start), (top.uuid(), top.score, top.key_start, top.query_start));
+
+        assert!(session.best_match(&noise(sample_rate * 3, 7)).is_none());
+    }
+
+    #[test]
+    fn test_explain() {
+        let config = SessionConfiguration::default();
+        let sample_rate = config.sample_rate;
+        let mut session = Session::from(config);
+
+        let melody = synth_melody(&MELODY, 0.75, sample_rate);
+        let clip = &melody[sample_rate..sample_rate * 4];
+
+        let empty = session.explain(clip);
+        assert_eq!(empty.reason, ExplainReason::EmptyDatabase);
+        assert!(empty.query_frames > 0);
+
+        session.register(uuid::Uuid::new_v4().to_string(), &melody).unwrap();
+
+        let silent = session.explain(&vec![0.0; sample_rate * 3]);
+        assert_eq!(silent.reason, ExplainReason::NoFeatures);
+        assert_eq!(silent.audible_frames, 0);
+        assert_eq!(session.explain(&melody[..16]).reason, ExplainReason::NoFeatures);
+
+        let matched = session.explain(clip);
+        assert_eq!(matched.reason, ExplainReason::Matched);
+        assert!(matched.best_score <= matched.score_threshold);
+
+        let mut strict = Session::from(SessionConfiguration { search_score_threshold: matched.best_score / 2.0, ..Default::default() });
+        strict.register(uuid::Uuid::new_v4().to_string(), &melody).unwrap();
+        let cut = strict.explain(clip);
+        assert_eq!(cut.reason, ExplainReason::AboveThreshold);
+        assert_eq!(cut.best_score, matched.best_score);
+    }
+
+    #[test]
+    fn test_register_auto_seeded() {
+        let config = SessionConfiguration::default();
+        let sample_rate = config.sample_rate;
+        let mut session = Session::from(config);
+        let song = synth_melody(&[261.6, 329.6, 392.0, 523.3], 0.75, sample_rate);
+
+        let first = session.register_auto_seeded(&song, 42);
+        assert_eq!(session.register_auto_seeded(&song, 42), first);
+        assert_ne!(session.register_auto_seeded(&song, 43), first);
+        assert_eq!(session.stats().song_count, 2);
+
+        let uuid = parse_song_key(&first).unwrap();
+        assert_eq!(uuid.get_version_num(), 4);
+        assert!(session.features_of(first).is_some());
+    }
+
+    #[test]
+    fn test_parse_song_key() {
+        let v4 = uuid::Uuid::new_v4();
+        assert_eq!(parse_song_key(&v4.to_string()).unwrap(), v4);
+
+        assert!(matches!(parse_song_key("00000000-0000-0000-0000-000000000000"), Err(SongKeyError::Nil)));
+        assert!(matches!(parse_song_key("not a uuid"), Err(SongKeyError::Malformed(_))));
+        assert_ne!(SongKeyError::Nil.to_string(), parse_song_key("").unwrap_err().to_string());
+    }
+
+    #[test]
+    fn test_identify_with_margin() {
+        let config = SessionConfiguration::default();
+        let sample_rate = config.sample_rate;
+        let mut session = Session::from(config);
+
+        let melody = synth_melody(&MELODY, 0.75, sample_rate);
+        let clip = &melody[sample_rate..sample_rate * 4];
+        let uuid = uuid::Uuid::new_v4().to_string();
+        session.register(uuid.clone(), &melody).unwrap();
+        session.register(uuid::Uuid::new_v4().to_string(), &synth_melody(&[110.0, 155.6, 207.7], 2.0, sample_rate)).unwrap();
+
+        let result = session.identify_with_margin(clip, 1.0).expect("a clear winner");
+        assert_eq!(result.uuid(), uuid);
+
+        // the same recording registered twice is a tie
+        session.register(uuid::Uuid::new_v4().to_string(), &melody).unwrap();
+        assert!(session.identify_with_margin(clip, 1.0).is_none());
+        assert!(session.identify_with_margin(clip, 0.0).is_some());
+    }
+
+    #[test]
+    fn test_scan() {
+        let config = SessionConfiguration::default();
+        let sample_rate = config.sample_rate;
+        let mut session = Session::from(config);
+
+        let tracks = [
+            synth_melody(&MELODY[..6], 0.75, sample_rate),
+            synth_melody(&[110.0, 155.6, 207.7, 123.5, 185.0], 1.0, sample_rate),
+            synth_melody(&[493.9, 293.7, 587.3, 246.9, 370.0, 311.1], 0.75, sample_rate),
+        ];
+        let uuids: Vec<String> = tracks.iter().map(|_| uuid::Uuid::new_v4().to_string()).collect();
+        for (uuid, track) in uuids.iter().zip(&tracks) {
+            session.register(uuid.clone(), track).unwrap();
+        }
+
+        let set: Vec<f32> = tracks.concat();
+        let results = session.scan(&set, 2.0, 1.0);
+
+        assert_eq!(results.iter().map(|r| r.uuid()).collect::<Vec<_>>(), uuids);
+
+        let mut track_start = 0.0;
+        for (result, track) in results.iter().zip(&tracks) {
+            let start = result.query_start - result.key_start;
+            assert!((start - track_start).abs() <= 2.0 * session.stride_dt, "found at {start}s, placed at {track_start}s");
+            track_start += track.len() as f32 / sample_rate as f32;
+        }
+    }
+
+    #[test]
+    fn test_i16_entry_points() {
+        let config = SessionConfiguration::default();
+        let sample_rate = config.sample_rate;
+        let mut session = Session::from(config);
+
+        let pcm: Vec<i16> = synth_melody(&MELODY[..6], 0.75, sample_rate)
+            .into_iter()
+            .map(|s| (s * i16::MAX as f32) as i16)
+            .collect();
+        let scaled: Vec<f32> = pcm.iter().map(|&s| s as f32 / 32768.0).collect();
+
+        let uuid = uuid::Uuid::new_v4().to_string();
+        assert_eq!(session.register_i16(uuid.clone(), &pcm).unwrap(), session.extractor.feature_count_for(pcm.len()));
+        assert_eq!(session.features_of(uuid.clone()), Some(session.query_features(&scaled)));
+
+        let from_i16 = session.search_i16(&pcm[sample_rate..sample_rate * 3]);
+        let from_f32 = session.search(&scaled[sample_rate..sample_rate * 3]);
+        assert_eq!(from_i16[0].uuid(), uuid);
+        assert_eq!(from_i16[0].score, from_f32[0].score);
+        assert_eq!(from_i16.len(), from_f32.len());
+    }
+
+    #[test]
+    fn test_search_ranged() {
+        let config = SessionConfiguration::default();
+        let sample_rate = config.sample_rate;
+        let mut session = Session::from(config);
+
+        let verse = synth_melody(&MELODY[..6], 0.75, sample_rate);
+        let mut song = verse.clone();
+        song.extend(synth_melody(&[110.0, 155.6, 207.7, 123.5], 1.0, sample_rate));
+
+        let uuid = uuid::Uuid::new_v4().to_string();
+        session.register(uuid.clone(), &song).unwrap();
+
+        let clip = &verse[sample_rate..sample_rate * 3];
+        assert!(session.search(clip)[0].key_end < 4.5);
+
+        let results = session.search_ranged(clip, vec![uuid.clone()], &[5.0], &[8.0]).unwrap();
+        assert!(!results.is_empty());
+        assert!(results.iter().all(|r| r.uuid() == uuid && r.key_start >= 5.0 - session.stride_dt && r.key_end < 8.0));
+    }
+
+    #[test]
+    fn test_search_query_frames() {
+        let config = SessionConfiguration::default();
+        let sample_rate = config.sample_rate;
+        let mut session = Session::from(config);
+
+        let song = synth_melody(&MELODY[..6], 0.75, sample_rate);
+        session.register(uuid::Uuid::new_v4().to_string(), &song).unwrap();
+
+        for clip in [&song[..sample_rate * 2], &song[1000..sample_rate * 3 + 77], &song[..100]] {
+            let results = session.search_with_frames(clip);
+            assert_eq!(results.query_frames, session.extractor.feature_count_for(clip.len()));
+            assert_eq!(results.into_results().len(), session.search(clip).len());
+        }
+        assert!(session.search(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_compare() {
+        let config = SessionConfiguration::default();
+        let sample_rate = config.sample_rate;
+        let perfect = config.search_score_penalty as f32;
+        let length_penalty = config.search_length_penalty as usize;
+        let session = Session::from(config);
+
+        let clip = synth_melody(&MELODY[..6], 0.5, sample_rate);
+        let frames = session.extractor.feature_count_for(clip.len());
+
+        // only the seeding penalty remains when every frame matches exactly
+        assert_eq!(session.compare(&clip, &clip), perfect / (frames + length_penalty) as f32);
+
+        let unrelated = session.compare(&clip, &noise(clip.len(), 3));
+        assert!(unrelated > 1.5 * session.compare(&clip, &clip), "unrelated {unrelated}");
+        assert!(unrelated > session.score_threshold);
+
+        assert_eq!(session.compare(&clip, &[]), f32::INFINITY);
+    }
+
+    #[test]
+    fn test_base64_fingerprints() {
+        let config = SessionConfiguration::default();
+        let sample_rate = config.sample_rate;
+        let mut session = Session::from(config);
+
+        let song = synth_melody(&MELODY[..6], 0.75, sample_rate);
+        let encoded = session.features_to_base64(&song);
+
+        let features: Vec<u64> = session.extractor.features(&song).iter().map(|f| *f.as_ref()).collect();
+        let decoded: Vec<u64> = Session::decode_base64(&encoded).unwrap().iter().map(|f| *f.as_ref()).collect();
+        assert_eq!(decoded, features);
+        assert!(encoded.len() < features.len() * 8, "{} chars for {} features", encoded.len(), features.len());
+
+        let uuid = uuid::Uuid::new_v4().to_string();
+        assert_eq!(session.register_from_base64(uuid.clone(), &encoded).unwrap(), features.len());
+        session.register(uuid::Uuid::new_v4().to_string(), &noise(song.len(), 11)).unwrap();
+
+        let results = session.search(&song[sample_rate..sample_rate * 3]);
+        assert_eq!(results[0].uuid(), uuid);
+
+        let clip = session.features_to_base64(&song[sample_rate..sample_rate * 3]);
+        assert_eq!(session.search_from_base64(&clip).unwrap()[0].uuid(), uuid);
+    }
+
+    #[test]
+    fn test_search_grouped() {
+        let config = SessionConfiguration::default();
+        let sample_rate = config.sample_rate;
+        let mut session = Session::from(config);
+
+        let song = synth_melody(&MELODY[..6], 0.75, sample_rate);
+        let uuid = uuid::Uuid::new_v4().to_string();
+        session.register(uuid.clone(), &song).unwrap();
+        session.register(uuid::Uuid::new_v4().to_string(), &noise(song.len(), 3)).unwrap();
+
+        let clip = &song[sample_rate..sample_rate * 3];
+        let grouped = session.search_grouped(clip);
+        let flat = session.search(clip);
+
+        assert_eq!(grouped[0].uuid(), uuid);
+        assert!(grouped.iter().all(|group| group.results().iter().all(|r| r.uuid() == group.uuid())));
+        assert_eq!(grouped.iter().map(|group| group.results.len()).sum::<usize>(), flat.len());
+    }
+
+    #[test]
+    fn test_features_of() {
+        let config = SessionConfiguration::default();
+        let sample_rate = config.sample_rate;
+        let mut session = Session::from(config);
+
+        let song = synth_melody(&[261.6, 329.6, 392.0, 523.3], 0.75, sample_rate);
+        let uuid = uuid::Uuid::new_v4().to_string();
+        session.register(uuid.clone(), &song).unwrap();
+
+        let features: Vec<u64> = session.extractor.features(&song).iter().map(|f| *f.as_ref()).collect();
+        assert_eq!(session.features_of(uuid.clone()), Some(features));
+        assert_eq!(session.features_of(uuid::Uuid::new_v4().to_string()), None);
+        assert_eq!(session.features_of("not a uuid".into()), None);
+
+        let duration = session.duration_of(uuid.clone()).unwrap();
+        assert!((duration - 3.0).abs() <= session.stride_dt, "{duration}s for a 3s clip");
+        assert_eq!(session.duration_of(uuid::Uuid::new_v4().to_string()), None);
+
+        let defaults = SessionConfiguration::default();
+        assert!(!defaults.requires_reextraction(&SessionConfiguration { search_beam_count: 10, ..Default::default() }));
+        assert!(defaults.requires_reextraction(&SessionConfiguration { chroma_smoothing: 0.5, ..Default::default() }));
+        // energy only weights the search; the stored features are the same
+        assert!(!defaults.requires_reextraction(&SessionConfiguration { energy_measure: EnergyMeasure::Spectrum, ..Default::default() }));
+    }
+
+    #[test]
+    fn test_search_query_hop() {
+        let sample_rate = SessionConfiguration::default().sample_rate;
+        let song = synth_melody(&MELODY, 0.75, sample_rate);
+        let clip = &song[sample_rate * 2..sample_rate * 5];
+
+        for hop in [1, 2] {
+            let mut session = Session::from(SessionConfiguration { search_query_hop: hop, ..Default::default() });
+            let uuid = uuid::Uuid::new_v4().to_string();
+            session.register(uuid.clone(), &song).unwrap();
+            session.register(uuid::Uuid::new_v4().to_string(), &noise(song.len(), 5)).unwrap();
+
+            let frames = session.extractor.feature_count_for(clip.len());
+            let updates = session.query(session.extractor.frames(clip)).stats().frames;
+            assert_eq!(updates, frames.div_ceil(hop));
+
+            let results = session.search_with_frames(clip);
+            assert_eq!(results.query_frames, frames);
+
+            // the clip starts two seconds into the song, whichever frames were skipped
+            let best = &results.results[0];
+            assert_eq!(best.uuid(), uuid);
+            let offset = best.key_start - best.query_start;
+            assert!((offset - 2.0).abs() <= 2.0 * session.stride_dt, "hop {hop}: offset {offset}");
+        }
+    }
+
+    #[test]
+    fn test_onset_gated_timing() {
+        let config = SessionConfiguration { onset_gated: true, ..Default::default() };
+        let sample_rate = config.sample_rate;
+        let mut session = Session::from(config);
+
+        // plucked notes, each a decaying tone followed by a rest
+        let plucked = |freqs: &[f32]| -> Vec<f32> {
+            let (note_len, rest_len) = (sample_rate * 6 / 10, sample_rate * 3 / 10);
+            freqs.iter()
+                .flat_map(|&f| (0..note_len + rest_len).map(move |i| {
+                    if i >= note_len { return 0.0 }
+                    let t = i as f32 / sample_rate as f32;
+                    (std::f32::consts::TAU * f * t).sin() * (-4.0 * t).exp()
+                }))
+                .collect()
+        };
+        let song = plucked(&[261.6, 392.0, 329.6, 523.3, 440.0, 293.7, 349.2, 246.9, 196.0, 220.0]);
+
+        let uuid = uuid::Uuid::new_v4().to_string();
+        let onsets = session.register(uuid.clone(), &song).unwrap();
+        assert!(onsets < session.extractor.feature_count_for(song.len()) / 3);
+
+        // a clip starting on the fourth note, 2.7 s in
+        let offset = sample_rate * 27 / 10;
+        let results = session.search(&song[offset..offset + sample_rate * 4]);
+        let best = &results[0];
+        assert_eq!(best.uuid(), uuid);
+
+        // positions are in real time, not onset counts
+        let lag = best.key_start - best.query_start;
+        assert!((lag - 2.7).abs() <= 2.0 * session.stride_dt, "lag {lag}");
+
+        // and every other query path gates and times its features the same way
+        let clip = &song[offset..offset + sample_rate * 4];
+        let transposed = session.search_transposed(clip, 2).unwrap();
+        let aligned = session.align_to(uuid.clone(), clip).unwrap().unwrap();
+        let ranged = session.search_ranged(clip, vec![uuid.clone()], &[2.0], &[8.0]).unwrap();
+        for result in [&transposed, &aligned, &ranged[0]] {
+            assert_eq!(result.uuid(), uuid);
+            assert_eq!(result.transposition, 0);
+            let lag = result.key_start - result.query_start;
+            assert!((lag - 2.7).abs() <= 2.0 * session.stride_dt, "lag {lag}");
+        }
+    }
+
+    #[test]
+    fn test_trim_silence_timing() {
+        let sample_rate = SessionConfiguration::default().sample_rate;
+        let melody = synth_melody(&MELODY, 0.75, sample_rate);
+        let padded: Vec<f32> = std::iter::repeat_n(0.0, sample_rate * 2)
+            .chain(melody.iter().copied())
+            .chain(std::iter::repeat_n(0.0, sample_rate * 2))
+            .collect();
+
+        let mut plain = Session::from(SessionConfiguration::default());
+        let mut trimmed = Session::from(SessionConfiguration { trim_silence_threshold: Some(0.01), ..Default::default() });
+        let uuid = uuid::Uuid::new_v4().to_string();
+        let full_count = plain.register(uuid.clone(), &padded).unwrap();
+        assert!(trimmed.register(uuid.clone(), &padded).unwrap() < full_count);
+
+        // a clip 3 s into the melody, 5 s into the padded recording
+        let clip = &melody[sample_rate * 3..sample_rate * 6];
+        let (a, b) = (&plain.search(clip)[0], &trimmed.search(clip)[0]);
+        assert_eq!(b.uuid(), uuid);
+        assert!((b.key_start - a.key_start).abs() <= trimmed.stride_dt, "{} vs {}", b.key_start, a.key_start);
+        assert!((b.key_start - 5.0).abs() <= 2.0 * trimmed.stride_dt, "key start {}", b.key_start);
+    }
+
+    #[test]
+    fn test_energy_weighted_search() {
+        let sample_rate = SessionConfiguration::default().sample_rate;
+        let song = synth_melody(&MELODY, 0.75, sample_rate);
+        let clip = song[sample_rate..sample_rate * 4].to_vec();
+
+        // the same clip trailing off into quiet noise
+        let mut tailed = clip.clone();
+        tailed.extend(noise(sample_rate * 2, 9).into_iter().map(|s| s * 0.01));
+
+        let scores = |weighted: bool| {
+            let mut session = Session::from(SessionConfiguration { search_energy_weight: weighted, ..Default::default() });
+            session.register(uuid::Uuid::new_v4().to_string(), &song).unwrap();
+            (session.search(&clip)[0].score, session.search(&tailed)[0].score)
+        };
+
+        let (clean, noisy) = scores(false);
+        assert!(noisy > clean * 1.3, "unweighted: {clean} -> {noisy}");
+
+        let (clean, noisy) = scores(true);
+        assert!((noisy - clean).abs() < clean * 0.05, "weighted: {clean} -> {noisy}");
+    }
+
+    #[test]
+    fn test_search_prefilter() {
+        let config = SessionConfiguration { search_prefilter: Some(2), ..Default::default() };
+        let sample_rate = config.sample_rate;
+        let mut session = Session::from(config);
+
+        let song = synth_melody(&MELODY[..6], 0.75, sample_rate);
+        let uuid = uuid::Uuid::new_v4().to_string();
+        session.register(uuid.clone(), &song).unwrap();
+        for seed in 0..6 {
+            session.register(uuid::Uuid::new_v4().to_string(), &noise(song.len(), seed)).unwrap();
+        }
+
+        let results = session.search(&song[sample_rate..sample_rate * 3]);
+        assert_eq!(results[0].uuid(), uuid);
+
+        let songs: std::collections::HashSet<String> = results.iter().map(|r| r.uuid()).collect();
+        assert!(songs.len() <= 2);
+    }
+
+    #[test]
+    fn test_search_coarse() {
+        let config = SessionConfiguration { search_coarse_factor: Some(4), search_coarse_shortlist: 2, ..Default::default() };
+        let sample_rate = config.sample_rate;
+        let mut session = Session::from(config);
+        let mut full = Session::from(SessionConfiguration::default());
+
+        let melody = synth_melody(&MELODY, 0.75, sample_rate);
+        let uuid = uuid::Uuid::new_v4().to_string();
+        for (i, song) in [melody.clone(), noise(sample_rate * 6, 3), noise(sample_rate * 6, 4), noise(sample_rate * 6, 5)].iter().enumerate() {
+            let key = if i == 0 { uuid.clone() } else { uuid::Uuid::new_v4().to_string() };
+            session.register(key.clone(), song).unwrap();
+            full.register(key, song).unwrap();
+        }
+
+        let clip = &melody[sample_rate..sample_rate * 4];
+        let (coarse, fine) = (session.search(clip), full.search(clip));
+        assert_eq!(coarse[0].uuid(), uuid);
+        assert_eq!(coarse[0].score, fine[0].score);
+        assert_eq!(coarse[0].key_start, fine[0].key_start);
+
+        // only the shortlisted songs reach the fine search
+        let songs: std::collections::HashSet<String> = coarse.iter().map(|res| res.uuid()).collect();
+        assert!(songs.len() <= 2);
+    }
+
+    #[test]
+    fn test_search_transposed() {
+        let config = SessionConfiguration::default();
+        let sample_rate = config.sample_rate;
+        let mut session = Session::from(config);
+
+        let melody = MELODY;
+        let key = uuid::Uuid::from_u128(1);
+        session.db.insert(key, session.extractor.features(&synth_melody(&melody, 0.5, sample_rate)));
+        session.db.insert(uuid::Uuid::from_u128(2), session.extractor.features(&synth_melody(&[110.0, 155.6, 207.7], 1.5, sample_rate)));
+
+        let up_two: Vec<f32> = melody.iter().map(|f| f * 2f32.powf(2.0 / 12.0)).collect();
+        let query = synth_melody(&up_two[2..6], 0.5, sample_rate);
+
+        let result = session.search_transposed(&query, 3).expect("should match");
+        assert_eq!(result.uuid(), key.to_string());
+        assert_eq!(result.transposition, 2);
+
+        let untransposed = session.search_transposed(&query, 0).expect("should still return something");
+        assert!(result.score < untransposed.score);
+    }
+}